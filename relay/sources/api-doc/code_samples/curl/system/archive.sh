@@ -0,0 +1 @@
+curl "http://localhost:3030/rudder/relay-api/2/system/archive/reports?node_id=e745a140-40bc-4b86-b6dc-084488fc906b&after=2020-01-01T00:00:00Z"
@@ -0,0 +1 @@
+curl --request POST "http://localhost:3030/rudder/relay-api/1/system/replay/reports?node_id=e745a140-40bc-4b86-b6dc-084488fc906b"
@@ -96,6 +96,7 @@ pub fn db() -> PgPool {
         url: "postgres://rudderreports@127.0.0.1/rudder".to_string(),
         password: Secret::new("PASSWORD".to_string()),
         max_pool_size: 10,
+        ..Default::default()
     };
     pg_pool(&db_config).unwrap()
 }
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate relayd;
+
+use relayd::input::uncompress;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = uncompress(data.to_vec(), Some("gz"));
+    let _ = uncompress(data.to_vec(), Some("zip"));
+});
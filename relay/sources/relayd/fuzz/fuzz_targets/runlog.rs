@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate relayd;
+
+use relayd::data::{report::runlog, RunInfo, RunLog};
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(raw) = std::str::from_utf8(data) {
+        let _ = runlog(raw);
+        let info = "2018-08-24T15:55:01+00:00@root.log"
+            .parse::<RunInfo>()
+            .unwrap();
+        let _ = RunLog::try_from((info, raw));
+    }
+});
@@ -0,0 +1,41 @@
+#!/bin/sh
+# SPDX-License-Identifier: GPL-3.0-or-later
+# SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+# Seeds the fuzzing corpus of each target from test files
+# Usage: ./seed_corpus.sh, then cargo fuzz run <target>
+
+set -e
+
+cd "$(dirname "$0")"
+files=../tests/files
+
+seed() {
+  target="$1"
+  shift
+  mkdir -p "corpus/${target}"
+  cp "$@" "corpus/${target}/"
+}
+
+# Seeds one file per line of the standard input
+seed_lines() {
+  target="$1"
+  mkdir -p "corpus/${target}"
+  i=0
+  while read -r line; do
+    i=$((i + 1))
+    printf '%s' "${line}" > "corpus/${target}/seed-${i}"
+  done
+}
+
+for runlog in "${files}"/runlogs/*; do
+  basename "${runlog}"
+done | seed_lines runinfo
+
+seed runlog "${files}"/runlogs/*.log "${files}"/smime/normal.log
+seed report "${files}"/runlogs/*.log "${files}"/smime/normal.log
+seed metadata "${files}"/metadata.txt ../tests/api_shared_files/*/files/*/*.metadata ../tests/api_shared_files/*/files/*/*.sign
+grep -o '"sha[0-9]*:[0-9a-f]*"' "${files}"/nodeslist.json | tr -d '"' | seed_lines hash
+seed nodes_list "${files}"/nodeslist.json
+printf 'class1\nclass2_3\nA0\n_class\ncl$$y\n' | seed_lines condition
+seed compressed_file "${files}"/gz/normal.log.gz "${files}"/gz/normal.log.zip
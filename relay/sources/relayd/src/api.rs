@@ -1,37 +1,64 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+mod auth;
 mod remote_run;
 mod shared_files;
 mod shared_folder;
 mod system;
+mod v2;
+
+pub use remote_run::Condition;
 
 use crate::{
     api::{
+        auth::authorize,
         remote_run::{RemoteRun, RemoteRunTarget},
         shared_files::{SharedFilesHeadParams, SharedFilesPutParams},
         shared_folder::SharedFolderParams,
-        system::{Info, Status},
+        system::{Info, Readiness, Status},
     },
+    configuration::main::{ApiScope, UnixSocketConfig},
     error::Error,
+    processing::{
+        archive,
+        replay::{replay, ReplayFilter, ReplayKind},
+    },
     stats::Stats,
+    telemetry::TRACEPARENT,
     JobConfig,
 };
-use futures::Future;
+use chrono::Utc;
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use hyper::Body;
 use serde::Serialize;
 use std::{
     collections::HashMap,
+    ffi::CString,
     fmt::Display,
+    fs::{create_dir_all, remove_file, set_permissions, symlink_metadata, Permissions},
+    io,
     net::SocketAddr,
-    path::PathBuf,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
-use tracing::{error, info, span, Level};
+use tokio::net::UnixListener;
+use tracing::{error, info, span, warn, Level};
 use warp::{
     body::{self, FullBody},
     filters::{method::v2::*, path::Peek},
     fs,
-    http::StatusCode,
+    http::{
+        header::{HeaderValue, CONTENT_TYPE},
+        Response, StatusCode,
+    },
     path, query,
     reject::custom,
     reply, Filter, Rejection, Reply,
@@ -91,97 +118,308 @@ impl<T: Serialize> ApiResponse<T> {
     fn reply(&self) -> impl Reply {
         reply::with_status(reply::json(self), self.status_code)
     }
+
+    /// Built response, for use outside of filters
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(
+            serde_json::to_string(self).expect("could not serialize response"),
+        ));
+        *response.status_mut() = self.status_code;
+        let _ = response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+    }
+}
+
+/// Name lookups use `getpwnam`/`getgrnam`, which are not thread-safe,
+/// so this must be called before starting the runtime
+fn chown(path: &Path, owner: Option<&str>, group: Option<&str>) -> Result<(), Error> {
+    let c_name = |name: &str| CString::new(name).map_err(|_| Error::UnknownOwner(name.to_string()));
+    // -1 leaves the id unchanged
+    let uid = match owner {
+        Some(name) => {
+            let passwd = unsafe { libc::getpwnam(c_name(name)?.as_ptr()) };
+            if passwd.is_null() {
+                return Err(Error::UnknownOwner(name.to_string()));
+            }
+            unsafe { (*passwd).pw_uid }
+        }
+        None => libc::uid_t::max_value(),
+    };
+    let gid = match group {
+        Some(name) => {
+            let group = unsafe { libc::getgrnam(c_name(name)?.as_ptr()) };
+            if group.is_null() {
+                return Err(Error::UnknownOwner(name.to_string()));
+            }
+            unsafe { (*group).gr_gid }
+        }
+        None => libc::gid_t::max_value(),
+    };
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::InvalidFileName)?;
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Binds the local API socket, replacing the one left by a previous instance,
+/// and applies the configured ownership and permissions
+pub fn bind_unix_socket(cfg: &UnixSocketConfig) -> Result<UnixListener, Error> {
+    if let Some(parent) = cfg.path.parent() {
+        create_dir_all(parent)?;
+    }
+    match symlink_metadata(&cfg.path) {
+        Ok(metadata) if metadata.file_type().is_socket() => remove_file(&cfg.path)?,
+        // Binding will fail on other existing files
+        _ => (),
+    }
+    let listener = UnixListener::bind(&cfg.path)?;
+    set_permissions(&cfg.path, Permissions::from_mode(cfg.mode))?;
+    if cfg.owner.is_some() || cfg.group.is_some() {
+        chown(&cfg.path, cfg.owner.as_deref(), cfg.group.as_deref())?;
+    }
+    Ok(listener)
 }
 
+/// Serves the API on `listen`, and on the Unix socket when configured. The
+/// socket is bound beforehand, see `bind_unix_socket`.
 pub fn run(
     listen: SocketAddr,
+    unix_socket: Option<UnixListener>,
     job_config: Arc<JobConfig>,
     stats: Arc<RwLock<Stats>>,
 ) -> impl Future<Item = (), Error = ()> {
     let span = span!(Level::TRACE, "api");
     let _enter = span.enter();
 
+    let unix_socket_cfg = job_config.cfg.general.unix_socket.clone();
+
+    // Stable version of the stats endpoint, for the v2 API
+    let stats_v2 = v2::stats(job_config.clone(), stats.clone());
+
     // WARNING: Not stable, will be replaced soon
     // Kept for testing mainly
+    let job_config11 = job_config.clone();
     let stats = get()
         .and(path("stats"))
-        .map(move || reply::json(&(*stats.clone().read().expect("open stats database"))));
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            let mut stats = stats
+                .read()
+                .expect("open stats database")
+                .with_rates(Utc::now());
+            stats.queues = job_config11.gauges.stats();
+            stats.runs = job_config11
+                .runs
+                .stats(Utc::now(), &job_config11.cfg.processing.reporting.runs);
+            reply::json(&stats)
+        });
 
     // New endpoints, following Rudder's API format
-    let info = get().and(path("info")).map(move || {
-        ApiResponse::new::<Error>("getSystemInfo", Ok(Some(Info::new())), None).reply()
-    });
+    let info = get()
+        .and(path("info"))
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            ApiResponse::new::<Error>("getSystemInfo", Ok(Some(Info::new())), None).reply()
+        });
 
     let job_config0 = job_config.clone();
-    let reload = post().and(path("reload")).map(move || {
-        ApiResponse::<()>::new::<Error>(
-            "reloadConfiguration",
-            job_config0.clone().reload().map(|_| None),
-            None,
-        )
-        .reply()
-    });
+    let reload = post()
+        .and(path("reload"))
+        .and(authorize(job_config.clone(), ApiScope::Reload))
+        .map(move || {
+            ApiResponse::<()>::new::<Error>(
+                "reloadConfiguration",
+                job_config0.clone().reload().map(|_| None),
+                None,
+            )
+            .reply()
+        });
 
     let job_config1 = job_config.clone();
-    let status = get().and(path("status")).map(move || {
-        ApiResponse::new::<Error>(
-            "getStatus",
-            Ok(Some(Status::poll(job_config1.clone()))),
-            None,
-        )
-        .reply()
+    let status = get()
+        .and(path("status"))
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            ApiResponse::new::<Error>(
+                "getStatus",
+                Ok(Some(Status::poll(job_config1.clone()))),
+                None,
+            )
+            .reply()
+        });
+
+    // Cheap checks for load balancers and service managers
+    let liveness = get()
+        .and(path("liveness"))
+        .map(move || ApiResponse::<()>::new::<Error>("getLiveness", Ok(None), None).reply());
+
+    let job_config12 = job_config.clone();
+    let readiness = get().and(path("readiness")).map(move || {
+        let readiness = Readiness::poll(job_config12.clone());
+        let status_code = if readiness.ready {
+            None
+        } else {
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        };
+        ApiResponse::new::<Error>("getReadiness", Ok(Some(readiness)), status_code).reply()
     });
 
+    let job_config13 = job_config.clone();
+    let runs = get()
+        .and(path("runs"))
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            ApiResponse::new::<Error>(
+                "getNodeRuns",
+                Ok(Some(job_config13.runs.status(
+                    Utc::now(),
+                    &job_config13.cfg.processing.reporting.runs,
+                ))),
+                None,
+            )
+            .reply()
+        });
+
+    let job_config8 = job_config.clone();
+    let replay_failed = post()
+        .and(path("replay"))
+        .and(path::param::<ReplayKind>())
+        .and(authorize(job_config.clone(), ApiScope::Reload))
+        .and(query::<ReplayFilter>())
+        .map(move |kind: ReplayKind, filter: ReplayFilter| {
+            let processing = &job_config8.cfg.processing;
+            let replayed = if job_config8
+                .pressure
+                .refuses_uploads(&kind.incoming(processing))
+            {
+                warn!("refused: disk usage is over the high watermark");
+                Err(Error::UploadsRefused)
+            } else {
+                replay(processing, kind, &filter)
+            };
+            let status_code = replayed.as_ref().err().map(v2::status_code);
+            ApiResponse::new("replayFailedFiles", replayed.map(Some), status_code).reply()
+        });
+
+    let job_config14 = job_config.clone();
+    let archive = get()
+        .and(path("archive"))
+        .and(path::param::<ReplayKind>())
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .and(query::<ReplayFilter>())
+        .map(move |kind, filter: ReplayFilter| {
+            let files = job_config14
+                .cfg
+                .processing
+                .archive
+                .as_ref()
+                .ok_or(Error::ArchiveDisabled)
+                .and_then(|cfg| archive::list(cfg, kind, &filter));
+            let status_code = files.as_ref().err().map(v2::status_code);
+            ApiResponse::new("listArchivedFiles", files.map(Some), status_code).reply()
+        });
+
+    let job_config9 = job_config.clone();
+    let topology = get()
+        .and(path("topology"))
+        .and(path::end())
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            ApiResponse::new::<Error>(
+                "getTopology",
+                Ok(Some(
+                    job_config9
+                        .nodes
+                        .read()
+                        .expect("Cannot read nodes list")
+                        .topology(),
+                )),
+                None,
+            )
+            .reply()
+        });
+
+    let job_config10 = job_config.clone();
+    let topology_dot = get()
+        .and(path("topology"))
+        .and(path("dot"))
+        .and(path::end())
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            reply::with_header(
+                job_config10
+                    .nodes
+                    .read()
+                    .expect("Cannot read nodes list")
+                    .topology_dot(),
+                "content-type",
+                "text/vnd.graphviz",
+            )
+        });
+
     // Old compatible endpoints
 
     let job_config2 = job_config.clone();
-    let node_id =
-        post()
-            .and(path("nodes"))
-            .and(path::param::<String>().and(body::form()).and_then(
-                move |node_id, simple_map: HashMap<String, String>| match RemoteRun::new(
-                    RemoteRunTarget::Nodes(vec![node_id]),
-                    &simple_map,
-                ) {
-                    Ok(handle) => handle.run(job_config2.clone()),
-                    Err(e) => Err(custom(e.to_string())),
-                },
-            ));
+    let node_id = post()
+        .and(path("nodes"))
+        .and(authorize(job_config.clone(), ApiScope::RemoteRun))
+        .and(
+            path::param::<String>()
+                .and(body::form())
+                .and(warp::header::optional::<String>(TRACEPARENT))
+                .and_then(
+                    move |node_id, simple_map: HashMap<String, String>, traceparent| {
+                        match RemoteRun::new(RemoteRunTarget::Nodes(vec![node_id]), &simple_map) {
+                            Ok(handle) => handle.run(job_config2.clone(), traceparent),
+                            Err(e) => Err(custom(e.to_string())),
+                        }
+                    },
+                ),
+        );
 
     let job_config3 = job_config.clone();
     let nodes =
         post()
             .and(path("nodes"))
-            .and(path::end().and(body::form()).and_then(
-                move |simple_map: HashMap<String, String>| {
-                    match simple_map.get("nodes") {
-                        Some(nodes) => match RemoteRun::new(
-                            RemoteRunTarget::Nodes(
-                                nodes
-                                    .split(',')
-                                    .map(|s| s.to_string())
-                                    .collect::<Vec<String>>(),
-                            ),
-                            &simple_map,
-                        ) {
-                            Ok(handle) => handle.run(job_config3.clone()),
-                            Err(e) => Err(custom(e.to_string())),
-                        },
-                        None => Err(custom(Error::MissingTargetNodes)),
-                    }
-                },
-            ));
+            .and(authorize(job_config.clone(), ApiScope::RemoteRun))
+            .and(
+                path::end()
+                    .and(body::form())
+                    .and(warp::header::optional::<String>(TRACEPARENT))
+                    .and_then(move |simple_map: HashMap<String, String>, traceparent| {
+                        match simple_map.get("nodes") {
+                            Some(nodes) => match RemoteRun::new(
+                                RemoteRunTarget::Nodes(
+                                    nodes
+                                        .split(',')
+                                        .map(|s| s.to_string())
+                                        .collect::<Vec<String>>(),
+                                ),
+                                &simple_map,
+                            ) {
+                                Ok(handle) => handle.run(job_config3.clone(), traceparent),
+                                Err(e) => Err(custom(e.to_string())),
+                            },
+                            None => Err(custom(Error::MissingTargetNodes)),
+                        }
+                    }),
+            );
 
     let job_config4 = job_config.clone();
-    let all = post().and(path("all")).and(body::form()).and_then(
-        move |simple_map: HashMap<String, String>| match RemoteRun::new(
-            RemoteRunTarget::All,
-            &simple_map,
-        ) {
-            Ok(handle) => handle.run(job_config4.clone()),
-            Err(e) => Err(custom(e.to_string())),
-        },
-    );
+    let all = post()
+        .and(path("all"))
+        .and(authorize(job_config.clone(), ApiScope::RemoteRun))
+        .and(body::form())
+        .and(warp::header::optional::<String>(TRACEPARENT))
+        .and_then(move |simple_map: HashMap<String, String>, traceparent| {
+            match RemoteRun::new(RemoteRunTarget::All, &simple_map) {
+                Ok(handle) => handle.run(job_config4.clone(), traceparent),
+                Err(e) => Err(custom(e.to_string())),
+            }
+        });
 
     let job_config5 = job_config.clone();
     let shared_files_put = put()
@@ -190,8 +428,14 @@ pub fn run(
         .and(path::param::<String>())
         .and(query::<SharedFilesPutParams>())
         .and(body::concat())
+        .and(warp::header::optional::<String>(TRACEPARENT))
         .map(
-            move |target_id, source_id, file_id, params: SharedFilesPutParams, buf: FullBody| {
+            move |target_id,
+                  source_id,
+                  file_id,
+                  params: SharedFilesPutParams,
+                  buf: FullBody,
+                  traceparent| {
                 reply::with_status(
                     "".to_string(),
                     match shared_files::put(
@@ -201,11 +445,12 @@ pub fn run(
                         params,
                         job_config5.clone(),
                         buf,
+                        traceparent,
                     ) {
                         Ok(x) => x,
                         Err(e) => {
                             error!("error while processing request: {}", e);
-                            StatusCode::INTERNAL_SERVER_ERROR
+                            shared_files::status_v1(&e)
                         }
                     },
                 )
@@ -249,29 +494,85 @@ pub fn run(
     // Routing
     // // /api/ for public API, /relay-api/ for internal relay API
     let base = path("rudder").and(path("relay-api"));
-    let system = path("system").and(stats.or(status).or(reload).or(info));
+    let system_common = status
+        .or(liveness)
+        .or(readiness)
+        .or(reload)
+        .or(info)
+        .or(runs)
+        .or(replay_failed)
+        .or(archive)
+        .or(topology);
+    let system = path("system").and(stats.or(topology_dot).or(system_common.clone()));
     let remote_run = path("remote-run").and(nodes.or(all).or(node_id));
     let shared_files = path("shared-files").and((shared_files_put).or(shared_files_head));
     let shared_folder = path("shared-folder").and(shared_folder_head.or(shared_folder_get));
 
+    let system_2 = path("system").and(
+        stats_v2
+            .or(v2::topology_dot(job_config.clone()))
+            .or(system_common),
+    );
+
     // Global route for /1/
-    let routes_1 = base
-        .and(path("1"))
-        .and(system.or(remote_run).or(shared_files).or(shared_folder))
-        .recover(customize_error)
+    let routes_1 = path("1").and(
+        system
+            .or(remote_run)
+            .or(shared_files)
+            .or(shared_folder)
+            .recover(customize_error),
+    );
+
+    // Global route for /2/, with JSON answers and client errors
+    let routes_2 = path("2").and(
+        system_2
+            .or(v2::remote_run(job_config.clone()))
+            .or(v2::shared_files(job_config.clone()))
+            .or(v2::shared_folder(job_config))
+            .recover(v2::customize_error),
+    );
+
+    let routes = base
+        .and(routes_1.or(routes_2))
         .with(warp::log("relayd::relay-api"));
 
-    info!("Starting API on {}", listen);
     // TODO graceful shutdown
-    warp::serve(routes_1).bind(listen)
+    let tcp = match unix_socket_cfg {
+        Some(ref cfg) if cfg.exclusive => Either::A(future::ok(())),
+        _ => {
+            info!("Starting API on {}", listen);
+            Either::B(warp::serve(routes.clone()).bind(listen))
+        }
+    };
+    let unix = match (unix_socket, unix_socket_cfg) {
+        (Some(listener), Some(cfg)) => {
+            info!("Starting API on {:?}", cfg.path);
+            Either::A(warp::serve(routes).serve_incoming(listener.incoming()))
+        }
+        _ => Either::B(future::ok(())),
+    };
+    tcp.join(unix).map(|_| ())
 }
 
-fn customize_error(reject: Rejection) -> Result<impl Reply, Rejection> {
+fn customize_error(reject: Rejection) -> Result<Response<Body>, Rejection> {
+    if let Some(e) = reject.find_cause::<Error>() {
+        let status = match e {
+            Error::MissingApiToken | Error::InvalidApiToken => Some(StatusCode::UNAUTHORIZED),
+            Error::MissingApiScope { .. } => Some(StatusCode::FORBIDDEN),
+            _ => None,
+        };
+        if let Some(status) = status {
+            return Ok(ApiResponse::<()>::new("authorize", Err(e), Some(status)).response());
+        }
+    }
+
+    let mut response = Response::new(Body::empty());
     // See https://github.com/seanmonstar/warp/issues/77
     // We generally prefer 404 to 405 when they are conflicting.
     // Maybe be improved in the future
     if reject.is_not_found() || reject.status() == StatusCode::METHOD_NOT_ALLOWED {
-        Ok(reply::with_status("", StatusCode::NOT_FOUND))
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        Ok(response)
     } else {
         Err(reject)
     }
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::{ApiConfig, ApiScope},
+    error::Error,
+    JobConfig,
+};
+use openssl::memcmp;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{info, warn};
+use warp::{
+    filters::{method::method, path::FullPath},
+    header,
+    http::Method,
+    path,
+    reject::custom,
+    Filter, Rejection,
+};
+
+const BEARER: &str = "bearer ";
+
+/// Constant-time comparison, to avoid leaking tokens through timing
+fn is_same_token(expected: &str, received: &str) -> bool {
+    expected.len() == received.len() && memcmp::eq(expected.as_bytes(), received.as_bytes())
+}
+
+/// Identifies the caller, for audit logs, and checks it is allowed to use `scope`
+fn caller(
+    cfg: &ApiConfig,
+    remote: Option<SocketAddr>,
+    authorization: Option<&str>,
+    scope: ApiScope,
+) -> Result<String, Error> {
+    if cfg.tokens.is_empty() {
+        return Ok("anonymous caller".to_string());
+    }
+
+    match authorization {
+        Some(header)
+            if header.len() > BEARER.len()
+                && header[..BEARER.len()].eq_ignore_ascii_case(BEARER) =>
+        {
+            let received = header[BEARER.len()..].trim();
+            match cfg
+                .tokens
+                .iter()
+                .find(|t| is_same_token(&t.token.value(), received))
+            {
+                Some(token) if token.scopes.contains(&scope) => Ok(format!("token {}", token.name)),
+                Some(token) => Err(Error::MissingApiScope {
+                    token: token.name.clone(),
+                    scope,
+                }),
+                None => Err(Error::InvalidApiToken),
+            }
+        }
+        Some(_) => Err(Error::InvalidApiToken),
+        None if cfg.trust_loopback && remote.map(|a| a.ip().is_loopback()).unwrap_or(false) => {
+            Ok("local caller".to_string())
+        }
+        // Only requests received on the Unix socket have no remote address
+        None if cfg.trust_unix_socket && remote.is_none() => Ok("local caller".to_string()),
+        None => Err(Error::MissingApiToken),
+    }
+}
+
+/// Rejects requests without a token allowing `scope`, and logs the caller
+/// of accepted requests
+pub fn authorize(
+    job_config: Arc<JobConfig>,
+    scope: ApiScope,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(header::optional::<String>("authorization"))
+        .and(method())
+        .and(path::full())
+        .and_then(
+            move |remote: Option<SocketAddr>,
+                  authorization: Option<String>,
+                  method: Method,
+                  path: FullPath| {
+                let address = remote
+                    .map(|a| a.to_string())
+                    .unwrap_or_else(|| "unix socket".to_string());
+                match caller(
+                    &job_config.cfg.api,
+                    remote,
+                    authorization.as_ref().map(String::as_str),
+                    scope,
+                ) {
+                    Ok(caller) => {
+                        info!(
+                            "audit: {} {} called by {} from {}",
+                            method,
+                            path.as_str(),
+                            caller,
+                            address
+                        );
+                        Ok(())
+                    }
+                    Err(e) => {
+                        warn!(
+                            "audit: {} {} refused from {}: {}",
+                            method,
+                            path.as_str(),
+                            address,
+                            e
+                        );
+                        Err(custom(e))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{main::ApiToken, Secret};
+
+    #[test]
+    fn it_checks_api_tokens() {
+        let local = Some("127.0.0.1:42000".parse().unwrap());
+        let remote = Some("192.168.1.2:42000".parse().unwrap());
+        let mut cfg = ApiConfig::default();
+
+        // Authentication disabled
+        assert!(caller(&cfg, remote, None, ApiScope::Reload).is_ok());
+
+        cfg.tokens.push(ApiToken {
+            name: "automation".to_string(),
+            token: Secret::new("TOKEN".to_string()),
+            scopes: vec![ApiScope::Read].into_iter().collect(),
+        });
+        assert_eq!(
+            caller(&cfg, remote, Some("Bearer TOKEN"), ApiScope::Read).unwrap(),
+            "token automation"
+        );
+        assert!(caller(&cfg, remote, Some("bearer TOKEN"), ApiScope::Read).is_ok());
+        match caller(&cfg, remote, Some("Bearer TOKEN"), ApiScope::Reload) {
+            Err(Error::MissingApiScope { .. }) => (),
+            _ => panic!("should not allow reload"),
+        }
+        match caller(&cfg, remote, Some("Bearer TOKEM"), ApiScope::Read) {
+            Err(Error::InvalidApiToken) => (),
+            _ => panic!("should refuse invalid token"),
+        }
+        match caller(&cfg, remote, Some("Basic dXNlcjpwYXNz"), ApiScope::Read) {
+            Err(Error::InvalidApiToken) => (),
+            _ => panic!("should refuse other schemes"),
+        }
+        match caller(&cfg, remote, None, ApiScope::Read) {
+            Err(Error::MissingApiToken) => (),
+            _ => panic!("should refuse missing token"),
+        }
+
+        // Local requests
+        assert!(caller(&cfg, local, None, ApiScope::Reload).is_ok());
+        cfg.trust_loopback = false;
+        assert!(caller(&cfg, local, None, ApiScope::Read).is_err());
+        assert!(caller(&cfg, None, None, ApiScope::RemoteRun).is_ok());
+        cfg.trust_unix_socket = false;
+        assert!(caller(&cfg, None, None, ApiScope::Read).is_err());
+    }
+}
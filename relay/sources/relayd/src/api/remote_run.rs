@@ -2,7 +2,8 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
-    configuration::main::RemoteRun as RemoteRunCfg, data::node::Host, error::Error, JobConfig,
+    configuration::main::RemoteRun as RemoteRunCfg, data::node::Host, error::Error, telemetry,
+    JobConfig,
 };
 use futures::{Future, Stream};
 use hyper::{Body, Chunk};
@@ -98,7 +99,12 @@ impl RemoteRun {
     pub fn run(
         &self,
         job_config: Arc<JobConfig>,
+        traceparent: Option<String>,
     ) -> Result<impl warp::reply::Reply, warp::reject::Rejection> {
+        let span = span!(Level::INFO, "remote_run", target = ?self.target);
+        telemetry::set_parent(&span, traceparent.as_deref());
+        let _enter = span.enter();
+
         debug!(
             "Starting remote run (asynchronous: {}, keep_output: {})",
             self.run_parameters.asynchronous, self.run_parameters.keep_output
@@ -179,6 +185,58 @@ impl RemoteRun {
         }
     }
 
+    /// Local and forwarded runs output
+    fn output(
+        &self,
+        job_config: Arc<JobConfig>,
+    ) -> impl Stream<Item = Chunk, Error = Error> + Send + 'static {
+        self.run_parameters
+            .remote_run(
+                &job_config.cfg.remote_run,
+                self.target.neighbors(job_config.clone()),
+                self.run_parameters.asynchronous,
+            )
+            .select(select_all(
+                self.target
+                    .next_hops(job_config.clone())
+                    .iter()
+                    .map(|(relay, target)| {
+                        self.forward_call(job_config.clone(), relay.clone(), target.clone())
+                    }),
+            ))
+    }
+
+    /// Runs for the v2 API, which does not stream output. Asynchronous runs return
+    /// immediately without output, synchronous runs return the output once finished
+    /// when `keep_output` is set.
+    pub fn run_collect(
+        &self,
+        job_config: Arc<JobConfig>,
+        traceparent: Option<String>,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Error> + Send> {
+        let span = span!(Level::INFO, "remote_run", target = ?self.target);
+        telemetry::set_parent(&span, traceparent.as_deref());
+        let _enter = span.enter();
+
+        debug!(
+            "Starting remote run (asynchronous: {}, keep_output: {})",
+            self.run_parameters.asynchronous, self.run_parameters.keep_output
+        );
+        if self.run_parameters.asynchronous {
+            tokio::spawn(RemoteRun::consume(self.output(job_config)));
+            Box::new(futures::future::ok(None))
+        } else {
+            let keep_output = self.run_parameters.keep_output;
+            Box::new(self.output(job_config).concat2().map(move |output| {
+                if keep_output {
+                    Some(String::from_utf8_lossy(&output).into_owned())
+                } else {
+                    None
+                }
+            }))
+        }
+    }
+
     fn forward_call(
         &self,
         job_config: Arc<JobConfig>,
@@ -209,29 +267,29 @@ impl RemoteRun {
             params.insert("nodes", nodes.join(","));
         }
 
-        job_config
-            .client
-            .clone()
-            .post(&format!(
+        telemetry::inject(
+            job_config.client.clone().post(&format!(
                 "https://{}/rudder/relay-api/remote-run/{}",
                 node,
                 match target {
                     RemoteRunTarget::All => "all",
                     RemoteRunTarget::Nodes(_) => "nodes",
                 },
-            ))
-            .form(&params)
-            .send()
-            .map(|response| response.into_body())
-            .flatten_stream()
-            .map_err(|e| {
-                error!("{}", e);
-                e.into()
-            })
-            // Don't fail if a relay is not available,
-            // just log it
-            .or_else(|_: Error| futures::future::empty())
-            .map(|c| c.into())
+            )),
+            telemetry::current_context(),
+        )
+        .form(&params)
+        .send()
+        .map(|response| response.into_body())
+        .flatten_stream()
+        .map_err(|e| {
+            error!("{}", e);
+            e.into()
+        })
+        // Don't fail if a relay is not available,
+        // just log it
+        .or_else(|_: Error| futures::future::empty())
+        .map(|c| c.into())
     }
 }
 
@@ -271,8 +329,9 @@ impl RemoteRunTarget {
     }
 }
 
+/// Agent condition (class) defined for a remote run
 #[derive(Debug, PartialEq)]
-struct Condition {
+pub struct Condition {
     data: String,
 }
 
@@ -428,6 +487,7 @@ impl RunParameters {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::{prop_assert, prop_assert_eq, proptest};
 
     #[test]
     fn it_handles_command_injection() {
@@ -464,4 +524,17 @@ mod tests {
     fn it_handles_too_long_conditions() {
         assert!(Condition::from_str("Qr6U6s161z8umvzZTMSPtsZpe3s2sAjwUeCD5pbzvwtT9jg8AsqaW1hbgJhDvOQ34J6GdUS0bEJLKz4zfWHO70rYdq70jrKip5gYwdbVyB7APyK3RRAGHGS7EZ8bUNEXUlHp1QsYOQeqPyPKCCJUYhAzWsD8b1lC4gOkmzATyabEBhaoAb5TLELtBra5dS1YzG1TxgHEthd8z7Qf7PHeltK1X628rfwPqVY2FHkgBGvNMAFTYUdnyabV0j7PHal4f31nNRCqZPdUv6iIlHHQo0oUQlwZ7ATUNYt2cznLYu5v8RhBL0uqOxMD9xHAnRxYRo57BDQxkunNyb7oTjruainGIqbXoDPjcKCQRrf3IrVvAQ6mwAgIdEzJkxBaZUkAGeNQFZEh5b3zJSryfgML2kc87ohLMmsIh5OvNnrPUipSnkpGruJV2uCRX1EYNH6skC9QY1oji6D3SYNeH0lZFIe8goO0Sa1geORlB5UpDwrGeWKgo6k7xBORpPdiVFjR1fAsO7po2CPrR2OwBv6IP0VcU4pPY3eIXgSWSecRE4UXDR2dyaSqSyo4E2l4KAIwy7LieKechiA3yROPrkk0MBC6JfUeOXrCvFBDpQ29Q0TE1J8LK0Xt8DexBZdTUI2ni3Gs1Clli4cvXwfyvTGWFpnTsgS7S7zOyYaIGVqI8UmmszQM8Y4IZBt5nmUsMcrsNBvp4ZqseHoaR0WHTp93c6l83dw3EuuQyFvbqmwQAeDNOrSW2YYAL6Ab5ru5XoRfxCB0LitHWeocyUCo6ukE7YnS8ZmqBIWjLizUD7OnaCSWajdalXINhHDmUQgBehAbPOOiFSlLEyUQeBfZEmWvV5CJ4NN2gBgpDGJywm9mKxr8KcN1TPtp4rGpVYWgDK4N3RjUcQiH7rkSN2zd3vb1MkvtvQsMSX45CpmVng6UQf2LPeRIBNBEaiiNeQAvhfTm86EWNkOwnhHr8QHd7yzLQ6kd4D7Q05oNkRrDDNn5zhS6rvJCujTVFqp5eMa2jbiUa").is_err());
     }
+
+    proptest! {
+        #[test]
+        fn it_parses_valid_conditions(ref condition in "[a-zA-Z0-9][a-zA-Z0-9_]{0,1023}") {
+            prop_assert_eq!(&Condition::from_str(condition).unwrap().data, condition);
+        }
+
+        #[test]
+        fn it_refuses_invalid_conditions(ref prefix in "[a-zA-Z0-9_]{0,10}", ref suffix in "[^a-zA-Z0-9_]+") {
+            prop_assert!(Condition::from_str(&format!("{}{}", prefix, suffix)).is_err());
+            prop_assert!(Condition::from_str(&format!("_{}", prefix)).is_err());
+        }
+    }
 }
@@ -4,7 +4,7 @@
 use crate::{
     data::shared_file::{Metadata, SharedFile},
     error::Error,
-    JobConfig,
+    telemetry, JobConfig,
 };
 use bytes::IntoBuf;
 use chrono::Utc;
@@ -46,6 +46,8 @@ impl SharedFilesPutParams {
     }
 }
 
+/// Refusals are returned as errors, see `status_v1` for the status
+/// codes of the v1 API
 pub fn put(
     target_id: String,
     source_id: String,
@@ -53,6 +55,7 @@ pub fn put(
     params: SharedFilesPutParams,
     job_config: Arc<JobConfig>,
     body: FullBody,
+    traceparent: Option<String>,
 ) -> Result<StatusCode, Error> {
     let span = span!(
         Level::INFO,
@@ -61,6 +64,7 @@ pub fn put(
         source_id = %source_id,
         file_id = %file_id,
     );
+    telemetry::set_parent(&span, traceparent.as_deref());
     let _enter = span.enter();
 
     let file = SharedFile::new(source_id, target_id, file_id)?;
@@ -85,21 +89,21 @@ fn put_forward(
     job_config: Arc<JobConfig>,
     body: FullBody,
 ) -> Result<StatusCode, Error> {
-    job_config
-        .client
-        .clone()
-        .put(&format!(
+    telemetry::inject(
+        job_config.client.clone().put(&format!(
             "{}/{}/{}",
             job_config.cfg.output.upstream.url,
             "relay-api/shared-files",
             file.url(),
-        ))
-        .query(&params)
-        .body(body.into_buf().collect::<Vec<u8>>())
-        .send()
-        .wait()
-        .map(|r| r.status())
-        .map_err(|e| e.into())
+        )),
+        telemetry::current_context(),
+    )
+    .query(&params)
+    .body(body.into_buf().collect::<Vec<u8>>())
+    .send()
+    .wait()
+    .map(|r| r.status())
+    .map_err(|e| e.into())
 }
 
 pub fn put_local(
@@ -115,7 +119,7 @@ pub fn put_local(
         .is_subnode(&file.source_id)
     {
         warn!("unknown source {}", file.source_id);
-        return Ok(StatusCode::NOT_FOUND);
+        return Err(Error::UnknownSourceNode(file.source_id));
     }
 
     let mut stream = BufReader::new(body.into_buf().reader());
@@ -149,7 +153,7 @@ pub fn put_local(
             "hash of public key ({}) does not match known hash ({})",
             key_hash, known_key_hash
         );
-        return Ok(StatusCode::NOT_FOUND);
+        return Err(Error::InvalidPublicKey(file.source_id));
     }
 
     // Read file content
@@ -164,12 +168,12 @@ pub fn put_local(
         Ok(is_valid) => {
             if !is_valid {
                 warn!("invalid signature");
-                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+                return Err(Error::InvalidSignature);
             }
         }
         Err(e) => {
             warn!("error checking file signature: {}", e);
-            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(e);
         }
     }
 
@@ -187,7 +191,7 @@ pub fn put_local(
                 .timestamp(),
                 Err(e) => {
                     warn!("invalid ttl: {}", e);
-                    return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+                    return Err(e);
                 }
             }
         ),
@@ -196,6 +200,14 @@ pub fn put_local(
     Ok(StatusCode::OK)
 }
 
+/// Status codes of the v1 API, kept for compatibility
+pub fn status_v1(error: &Error) -> StatusCode {
+    match error {
+        Error::UnknownSourceNode(_) | Error::InvalidPublicKey(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct SharedFilesHeadParams {
     hash: String,
@@ -1,9 +1,19 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::{api::ApiResult, check_configuration, output::database::ping, Error, JobConfig};
+use crate::{
+    api::ApiResult,
+    configuration::{
+        logging::LogConfig,
+        main::{Configuration, InventoryOutputSelect, ReportingOutputSelect},
+    },
+    data::node::NodesListReload,
+    input::pressure::DirectoryUsage,
+    output::database::{ping, DatabaseOutage},
+    Error, JobConfig,
+};
 use serde::Serialize;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 use structopt::clap::crate_version;
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
@@ -33,6 +43,26 @@ pub struct State {
     details: Option<String>,
 }
 
+impl State {
+    fn success() -> Self {
+        State {
+            status: ApiResult::Success,
+            details: None,
+        }
+    }
+
+    fn error(details: String) -> Self {
+        State {
+            status: ApiResult::Error,
+            details: Some(details),
+        }
+    }
+
+    fn is_success(&self) -> bool {
+        self.status == ApiResult::Success
+    }
+}
+
 impl From<Result<(), Error>> for State {
     fn from(result: Result<(), Error>) -> Self {
         match result {
@@ -51,7 +81,16 @@ impl From<Result<(), Error>> for State {
 #[derive(Serialize, Debug, PartialEq, Eq)]
 pub struct Status {
     database: Option<State>,
+    /// Only present during an outage and the following resume
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database_outage: Option<DatabaseOutage>,
     configuration: State,
+    /// Only contains directories with configured watermarks
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    disk_usage: BTreeMap<String, DirectoryUsage>,
+    /// Only present after a reload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes_list_reload: Option<NodesListReload>,
 }
 
 impl Status {
@@ -61,9 +100,75 @@ impl Status {
                 .pool
                 .clone()
                 .map(|p| ping(&p).map_err(|e| e).into()),
-            configuration: check_configuration(&job_config.cli_cfg.configuration_dir)
-                .map_err(|e| e)
+            database_outage: job_config.database_health.outage(),
+            // Only parsing, semantic checks are done by `rudder-relayd --test`
+            configuration: Configuration::new(&job_config.cli_cfg.configuration_dir)
+                .and_then(|_| LogConfig::new(&job_config.cli_cfg.configuration_dir))
+                .map(|_| ())
                 .into(),
+            disk_usage: job_config.pressure.status(),
+            nodes_list_reload: job_config
+                .nodes_reload
+                .read()
+                .expect("could not read nodes list reload")
+                .clone(),
+        }
+    }
+}
+
+/// Only relies on states maintained by the running jobs, to be cheap
+/// enough to be called frequently
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Only contains the checks relevant for the enabled outputs
+    checks: BTreeMap<String, State>,
+}
+
+impl Readiness {
+    pub fn poll(job_config: Arc<JobConfig>) -> Self {
+        let processing = &job_config.cfg.processing;
+        let mut checks = BTreeMap::new();
+
+        if processing.reporting.output == ReportingOutputSelect::Database {
+            let database = match job_config.database_health.outage() {
+                Some(outage) if !outage.resuming => {
+                    State::error(format!("unavailable since {}", outage.since))
+                }
+                _ => State::success(),
+            };
+            let _ = checks.insert("database".to_string(), database);
+        }
+
+        if processing.reporting.output == ReportingOutputSelect::Upstream
+            || processing.inventory.output == InventoryOutputSelect::Upstream
+        {
+            let upstream = match job_config.upstream_health.error() {
+                Some(e) => State::error(e),
+                None => State::success(),
+            };
+            let _ = checks.insert("upstream".to_string(), upstream);
+        }
+
+        if processing.reporting.output.is_enabled() || processing.inventory.output.is_enabled() {
+            let failures = job_config.watchers.failures();
+            let watchers = if failures.is_empty() {
+                State::success()
+            } else {
+                State::error(
+                    failures
+                        .iter()
+                        .map(|(path, reason)| format!("{}: {}", path, reason))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            };
+            let _ = checks.insert("watchers".to_string(), watchers);
+        }
+
+        Self {
+            ready: checks.values().all(State::is_success),
+            checks,
         }
     }
 }
@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Endpoints specific to the v2 API. All answers use the `ApiResponse` format,
+//! and client errors give 4xx status codes.
+
+use crate::{
+    api::{
+        auth::authorize,
+        remote_run::{RemoteRun, RemoteRunTarget},
+        shared_files::{self, SharedFilesHeadParams, SharedFilesPutParams},
+        shared_folder::{self, SharedFolderParams},
+        ApiResponse,
+    },
+    configuration::main::ApiScope,
+    error::Error,
+    stats::Stats,
+    telemetry::TRACEPARENT,
+    JobConfig,
+};
+use chrono::Utc;
+use futures::{future::Either, Future};
+use hyper::Body;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::error;
+use warp::{
+    body::{self, FullBody},
+    filters::{method::v2::*, path::Peek},
+    http::{Response, StatusCode},
+    path, query, Filter, Rejection, Reply,
+};
+
+pub fn status_code(error: &Error) -> StatusCode {
+    match error {
+        Error::InvalidCondition { .. }
+        | Error::MaxLengthCondition { .. }
+        | Error::MissingTargetNodes
+        | Error::InvalidHashType { .. }
+        | Error::InvalidHash(_)
+        | Error::InvalidHeader(_)
+        | Error::DuplicateHeader(_)
+        | Error::MissingHeader(_)
+        | Error::InvalidDuration(_)
+        | Error::InvalidHexadecimalValue(_)
+        | Error::InvalidSharedFile(_)
+        | Error::InvalidFileKind(_)
+        | Error::ParseBoolean(_)
+        | Error::IntegerParsing(_)
+        | Error::InvalidSignature => StatusCode::BAD_REQUEST,
+        Error::MissingApiToken | Error::InvalidApiToken => StatusCode::UNAUTHORIZED,
+        Error::MissingApiScope { .. } | Error::InvalidPublicKey(_) => StatusCode::FORBIDDEN,
+        Error::UnknownNode(_) | Error::UnknownSourceNode(_) | Error::ArchiveDisabled => {
+            StatusCode::NOT_FOUND
+        }
+        Error::UploadsRefused => StatusCode::INSUFFICIENT_STORAGE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl<T: Serialize> ApiResponse<T> {
+    /// Status code depends on the kind of error
+    fn from_result(action: &'static str, data: Result<Option<T>, Error>) -> Self {
+        let status_code = data.as_ref().err().map(status_code);
+        if let Err(ref e) = data {
+            error!("error while processing request: {}", e);
+        }
+        Self::new(action, data, status_code)
+    }
+}
+
+/// For endpoints answering with a bare status code
+fn from_status(action: &'static str, status: Result<StatusCode, Error>) -> ApiResponse<()> {
+    match status {
+        Ok(status) if status.is_success() || status.is_redirection() => {
+            ApiResponse::new::<Error>(action, Ok(None), Some(status))
+        }
+        Ok(status) => ApiResponse::new(
+            action,
+            Err(status.canonical_reason().unwrap_or("unknown error")),
+            Some(status),
+        ),
+        Err(e) => ApiResponse::from_result(action, Err(e)),
+    }
+}
+
+pub fn stats(
+    job_config: Arc<JobConfig>,
+    stats: Arc<RwLock<Stats>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    get()
+        .and(path("stats"))
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            let mut stats = stats
+                .read()
+                .expect("open stats database")
+                .with_rates(Utc::now());
+            stats.queues = job_config.gauges.stats();
+            stats.runs = job_config
+                .runs
+                .stats(Utc::now(), &job_config.cfg.processing.reporting.runs);
+            ApiResponse::new::<Error>("getStats", Ok(Some(stats)), None).reply()
+        })
+}
+
+pub fn topology_dot(
+    job_config: Arc<JobConfig>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    get()
+        .and(path("topology"))
+        .and(path("dot"))
+        .and(path::end())
+        .and(authorize(job_config.clone(), ApiScope::Read))
+        .map(move || {
+            let dot = job_config
+                .nodes
+                .read()
+                .expect("Cannot read nodes list")
+                .topology_dot();
+            ApiResponse::new::<Error>("getTopologyDot", Ok(Some(dot)), None).reply()
+        })
+}
+
+#[derive(Serialize, Debug)]
+struct RemoteRunOutput {
+    output: String,
+}
+
+fn remote_run_reply(
+    job_config: Arc<JobConfig>,
+    target: Result<RemoteRunTarget, Error>,
+    options: &HashMap<String, String>,
+    traceparent: Option<String>,
+) -> impl Future<Item = impl Reply, Error = Rejection> {
+    match target.and_then(|target| RemoteRun::new(target, options)) {
+        Ok(handle) => Either::A(handle.run_collect(job_config, traceparent).then(|res| {
+            Ok::<_, Rejection>(
+                ApiResponse::from_result(
+                    "remoteRun",
+                    res.map(|output| output.map(|output| RemoteRunOutput { output })),
+                )
+                .reply(),
+            )
+        })),
+        Err(e) => Either::B(futures::future::ok(
+            ApiResponse::<RemoteRunOutput>::from_result("remoteRun", Err(e)).reply(),
+        )),
+    }
+}
+
+pub fn remote_run(
+    job_config: Arc<JobConfig>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let job_config_node = job_config.clone();
+    let node = post()
+        .and(path("nodes"))
+        .and(path::param::<String>())
+        .and(authorize(job_config.clone(), ApiScope::RemoteRun))
+        .and(body::form())
+        .and(warp::header::optional::<String>(TRACEPARENT))
+        .and_then(
+            move |node_id, options: HashMap<String, String>, traceparent| {
+                remote_run_reply(
+                    job_config_node.clone(),
+                    Ok(RemoteRunTarget::Nodes(vec![node_id])),
+                    &options,
+                    traceparent,
+                )
+            },
+        );
+
+    let job_config_nodes = job_config.clone();
+    let nodes = post()
+        .and(path("nodes"))
+        .and(path::end())
+        .and(authorize(job_config.clone(), ApiScope::RemoteRun))
+        .and(body::form())
+        .and(warp::header::optional::<String>(TRACEPARENT))
+        .and_then(move |options: HashMap<String, String>, traceparent| {
+            let target = options
+                .get("nodes")
+                .map(|nodes| {
+                    RemoteRunTarget::Nodes(nodes.split(',').map(|s| s.to_string()).collect())
+                })
+                .ok_or(Error::MissingTargetNodes);
+            remote_run_reply(job_config_nodes.clone(), target, &options, traceparent)
+        });
+
+    let job_config_all = job_config.clone();
+    let all = post()
+        .and(path("all"))
+        .and(authorize(job_config, ApiScope::RemoteRun))
+        .and(body::form())
+        .and(warp::header::optional::<String>(TRACEPARENT))
+        .and_then(move |options: HashMap<String, String>, traceparent| {
+            remote_run_reply(
+                job_config_all.clone(),
+                Ok(RemoteRunTarget::All),
+                &options,
+                traceparent,
+            )
+        });
+
+    path("remote-run").and(nodes.or(all).or(node))
+}
+
+pub fn shared_files(
+    job_config: Arc<JobConfig>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let job_config_put = job_config.clone();
+    let put = put()
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(query::<SharedFilesPutParams>())
+        .and(body::concat())
+        .and(warp::header::optional::<String>(TRACEPARENT))
+        .map(
+            move |target_id,
+                  source_id,
+                  file_id,
+                  params: SharedFilesPutParams,
+                  buf: FullBody,
+                  traceparent| {
+                from_status(
+                    "putSharedFile",
+                    shared_files::put(
+                        target_id,
+                        source_id,
+                        file_id,
+                        params,
+                        job_config_put.clone(),
+                        buf,
+                        traceparent,
+                    ),
+                )
+                .reply()
+            },
+        );
+
+    // HEAD answers have no body, but use the same status codes
+    let head = head()
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(query::<SharedFilesHeadParams>())
+        .map(move |target_id, source_id, file_id, params| {
+            from_status(
+                "headSharedFile",
+                shared_files::head(target_id, source_id, file_id, params, job_config.clone()),
+            )
+            .reply()
+        });
+
+    path("shared-files").and(put.or(head))
+}
+
+pub fn shared_folder(
+    job_config: Arc<JobConfig>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let job_config_head = job_config.clone();
+    let head = head()
+        .and(path::peek())
+        .and(query::<SharedFolderParams>())
+        .and_then(move |file: Peek, params| {
+            shared_folder::head(
+                params,
+                PathBuf::from(&file.as_str()),
+                job_config_head.clone(),
+            )
+            .then(|status| Ok::<_, Rejection>(from_status("headSharedFolder", status).reply()))
+        });
+    // Files are served as is
+    let get = warp::fs::dir(job_config.cfg.shared_folder.path.clone());
+
+    path("shared-folder").and(head.or(get))
+}
+
+pub fn customize_error(reject: Rejection) -> Result<Response<Body>, Rejection> {
+    let response = if let Some(e) = reject.find_cause::<Error>() {
+        let action = match e {
+            Error::MissingApiToken | Error::InvalidApiToken | Error::MissingApiScope { .. } => {
+                "authorize"
+            }
+            _ => "request",
+        };
+        ApiResponse::<()>::new(action, Err(e), Some(status_code(e)))
+    } else if reject.is_not_found() || reject.status() == StatusCode::METHOD_NOT_ALLOWED {
+        // We prefer 404 to 405 when they are conflicting, like in v1
+        ApiResponse::<()>::new("request", Err("not found"), Some(StatusCode::NOT_FOUND))
+    } else {
+        let status = reject.status();
+        ApiResponse::<()>::new(
+            "request",
+            Err(status.canonical_reason().unwrap_or("invalid request")),
+            Some(status),
+        )
+    };
+    Ok(response.response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_uses_client_error_codes() {
+        assert_eq!(
+            status_code(&Error::UnknownNode("node".to_string())),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status_code(&Error::MissingTargetNodes),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status_code(&Error::InconsistentRunlog),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -1,27 +1,166 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+pub mod check;
 pub mod cli;
 pub mod logging;
 pub mod main;
 
-use serde::Deserialize;
-use std::fmt;
+use crate::error::Error;
+use serde::{
+    de::{Deserializer, Error as SerdeError},
+    ser::{SerializeMap, Serializer},
+    Deserialize, Serialize,
+};
+use std::{
+    env, fmt,
+    fs::read_to_string,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
 
-/// Allows hiding a value in logs
-#[derive(Deserialize, PartialEq, Eq, Clone, Default)]
-#[serde(transparent)]
+/// Where the value of a secret comes from
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SecretSource {
+    /// Written in the configuration file
+    Inline,
+    /// Content of a file, without trailing newlines, like a systemd credential
+    File(PathBuf),
+    /// Environment variable
+    Env(String),
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Inline => write!(f, "inline"),
+            SecretSource::File(file) => write!(f, "file {}", file.display()),
+            SecretSource::Env(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+impl SecretSource {
+    fn read(&self) -> Result<Option<String>, Error> {
+        Ok(match self {
+            SecretSource::Inline => None,
+            SecretSource::File(file) => Some(
+                read_to_string(file)?
+                    .trim_end_matches(|c| c == '\n' || c == '\r')
+                    .to_string(),
+            ),
+            SecretSource::Env(var) => {
+                Some(env::var(var).map_err(|e| Error::InvalidSecret(format!("{}: {}", var, e)))?)
+            }
+        })
+    }
+}
+
+/// Allows hiding a value in logs.
+///
+/// Can be given inline, or read from a file or an environment variable.
+/// Files are read again on reload, the environment of the process cannot
+/// change. Clones share the value.
+#[derive(Clone)]
 pub struct Secret {
-    value: String,
+    source: SecretSource,
+    value: Arc<RwLock<String>>,
 }
 
 impl<'a> Secret {
     pub fn new(value: String) -> Self {
-        Self { value }
+        Self {
+            source: SecretSource::Inline,
+            value: Arc::new(RwLock::new(value)),
+        }
     }
 
-    pub fn value(&'a self) -> &'a str {
-        &self.value
+    pub fn from_source(source: SecretSource) -> Result<Self, Error> {
+        let value = source.read()?.unwrap_or_default();
+        Ok(Self {
+            source,
+            value: Arc::new(RwLock::new(value)),
+        })
+    }
+
+    pub fn value(&'a self) -> String {
+        self.value.read().expect("could not read secret").clone()
+    }
+
+    pub fn source(&'a self) -> &'a SecretSource {
+        &self.source
+    }
+
+    /// Reads the value again from its file, returns true when it changed
+    pub fn reload(&self) -> Result<bool, Error> {
+        let new = match self.source {
+            SecretSource::File(_) => self.source.read()?,
+            SecretSource::Inline | SecretSource::Env(_) => None,
+        };
+        match new {
+            Some(new) => {
+                let mut value = self.value.write().expect("could not write secret");
+                let changed = *value != new;
+                *value = new;
+                Ok(changed)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Default for Secret {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source && self.value() == other.value()
+    }
+}
+
+impl Eq for Secret {}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawSecret {
+            Value(String),
+            File { file: PathBuf },
+            Env { env: String },
+        }
+
+        let source = match RawSecret::deserialize(deserializer)? {
+            RawSecret::Value(value) => return Ok(Self::new(value)),
+            RawSecret::File { file } => SecretSource::File(file),
+            RawSecret::Env { env } => SecretSource::Env(env),
+        };
+        Self::from_source(source.clone())
+            .map_err(|e| D::Error::custom(format!("could not read secret from {}: {}", source, e)))
+    }
+}
+
+/// Only exposes the source, and whether an inline value is empty
+impl Serialize for Secret {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (key, value) = match &self.source {
+            SecretSource::Inline if self.value().is_empty() => return serializer.serialize_str(""),
+            SecretSource::Inline => return serializer.serialize_str("******"),
+            SecretSource::File(file) => ("file", file.display().to_string()),
+            SecretSource::Env(var) => ("env", var.clone()),
+        };
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(key, &value)?;
+        map.end()
     }
 }
 
@@ -36,3 +175,63 @@ impl fmt::Debug for Secret {
         write!(f, "\"******\"")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_reads_secrets_from_sources() {
+        #[derive(Deserialize, Serialize)]
+        struct Cfg {
+            inline: Secret,
+            file: Secret,
+            env: Secret,
+        }
+
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("password");
+        write(&file, "from file\n").unwrap();
+        env::set_var("RELAYD_TEST_SECRET", "from env");
+
+        let cfg: Cfg = toml::from_str(&format!(
+            "inline = \"inline\"\nfile = {{ file = {:?} }}\nenv = {{ env = \"RELAYD_TEST_SECRET\" }}",
+            file.display().to_string()
+        ))
+        .unwrap();
+        assert_eq!(cfg.inline.value(), "inline");
+        assert_eq!(cfg.inline.source(), &SecretSource::Inline);
+        assert_eq!(cfg.file.value(), "from file");
+        assert_eq!(cfg.file.source(), &SecretSource::File(file.clone()));
+        assert_eq!(cfg.env.value(), "from env");
+        // Never displayed
+        assert_eq!(format!("{} {:?}", cfg.file, cfg.env), "****** \"******\"");
+        // Only sources are serialized
+        assert_eq!(
+            toml::Value::try_from(&cfg).unwrap(),
+            toml::from_str::<toml::Value>(&format!(
+                "inline = \"******\"\nfile = {{ file = {:?} }}\nenv = {{ env = \"RELAYD_TEST_SECRET\" }}",
+                file.display().to_string()
+            ))
+            .unwrap()
+        );
+
+        // Clones share the reloaded value
+        let clone = cfg.file.clone();
+        write(&file, "new").unwrap();
+        assert!(cfg.file.reload().unwrap());
+        assert!(!cfg.file.reload().unwrap());
+        assert_eq!(clone.value(), "new");
+        assert!(!cfg.inline.reload().unwrap());
+        env::set_var("RELAYD_TEST_SECRET", "changed");
+        assert!(!cfg.env.reload().unwrap());
+        assert_eq!(cfg.env.value(), "from env");
+
+        assert!(toml::from_str::<Cfg>(
+            "inline = \"\"\nfile = { file = \"/nonexistent\" }\nenv = \"\""
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Checks done by `rudder-relayd --test`, beyond parsing
+
+use crate::{
+    configuration::{
+        logging::LogConfig,
+        main::{Configuration, InventoryOutputSelect, OutputSelect, ReportingOutputSelect},
+    },
+    data::node::NodesList,
+    error::Error,
+};
+use std::{
+    fs::{read_dir, read_to_string},
+    path::Path,
+};
+use toml::Value;
+
+/// Parsed configuration with the problems found in it
+#[derive(Debug, Clone)]
+pub struct ConfigurationCheck {
+    pub configuration: Configuration,
+    /// Unknown keys and problems that do not prevent relayd from working
+    pub warnings: Vec<String>,
+    /// Problems preventing relayd from working as configured
+    pub errors: Vec<String>,
+}
+
+impl ConfigurationCheck {
+    pub fn new(cfg_dir: &Path) -> Result<Self, Error> {
+        let raw = read_to_string(cfg_dir.join("main.conf"))?;
+        let configuration = raw.parse::<Configuration>()?;
+        LogConfig::new(cfg_dir)?;
+
+        let raw: Value = toml::from_str(&raw)?;
+        let mut check = Self {
+            warnings: unknown_keys(&raw, &Value::try_from(&configuration)?, "")
+                .into_iter()
+                .map(|key| format!("unknown key '{}'", key))
+                .collect(),
+            errors: vec![],
+            configuration,
+        };
+        check.check_outputs(&raw);
+        check.check_paths();
+        Ok(check)
+    }
+
+    /// Configuration with default values and masked secrets, in main.conf format
+    pub fn effective(&self) -> Result<String, Error> {
+        // Going through a `Value` puts tables after values, as required by TOML
+        Ok(toml::to_string(&Value::try_from(&self.configuration)?)?)
+    }
+
+    fn check_outputs(&mut self, raw: &Value) {
+        let cfg = &self.configuration;
+        if cfg.processing.reporting.output == ReportingOutputSelect::Database
+            && raw.get("output").and_then(|o| o.get("database")).is_none()
+        {
+            self.errors.push(
+                "reporting output is 'database' but there is no [output.database] section"
+                    .to_string(),
+            );
+        }
+        if (cfg.processing.reporting.output == ReportingOutputSelect::Upstream
+            || cfg.processing.inventory.output == InventoryOutputSelect::Upstream)
+            && cfg.output.upstream.url.is_empty()
+        {
+            self.errors
+                .push("output is 'upstream' but output.upstream.url is not set".to_string());
+        }
+    }
+
+    fn check_paths(&mut self) {
+        let cfg = &self.configuration;
+        let general = &cfg.general;
+        let mut errors = vec![];
+        let mut warnings = vec![];
+
+        if !general.nodes_list_file.exists() {
+            warnings.push(format!(
+                "nodes list {:?} does not exist, it will be considered empty",
+                general.nodes_list_file
+            ));
+        }
+        if !general.nodes_certs_file.exists() {
+            warnings.push(format!(
+                "nodes certificates {:?} do not exist, signed reports and inventories will be refused",
+                general.nodes_certs_file
+            ));
+        }
+        if let Err(e) = NodesList::new(
+            general.node_id.clone(),
+            &general.nodes_list_file,
+            Some(&general.nodes_certs_file),
+        ) {
+            errors.push(format!("could not load nodes list: {}", e));
+        }
+
+        // Created at startup when missing
+        let processing = &cfg.processing;
+        if processing.reporting.output.is_enabled() {
+            errors.extend(unreadable_directory(&processing.reporting.directory));
+        }
+        if processing.inventory.output.is_enabled() {
+            errors.extend(unreadable_directory(&processing.inventory.directory));
+        }
+        if let Some(ref archive) = processing.archive {
+            errors.extend(unreadable_directory(&archive.directory));
+        }
+        if let Some(parent) = cfg.stats.as_ref().and_then(|s| s.file.parent()) {
+            if !parent.is_dir() {
+                errors.push(format!(
+                    "statistics directory {:?} does not exist, statistics will not be saved",
+                    parent
+                ));
+            }
+        }
+        // Only used by API endpoints
+        for path in &[&cfg.shared_files.path, &cfg.shared_folder.path] {
+            if !path.exists() {
+                warnings.push(format!("directory {:?} does not exist", path));
+            } else {
+                warnings.extend(unreadable_directory(path));
+            }
+        }
+
+        self.errors.extend(errors);
+        self.warnings.extend(warnings);
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Missing directories are not a problem
+fn unreadable_directory(path: &Path) -> Option<String> {
+    if !path.exists() {
+        None
+    } else if !path.is_dir() {
+        Some(format!("{:?} is not a directory", path))
+    } else {
+        read_dir(path)
+            .err()
+            .map(|e| format!("directory {:?} is not readable: {}", path, e))
+    }
+}
+
+/// Keys of the raw configuration missing from the effective configuration,
+/// i.e. ignored during parsing
+fn unknown_keys(raw: &Value, effective: &Value, prefix: &str) -> Vec<String> {
+    let mut unknown = vec![];
+    match (raw, effective) {
+        (Value::Table(raw), Value::Table(effective)) => {
+            for (key, value) in raw {
+                let path = if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                match effective.get(key) {
+                    Some(effective) => unknown.extend(unknown_keys(value, effective, &path)),
+                    None => unknown.push(path),
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(effective)) => {
+            for (index, (raw, effective)) in raw.iter().zip(effective.iter()).enumerate() {
+                unknown.extend(unknown_keys(
+                    raw,
+                    effective,
+                    &format!("{}[{}]", prefix, index),
+                ));
+            }
+        }
+        _ => (),
+    }
+    unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{copy, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_finds_unknown_keys() {
+        let raw: Value = toml::from_str(
+            "[general]\nnode_id = \"root\"\nunknown = 1\n\
+             [processing.reporting]\nouptut = \"database\"\n\
+             [[processing.reporting.sinks]]\ntype = \"file\"\npath = \"/tmp/reports\"\nmax_files = 2\nmax_size_mb = 2\n\
+             [output.upstream]\nurl = \"https://127.0.0.1\"\npassword = { file = \"tests/files/secrets/default_password\", mode = 1 }\n\
+             [typo]\nkey = 1",
+        )
+        .unwrap();
+        let cfg: Configuration = raw.clone().try_into().unwrap();
+        assert_eq!(
+            unknown_keys(&raw, &Value::try_from(&cfg).unwrap(), ""),
+            vec![
+                "general.unknown",
+                "output.upstream.password.mode",
+                "processing.reporting.ouptut",
+                "processing.reporting.sinks[0].max_size_mb",
+                "typo",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_checks_configuration() {
+        let dir = tempdir().unwrap();
+        copy(
+            "tests/files/config/logging.conf",
+            dir.path().join("logging.conf"),
+        )
+        .unwrap();
+        write(
+            dir.path().join("main.conf"),
+            "[general]\nnode_id = \"root\"\nnodes_list_file = \"tests/files/nodeslist.json\"\n\
+             nodes_certs_file = \"tests/files/keys/nodescerts.pem\"\n\
+             [processing.reporting]\ndirectory = \"tests/files/nodeslist.json\"\noutput = \"database\"\n\
+             [processing.inventory]\noutput = \"upstream\"\ndirectory = \"target/tmp/check/\"\n\
+             [shared_files]\npath = \"tests/files/\"\n[shared_folder]\npath = \"tests/files/\"\n",
+        )
+        .unwrap();
+
+        let check = ConfigurationCheck::new(dir.path()).unwrap();
+        assert!(!check.is_valid());
+        assert_eq!(
+            check.errors,
+            vec![
+                "reporting output is 'database' but there is no [output.database] section",
+                "output is 'upstream' but output.upstream.url is not set",
+                "\"tests/files/nodeslist.json\" is not a directory",
+            ]
+        );
+        assert!(check.warnings.is_empty());
+
+        // Defaults are filled in and secrets masked, and it can be parsed again
+        let effective = check.effective().unwrap();
+        assert!(effective.contains("listen = \"127.0.0.1:3030\""));
+        assert!(effective.contains("password = \"\""));
+        assert!(effective.contains("nodes_list_debounce = \"5s\""));
+        assert!(effective.contains("retention = \"1d\""));
+        assert_eq!(
+            effective.parse::<Configuration>().unwrap(),
+            check.configuration
+        );
+    }
+
+    #[test]
+    fn it_dumps_secrets_sources() {
+        let dir = tempdir().unwrap();
+        copy(
+            "tests/files/config/logging.conf",
+            dir.path().join("logging.conf"),
+        )
+        .unwrap();
+        write(
+            dir.path().join("main.conf"),
+            "[general]\nnode_id = \"root\"\n\
+             [output.database]\npassword = \"PASSWORD\"\n\
+             [output.upstream]\nurl = \"https://127.0.0.1\"\npassword = \"PASSWORD\"\n\
+             default_password = { file = \"tests/files/secrets/default_password\" }\n",
+        )
+        .unwrap();
+
+        let check = ConfigurationCheck::new(dir.path()).unwrap();
+        let effective = check.effective().unwrap();
+        assert!(!effective.contains("PASSWORD"));
+        assert!(effective.contains("password = \"******\""));
+        let value: Value = toml::from_str(&effective).unwrap();
+        assert_eq!(
+            value["output"]["upstream"]["default_password"]["file"].as_str(),
+            Some("tests/files/secrets/default_password")
+        );
+        assert!(check.warnings.iter().all(|w| !w.starts_with("unknown key")));
+    }
+}
@@ -1,7 +1,14 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use std::path::{Path, PathBuf};
+use crate::{
+    error::Error,
+    processing::replay::{ReplayFilter, ReplayKind},
+};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "rudder-relayd")]
@@ -21,6 +28,65 @@ pub struct CliConfiguration {
     /// Checks the syntax of the configuration file and exit
     #[structopt(short = "t", long = "test")]
     pub check_configuration: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Moves failed files back into the incoming directory to process them again
+    Replay {
+        /// Kind of files to replay: reports or inventories
+        #[structopt(default_value = "reports")]
+        kind: ReplayKind,
+        #[structopt(flatten)]
+        filter: ReplayFilter,
+    },
+    /// Lists archived files, or copies them into a directory
+    Archive {
+        /// Kind of archived files: reports or inventories
+        #[structopt(default_value = "reports")]
+        kind: ReplayKind,
+        #[structopt(flatten)]
+        filter: ReplayFilter,
+        /// Copies matching files into this directory
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Parses a runlog file and prints the resulting reports, without database or daemon
+    Validate {
+        /// Runlog file, compressed or not
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Checks the runlog signature with the given PEM certificate(s)
+        #[structopt(long, parse(from_os_str))]
+        certificate: Option<PathBuf>,
+        /// Comma-separated event types to skip (defaults to configured skip_event_types)
+        #[structopt(long)]
+        skip_event_types: Option<String>,
+        /// Output format: table or json
+        #[structopt(long, default_value = "table")]
+        format: OutputFormat,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::InvalidOutputFormat(s.to_string())),
+        }
+    }
 }
 
 impl CliConfiguration {
@@ -29,6 +95,7 @@ impl CliConfiguration {
         Self {
             configuration_dir: path.as_ref().to_path_buf(),
             check_configuration,
+            command: None,
         }
     }
 }
@@ -2,9 +2,10 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{configuration::Secret, data::node::NodeId, error::Error};
+use regex::Regex;
 use serde::{
     de::{Deserializer, Error as SerdeError, Unexpected, Visitor},
-    Deserialize,
+    Deserialize, Serialize, Serializer,
 };
 use std::{
     collections::HashSet,
@@ -58,7 +59,30 @@ where
     d.deserialize_str(V)
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+// Readable by compat_humantime. Unlike humantime's formatting, avoids months
+// and years which do not contain a whole number of days.
+fn serialize_humantime<S>(d: &Duration, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut secs = d.as_secs();
+    let mut parts = vec![];
+    for (unit, length) in &[("d", 24 * 3600), ("h", 3600), ("min", 60), ("s", 1)] {
+        if secs >= *length {
+            parts.push(format!("{}{}", secs / length, unit));
+            secs %= length;
+        }
+    }
+    if d.subsec_millis() > 0 {
+        parts.push(format!("{}ms", d.subsec_millis()));
+    }
+    if parts.is_empty() {
+        parts.push("0s".to_string());
+    }
+    s.serialize_str(&parts.join(" "))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 // Default can be implemented in serde using the Default trait
 pub struct Configuration {
     // general section is mandatory
@@ -73,6 +97,11 @@ pub struct Configuration {
     pub shared_files: SharedFiles,
     #[serde(default)]
     pub shared_folder: SharedFolder,
+    #[serde(default)]
+    pub api: ApiConfig,
+    /// Persistence of statistics, disabled when absent
+    #[serde(default)]
+    pub stats: Option<StatsConfig>,
 }
 
 impl Configuration {
@@ -83,6 +112,28 @@ impl Configuration {
         }
         res
     }
+
+    /// Secrets with their location in the configuration
+    pub fn secrets(&self) -> Vec<(String, &Secret)> {
+        let mut secrets = vec![
+            (
+                "output.database.password".to_string(),
+                &self.output.database.password,
+            ),
+            (
+                "output.upstream.password".to_string(),
+                &self.output.upstream.password,
+            ),
+            (
+                "output.upstream.default_password".to_string(),
+                &self.output.upstream.default_password,
+            ),
+        ];
+        for token in &self.api.tokens {
+            secrets.push((format!("api.tokens.{}", token.name), &token.token));
+        }
+        secrets
+    }
 }
 
 impl FromStr for Configuration {
@@ -98,7 +149,7 @@ impl FromStr for Configuration {
 /// * `#[serde(default)]` when section is not there
 /// * `#[serde(default)]` or `#[serde(default = ...)]` when a value is missing in a section
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct GeneralConfig {
     #[serde(default = "GeneralConfig::default_nodes_list_file")]
     pub nodes_list_file: NodesListFile,
@@ -112,6 +163,19 @@ pub struct GeneralConfig {
     pub core_threads: Option<usize>,
     #[serde(default = "GeneralConfig::default_blocking_threads")]
     pub blocking_threads: usize,
+    /// Reload nodes list and certificates when they change
+    #[serde(default = "GeneralConfig::default_watch_nodes_list")]
+    pub watch_nodes_list: bool,
+    /// Time without changes to wait for before reloading
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "GeneralConfig::default_nodes_list_debounce")]
+    pub nodes_list_debounce: Duration,
+    /// Local API listener, in addition to or instead of `listen`
+    #[serde(default)]
+    pub unix_socket: Option<UnixSocketConfig>,
 }
 
 impl GeneralConfig {
@@ -130,15 +194,56 @@ impl GeneralConfig {
     fn default_blocking_threads() -> usize {
         100
     }
+
+    fn default_watch_nodes_list() -> bool {
+        true
+    }
+
+    fn default_nodes_list_debounce() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+/// Access is controlled by the ownership and permissions of the socket
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file
+    #[serde(default = "UnixSocketConfig::default_mode")]
+    pub mode: u32,
+    /// User owning the socket file, unchanged when not set
+    pub owner: Option<String>,
+    /// Group owning the socket file, unchanged when not set
+    pub group: Option<String>,
+    /// Only listen on the Unix socket, `listen` is not used
+    #[serde(default)]
+    pub exclusive: bool,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+impl UnixSocketConfig {
+    fn default_mode() -> u32 {
+        0o660
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
 pub struct CatchupConfig {
-    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     #[serde(default = "CatchupConfig::default_catchup_frequency")]
     pub frequency: Duration,
     #[serde(default = "CatchupConfig::default_catchup_limit")]
     pub limit: u64,
+    /// Files modified more recently are left to the watcher, as they
+    /// may still be being written
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "CatchupConfig::default_catchup_min_age")]
+    pub min_age: Duration,
 }
 
 impl CatchupConfig {
@@ -149,6 +254,10 @@ impl CatchupConfig {
     fn default_catchup_limit() -> u64 {
         50
     }
+
+    fn default_catchup_min_age() -> Duration {
+        Duration::from_secs(30)
+    }
 }
 
 impl Default for CatchupConfig {
@@ -156,16 +265,23 @@ impl Default for CatchupConfig {
         Self {
             frequency: Self::default_catchup_frequency(),
             limit: Self::default_catchup_limit(),
+            min_age: Self::default_catchup_min_age(),
         }
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
 pub struct CleanupConfig {
-    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     #[serde(default = "CleanupConfig::default_cleanup_frequency")]
     pub frequency: Duration,
-    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
     #[serde(default = "CleanupConfig::default_cleanup_retention")]
     pub retention: Duration,
 }
@@ -191,15 +307,129 @@ impl Default for CleanupConfig {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+/// What to do with a watched directory when it reaches a high watermark
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum PressureBehavior {
+    /// Remove oldest files until usage goes under the low watermarks
+    DropOldest,
+    /// Like `Pause`, and refuse to replay failed files into the directory
+    /// in the API. Reports and inventories are received by the web server,
+    /// which keeps accepting them.
+    Refuse,
+    /// Stop the catchup of the directory and process its oldest files
+    /// until usage goes under the low watermarks
+    Pause,
+}
+
+impl Default for PressureBehavior {
+    fn default() -> Self {
+        Self::Pause
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct DiskUsageConfig {
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "DiskUsageConfig::default_disk_usage_frequency")]
+    pub frequency: Duration,
+    /// None means no limit
+    pub high_files: Option<u64>,
+    /// None means 90% of `high_files`
+    pub low_files: Option<u64>,
+    /// None means no limit
+    pub high_bytes: Option<u64>,
+    /// None means 90% of `high_bytes`
+    pub low_bytes: Option<u64>,
+    #[serde(default)]
+    pub behavior: PressureBehavior,
+}
+
+impl DiskUsageConfig {
+    fn default_disk_usage_frequency() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.high_files.is_some() || self.high_bytes.is_some()
+    }
+}
+
+impl Default for DiskUsageConfig {
+    fn default() -> Self {
+        Self {
+            frequency: Self::default_disk_usage_frequency(),
+            high_files: None,
+            low_files: None,
+            high_bytes: None,
+            low_bytes: None,
+            behavior: PressureBehavior::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
 pub struct ProcessingConfig {
     #[serde(default)]
     pub inventory: InventoryConfig,
     #[serde(default)]
     pub reporting: ReportingConfig,
+    /// Archive of processed files, disabled when absent
+    #[serde(default)]
+    pub archive: Option<ArchiveConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ArchiveConfig {
+    #[serde(default = "ArchiveConfig::default_directory")]
+    pub directory: BaseDirectory,
+    /// Cleanup job frequency
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "ArchiveConfig::default_frequency")]
+    pub frequency: Duration,
+    /// Archived files are removed by day, from the date of the run for
+    /// reports and of the archival for inventories
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "ArchiveConfig::default_retention")]
+    pub retention: Duration,
+}
+
+impl ArchiveConfig {
+    fn default_directory() -> PathBuf {
+        PathBuf::from("/var/rudder/archive/")
+    }
+
+    /// 1 hour
+    fn default_frequency() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    /// 90 days
+    fn default_retention() -> Duration {
+        Duration::from_secs(3600 * 24 * 90)
+    }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            directory: Self::default_directory(),
+            frequency: Self::default_frequency(),
+            retention: Self::default_retention(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct InventoryConfig {
     #[serde(default = "InventoryConfig::default_directory")]
     pub directory: BaseDirectory,
@@ -209,12 +439,22 @@ pub struct InventoryConfig {
     pub catchup: CatchupConfig,
     #[serde(default)]
     pub cleanup: CleanupConfig,
+    #[serde(default)]
+    pub disk_usage: DiskUsageConfig,
+    /// Maximal number of inventories processed at the same time, for each
+    /// of the two inventory queues
+    #[serde(default = "InventoryConfig::default_concurrency")]
+    pub concurrency: usize,
 }
 
 impl InventoryConfig {
     fn default_directory() -> PathBuf {
         PathBuf::from("/var/rudder/inventories/")
     }
+
+    fn default_concurrency() -> usize {
+        10
+    }
 }
 
 impl Default for InventoryConfig {
@@ -224,11 +464,13 @@ impl Default for InventoryConfig {
             output: InventoryOutputSelect::default(),
             catchup: Default::default(),
             cleanup: Default::default(),
+            disk_usage: Default::default(),
+            concurrency: Self::default_concurrency(),
         }
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum InventoryOutputSelect {
     Upstream,
@@ -241,7 +483,7 @@ impl Default for InventoryOutputSelect {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct ReportingConfig {
     #[serde(default = "ReportingConfig::default_directory")]
     pub directory: BaseDirectory,
@@ -252,13 +494,34 @@ pub struct ReportingConfig {
     #[serde(default)]
     pub cleanup: CleanupConfig,
     #[serde(default)]
+    pub disk_usage: DiskUsageConfig,
+    #[serde(default)]
     pub skip_event_types: HashSet<String>,
+    /// Additional destinations for parsed reports
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    /// Maximal number of reports processed at the same time
+    #[serde(default = "ReportingConfig::default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub runs: RunsConfig,
+    /// Applied to parsed reports, before database insertion and sinks
+    #[serde(default)]
+    pub redactions: Vec<RedactionRule>,
+    /// Applied to parsed reports, after `skip_event_types`. Reloaded
+    /// with the configuration.
+    #[serde(default)]
+    pub filters: ReportFilters,
 }
 
 impl ReportingConfig {
     fn default_directory() -> PathBuf {
         PathBuf::from("/var/rudder/reports/")
     }
+
+    fn default_concurrency() -> usize {
+        20
+    }
 }
 
 impl Default for ReportingConfig {
@@ -268,12 +531,166 @@ impl Default for ReportingConfig {
             output: ReportingOutputSelect::default(),
             catchup: Default::default(),
             cleanup: Default::default(),
+            disk_usage: Default::default(),
             skip_event_types: Default::default(),
+            sinks: Default::default(),
+            concurrency: Self::default_concurrency(),
+            runs: Default::default(),
+            redactions: Default::default(),
+            filters: Default::default(),
         }
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+/// Compiled when loading the configuration
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(Pattern)
+            .map_err(|e| D::Error::custom(format!("invalid regex '{}': {}", pattern, e)))
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportField {
+    Msg,
+    KeyValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RedactionRule {
+    pub regex: Pattern,
+    /// Report fields the regex is applied to
+    #[serde(default = "RedactionRule::default_fields")]
+    pub fields: HashSet<ReportField>,
+    #[serde(default = "RedactionRule::default_replacement")]
+    pub replacement: String,
+    /// Only apply to reports of this rule
+    pub rule_id: Option<String>,
+    /// Only apply to reports of this directive
+    pub directive_id: Option<String>,
+}
+
+impl RedactionRule {
+    fn default_fields() -> HashSet<ReportField> {
+        vec![ReportField::Msg, ReportField::KeyValue]
+            .into_iter()
+            .collect()
+    }
+
+    fn default_replacement() -> String {
+        "[REDACTED]".to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Drop,
+    Keep,
+}
+
+impl Default for FilterAction {
+    fn default() -> Self {
+        Self::Drop
+    }
+}
+
+/// Matches reports for which all the given criteria match
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct FilterRule {
+    #[serde(default)]
+    pub action: FilterAction,
+    pub rule_id: Option<String>,
+    pub directive_id: Option<String>,
+    pub component: Option<String>,
+    pub node_id: Option<NodeId>,
+    pub event_type: Option<String>,
+    /// Regex searched in the report message
+    pub msg: Option<Pattern>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct ReportFilters {
+    /// The first matching rule applies, reports matching no rule are kept
+    #[serde(default)]
+    pub rules: Vec<FilterRule>,
+    /// Maximal number of `log_*` reports kept per run log, the first ones
+    /// are kept. No limit when not set.
+    pub max_logs: Option<usize>,
+}
+
+impl ReportFilters {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.max_logs.is_none()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RunsConfig {
+    /// Maximal difference between the clock of an agent and the
+    /// clock of the relay
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "RunsConfig::default_max_clock_offset")]
+    pub max_clock_offset: Duration,
+    /// Delay without receiving runs after which a node is considered
+    /// as not reporting anymore
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "RunsConfig::default_missing_after")]
+    pub missing_after: Duration,
+}
+
+impl RunsConfig {
+    fn default_max_clock_offset() -> Duration {
+        Duration::from_secs(10 * 60)
+    }
+
+    fn default_missing_after() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+}
+
+impl Default for RunsConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_offset: Self::default_max_clock_offset(),
+            missing_after: Self::default_missing_after(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportingOutputSelect {
     Database,
@@ -287,6 +704,83 @@ impl Default for ReportingOutputSelect {
     }
 }
 
+/// Parsed reports are sent to all configured sinks, in addition
+/// to the main output
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// Newline-delimited JSON reports in rotating files
+    File(FileSinkConfig),
+    /// RFC 5424 messages sent to a syslog server
+    Syslog(SyslogSinkConfig),
+}
+
+impl SinkConfig {
+    /// Used to identify the sink in logs and stats
+    pub fn name(&self) -> String {
+        match self {
+            SinkConfig::File(cfg) => format!("file:{}", cfg.path.display()),
+            SinkConfig::Syslog(cfg) => format!("syslog:{}", cfg.address),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FileSinkConfig {
+    pub path: PathBuf,
+    /// Size in bytes after which the file is rotated
+    #[serde(default = "FileSinkConfig::default_max_size")]
+    pub max_size: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "FileSinkConfig::default_max_files")]
+    pub max_files: u32,
+}
+
+impl FileSinkConfig {
+    /// 100 MiB
+    fn default_max_size() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    fn default_max_files() -> u32 {
+        5
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    Udp,
+    Tcp,
+}
+
+impl Default for SyslogProtocol {
+    fn default() -> Self {
+        Self::Udp
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SyslogSinkConfig {
+    /// host:port
+    pub address: String,
+    #[serde(default)]
+    pub protocol: SyslogProtocol,
+    /// Connection and write timeout, only used with TCP
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "SyslogSinkConfig::default_timeout")]
+    pub timeout: Duration,
+}
+
+impl SyslogSinkConfig {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
 pub trait OutputSelect {
     fn is_enabled(&self) -> bool;
 }
@@ -303,7 +797,7 @@ impl OutputSelect for InventoryOutputSelect {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct RemoteRun {
     #[serde(default = "RemoteRun::default_command")]
     pub command: PathBuf,
@@ -330,7 +824,7 @@ impl Default for RemoteRun {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct SharedFiles {
     #[serde(default = "SharedFiles::default_path")]
     pub path: PathBuf,
@@ -350,7 +844,107 @@ impl Default for SharedFiles {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ApiConfig {
+    /// Bearer tokens allowed to call the system and remote-run endpoints.
+    /// Authentication is disabled when empty.
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+    /// Allow requests from loopback addresses without token, like the ones
+    /// proxied by the local web server
+    #[serde(default = "ApiConfig::default_trust_loopback")]
+    pub trust_loopback: bool,
+    /// Allow requests received on the Unix socket without token
+    #[serde(default = "ApiConfig::default_trust_unix_socket")]
+    pub trust_unix_socket: bool,
+}
+
+impl ApiConfig {
+    fn default_trust_loopback() -> bool {
+        true
+    }
+
+    fn default_trust_unix_socket() -> bool {
+        true
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            tokens: Default::default(),
+            trust_loopback: Self::default_trust_loopback(),
+            trust_unix_socket: Self::default_trust_unix_socket(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ApiToken {
+    /// Identifies the caller in audit logs
+    pub name: String,
+    pub token: Secret,
+    pub scopes: HashSet<ApiScope>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiScope {
+    /// Read-only system endpoints
+    Read,
+    /// Configuration reload and failed files replay
+    Reload,
+    /// Agent runs triggering
+    RemoteRun,
+}
+
+impl fmt::Display for ApiScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ApiScope::Read => "read",
+                ApiScope::Reload => "reload",
+                ApiScope::RemoteRun => "remote-run",
+            }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct StatsConfig {
+    /// Counters and recent events, loaded at startup
+    #[serde(default = "StatsConfig::default_file")]
+    pub file: PathBuf,
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "StatsConfig::default_save_frequency")]
+    pub save_frequency: Duration,
+}
+
+impl StatsConfig {
+    fn default_file() -> PathBuf {
+        PathBuf::from("/var/rudder/lib/relay/stats.json")
+    }
+
+    fn default_save_frequency() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            file: Self::default_file(),
+            save_frequency: Self::default_save_frequency(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct SharedFolder {
     #[serde(default = "SharedFolder::default_path")]
     pub path: PathBuf,
@@ -370,15 +964,18 @@ impl Default for SharedFolder {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
 pub struct OutputConfig {
     #[serde(default)]
     pub database: DatabaseConfig,
     #[serde(default)]
     pub upstream: UpstreamConfig,
+    /// Export of spans, disabled when absent
+    #[serde(default)]
+    pub traces: Option<TracesConfig>,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct DatabaseConfig {
     /// URL without the password
     #[serde(default = "DatabaseConfig::default_url")]
@@ -387,6 +984,18 @@ pub struct DatabaseConfig {
     pub password: Secret,
     #[serde(default = "DatabaseConfig::default_max_pool_size")]
     pub max_pool_size: u32,
+    /// Frequency of the availability check. Report insertion is paused
+    /// while the database is unavailable.
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "DatabaseConfig::default_health_check_frequency")]
+    pub health_check_frequency: Duration,
+    /// Maximal number of reports received during an outage sent for insertion
+    /// per second once the database is available again
+    #[serde(default = "DatabaseConfig::default_resume_rate")]
+    pub resume_rate: u32,
 }
 
 impl DatabaseConfig {
@@ -397,6 +1006,14 @@ impl DatabaseConfig {
     fn default_max_pool_size() -> u32 {
         10
     }
+
+    fn default_health_check_frequency() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_resume_rate() -> u32 {
+        50
+    }
 }
 
 impl Default for DatabaseConfig {
@@ -405,11 +1022,13 @@ impl Default for DatabaseConfig {
             url: Self::default_url(),
             password: Default::default(),
             max_pool_size: Self::default_max_pool_size(),
+            health_check_frequency: Self::default_health_check_frequency(),
+            resume_rate: Self::default_resume_rate(),
         }
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct UpstreamConfig {
     // TODO better URL type
     /// When the section is there, url is mandatory
@@ -452,6 +1071,32 @@ impl Default for UpstreamConfig {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TracesConfig {
+    /// OTLP/HTTP traces endpoint.
+    /// When the section is there, endpoint is mandatory
+    pub endpoint: String,
+    #[serde(default = "TracesConfig::default_service_name")]
+    pub service_name: String,
+    /// Maximal delay before sending finished spans
+    #[serde(
+        deserialize_with = "compat_humantime",
+        serialize_with = "serialize_humantime"
+    )]
+    #[serde(default = "TracesConfig::default_export_interval")]
+    pub export_interval: Duration,
+}
+
+impl TracesConfig {
+    fn default_service_name() -> String {
+        "rudder-relayd".to_string()
+    }
+
+    fn default_export_interval() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -478,6 +1123,9 @@ mod tests {
                 listen: "127.0.0.1:3030".parse().unwrap(),
                 core_threads: None,
                 blocking_threads: 100,
+                watch_nodes_list: true,
+                nodes_list_debounce: Duration::from_secs(5),
+                unix_socket: None,
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -486,11 +1134,14 @@ mod tests {
                     catchup: CatchupConfig {
                         frequency: Duration::from_secs(10),
                         limit: 50,
+                        min_age: Duration::from_secs(30),
                     },
                     cleanup: CleanupConfig {
                         frequency: Duration::from_secs(3600),
                         retention: Duration::from_secs(3600 * 24 * 7),
                     },
+                    disk_usage: DiskUsageConfig::default(),
+                    concurrency: 10,
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("/var/rudder/reports/"),
@@ -498,13 +1149,24 @@ mod tests {
                     catchup: CatchupConfig {
                         frequency: Duration::from_secs(10),
                         limit: 50,
+                        min_age: Duration::from_secs(30),
                     },
                     cleanup: CleanupConfig {
                         frequency: Duration::from_secs(3600),
                         retention: Duration::from_secs(3600 * 24 * 7),
                     },
+                    disk_usage: DiskUsageConfig::default(),
                     skip_event_types: HashSet::new(),
+                    sinks: vec![],
+                    concurrency: 20,
+                    runs: RunsConfig {
+                        max_clock_offset: Duration::from_secs(600),
+                        missing_after: Duration::from_secs(3600),
+                    },
+                    redactions: vec![],
+                    filters: ReportFilters::default(),
                 },
+                archive: None,
             },
             output: OutputConfig {
                 upstream: UpstreamConfig {
@@ -518,7 +1180,10 @@ mod tests {
                     url: "postgres://rudder@127.0.0.1/rudder".to_string(),
                     password: Secret::new("".to_string()),
                     max_pool_size: 10,
+                    health_check_frequency: Duration::from_secs(10),
+                    resume_rate: 50,
                 },
+                traces: None,
             },
             remote_run: RemoteRun {
                 command: PathBuf::from("/opt/rudder/bin/rudder"),
@@ -530,6 +1195,12 @@ mod tests {
             shared_folder: SharedFolder {
                 path: PathBuf::from("/var/rudder/configuration-repository/shared-files/"),
             },
+            api: ApiConfig {
+                tokens: vec![],
+                trust_loopback: true,
+                trust_unix_socket: true,
+            },
+            stats: None,
         };
 
         assert_eq!(config.unwrap(), reference);
@@ -553,6 +1224,235 @@ mod tests {
         assert!(default.parse::<Configuration>().is_ok());
     }
 
+    #[test]
+    fn it_parses_sinks() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [[processing.reporting.sinks]]\n\
+                      type = \"file\"\n\
+                      path = \"target/tmp/sinks/reports.json\"\n\
+                      max_size = 1048576\n\
+                      [[processing.reporting.sinks]]\n\
+                      type = \"syslog\"\n\
+                      address = \"127.0.0.1:5514\"\n\
+                      [[processing.reporting.sinks]]\n\
+                      type = \"syslog\"\n\
+                      address = \"127.0.0.1:6514\"\n\
+                      protocol = \"tcp\"\n\
+                      timeout = \"2s\""
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.processing.reporting.sinks,
+            vec![
+                SinkConfig::File(FileSinkConfig {
+                    path: PathBuf::from("target/tmp/sinks/reports.json"),
+                    max_size: 1024 * 1024,
+                    max_files: 5,
+                }),
+                SinkConfig::Syslog(SyslogSinkConfig {
+                    address: "127.0.0.1:5514".to_string(),
+                    protocol: SyslogProtocol::Udp,
+                    timeout: Duration::from_secs(10),
+                }),
+                SinkConfig::Syslog(SyslogSinkConfig {
+                    address: "127.0.0.1:6514".to_string(),
+                    protocol: SyslogProtocol::Tcp,
+                    timeout: Duration::from_secs(2),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_redactions() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [[processing.reporting.redactions]]\n\
+                      regex = 'password=\\S+'\n\
+                      fields = [\"msg\"]\n\
+                      replacement = \"password=[REDACTED]\"\n\
+                      directive_id = \"directive1\""
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.processing.reporting.redactions,
+            vec![RedactionRule {
+                regex: Pattern(Regex::new("password=\\S+").unwrap()),
+                fields: vec![ReportField::Msg].into_iter().collect(),
+                replacement: "password=[REDACTED]".to_string(),
+                rule_id: None,
+                directive_id: Some("directive1".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn it_parses_filters() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [processing.reporting.filters]\n\
+                      max_logs = 1000\n\
+                      [[processing.reporting.filters.rules]]\n\
+                      action = \"keep\"\n\
+                      directive_id = \"directive1\"\n\
+                      msg = '^error'\n\
+                      [[processing.reporting.filters.rules]]\n\
+                      directive_id = \"directive1\"\n\
+                      event_type = \"log_info\""
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.processing.reporting.filters,
+            ReportFilters {
+                rules: vec![
+                    FilterRule {
+                        action: FilterAction::Keep,
+                        directive_id: Some("directive1".to_string()),
+                        msg: Some(Pattern(Regex::new("^error").unwrap())),
+                        ..Default::default()
+                    },
+                    FilterRule {
+                        directive_id: Some("directive1".to_string()),
+                        event_type: Some("log_info".to_string()),
+                        ..Default::default()
+                    },
+                ],
+                max_logs: Some(1000),
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_archive() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [processing.archive]\n\
+                      directory = \"target/tmp/archive/\"\n\
+                      retention = \"30days\""
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.processing.archive,
+            Some(ArchiveConfig {
+                directory: PathBuf::from("target/tmp/archive/"),
+                frequency: Duration::from_secs(3600),
+                retention: Duration::from_secs(3600 * 24 * 30),
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_traces() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [output.traces]\n\
+                      endpoint = \"http://127.0.0.1:4318/v1/traces\"\n\
+                      export_interval = \"1s\""
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.output.traces,
+            Some(TracesConfig {
+                endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+                service_name: "rudder-relayd".to_string(),
+                export_interval: Duration::from_secs(1),
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_unix_socket() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [general.unix_socket]\n\
+                      path = \"target/tmp/api.sock\"\n\
+                      mode = 0o600"
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.general.unix_socket,
+            Some(UnixSocketConfig {
+                path: PathBuf::from("target/tmp/api.sock"),
+                mode: 0o600,
+                owner: None,
+                group: None,
+                exclusive: false,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_processing_limits() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [processing.inventory.catchup]\n\
+                      min_age = \"1min\"\n\
+                      [processing.inventory.disk_usage]\n\
+                      high_files = 10000\n\
+                      behavior = \"drop-oldest\"\n\
+                      [processing.reporting]\n\
+                      concurrency = 5\n\
+                      [processing.reporting.runs]\n\
+                      max_clock_offset = \"5m\"\n\
+                      [output.database]\n\
+                      password = \"PASSWORD\"\n\
+                      health_check_frequency = \"30s\""
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.processing.inventory.catchup.min_age,
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            config.processing.inventory.disk_usage,
+            DiskUsageConfig {
+                frequency: Duration::from_secs(60),
+                high_files: Some(10_000),
+                low_files: None,
+                high_bytes: None,
+                low_bytes: None,
+                behavior: PressureBehavior::DropOldest,
+            }
+        );
+        assert_eq!(config.processing.reporting.concurrency, 5);
+        assert_eq!(
+            config.processing.reporting.runs,
+            RunsConfig {
+                max_clock_offset: Duration::from_secs(300),
+                missing_after: Duration::from_secs(3600),
+            }
+        );
+        assert_eq!(
+            config.output.database.health_check_frequency,
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn it_parses_api_tokens() {
+        let config = "[general]\n\
+                      node_id = \"root\"\n\
+                      [[api.tokens]]\n\
+                      name = \"automation\"\n\
+                      token = \"TOKEN\"\n\
+                      scopes = [\"read\", \"reload\"]"
+            .parse::<Configuration>()
+            .unwrap();
+        assert_eq!(
+            config.api,
+            ApiConfig {
+                tokens: vec![ApiToken {
+                    name: "automation".to_string(),
+                    token: Secret::new("TOKEN".to_string()),
+                    scopes: vec![ApiScope::Read, ApiScope::Reload].into_iter().collect(),
+                }],
+                trust_loopback: true,
+                trust_unix_socket: true,
+            }
+        );
+    }
+
     #[test]
     fn it_parses_main_configuration() {
         let config = Configuration::new("tests/files/config/");
@@ -565,6 +1465,9 @@ mod tests {
                 listen: "127.0.0.1:3030".parse().unwrap(),
                 core_threads: None,
                 blocking_threads: 100,
+                watch_nodes_list: true,
+                nodes_list_debounce: Duration::from_secs(5),
+                unix_socket: None,
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -573,11 +1476,14 @@ mod tests {
                     catchup: CatchupConfig {
                         frequency: Duration::from_secs(10),
                         limit: 50,
+                        min_age: Duration::from_secs(30),
                     },
                     cleanup: CleanupConfig {
                         frequency: Duration::from_secs(10),
                         retention: Duration::from_secs(10),
                     },
+                    disk_usage: DiskUsageConfig::default(),
+                    concurrency: 10,
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("target/tmp/reporting/"),
@@ -585,13 +1491,24 @@ mod tests {
                     catchup: CatchupConfig {
                         frequency: Duration::from_secs(10),
                         limit: 50,
+                        min_age: Duration::from_secs(30),
                     },
                     cleanup: CleanupConfig {
                         frequency: Duration::from_secs(30),
                         retention: Duration::from_secs(30 * 60 + 20),
                     },
+                    disk_usage: DiskUsageConfig::default(),
                     skip_event_types: HashSet::new(),
+                    sinks: vec![],
+                    concurrency: 20,
+                    runs: RunsConfig {
+                        max_clock_offset: Duration::from_secs(600),
+                        missing_after: Duration::from_secs(3600),
+                    },
+                    redactions: vec![],
+                    filters: ReportFilters::default(),
                 },
+                archive: None,
             },
             output: OutputConfig {
                 upstream: UpstreamConfig {
@@ -605,7 +1522,10 @@ mod tests {
                     url: "postgres://rudderreports@127.0.0.1/rudder".to_string(),
                     password: Secret::new("PASSWORD".to_string()),
                     max_pool_size: 5,
+                    health_check_frequency: Duration::from_secs(10),
+                    resume_rate: 50,
                 },
+                traces: None,
             },
             remote_run: RemoteRun {
                 command: PathBuf::from("tests/api_remote_run/fake_agent.sh"),
@@ -617,6 +1537,12 @@ mod tests {
             shared_folder: SharedFolder {
                 path: PathBuf::from("tests/api_shared_folder"),
             },
+            api: ApiConfig {
+                tokens: vec![],
+                trust_loopback: true,
+                trust_unix_socket: true,
+            },
+            stats: None,
         };
         assert_eq!(config.unwrap(), reference);
     }
@@ -2,11 +2,13 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{error::Error, hashing::Hash};
+use chrono::{DateTime, Utc};
 use openssl::{stack::Stack, x509::X509};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
     fs::{read, read_to_string},
     path::Path,
     str::FromStr,
@@ -17,7 +19,7 @@ pub type NodeId = String;
 pub type NodeIdRef = str;
 pub type Host = String;
 
-#[derive(Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default)]
 struct Info {
     hostname: Host,
 
@@ -32,6 +34,7 @@ struct Info {
     // * `Some(hash)` if parsing was successful
     // * `None` in case of deserializing/parsing error which is expected to happen
     #[serde(deserialize_with = "deserialize_hash")]
+    #[serde(serialize_with = "serialize_hash")]
     // May not exist if node keys were reset and not updated yet
     key_hash: Option<Hash>,
 
@@ -57,7 +60,26 @@ where
         }))
 }
 
+/// Same as the server, "sha256:" for nodes without keys
+fn serialize_hash<S>(hash: &Option<Hash>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match hash {
+        Some(hash) => serializer.serialize_str(&hash.to_string()),
+        None => serializer.serialize_str("sha256:"),
+    }
+}
+
 impl Info {
+    /// DER representations, as certificates are not comparable
+    fn certificates_der(&self) -> Vec<Vec<u8>> {
+        self.certificates
+            .as_ref()
+            .map(|certs| certs.iter().filter_map(|c| c.to_der().ok()).collect())
+            .unwrap_or_default()
+    }
+
     fn add_certificate(&mut self, cert: X509) -> Result<(), Error> {
         match self.certificates {
             Some(ref mut certs) => certs.push(cert)?,
@@ -71,7 +93,7 @@ impl Info {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct RawNodesList {
     data: HashMap<NodeId, Info>,
@@ -96,6 +118,7 @@ impl RawNodesList {
 pub struct NodesList {
     list: RawNodesList,
     my_id: NodeId,
+    topology: Topology,
 }
 
 impl NodesList {
@@ -129,7 +152,43 @@ impl NodesList {
                 info!("Certificates file does not exist, skipping");
             }
         }
-        Ok(NodesList { list: nodes, my_id })
+        let topology = Topology::new(&my_id, &nodes);
+        Ok(NodesList {
+            list: nodes,
+            my_id,
+            topology,
+        })
+    }
+
+    /// Changes needed to go from this list to the given one
+    pub fn diff(&self, new: &NodesList) -> NodesListDiff {
+        let mut diff = NodesListDiff::default();
+        for (id, info) in &self.list.data {
+            match new.list.data.get(id) {
+                None => {
+                    let _ = diff.removed.insert(id.clone());
+                }
+                Some(new_info) => {
+                    if info.policy_server != new_info.policy_server {
+                        let _ = diff.policy_server_changed.insert(id.clone());
+                    }
+                    if info.key_hash != new_info.key_hash {
+                        let _ = diff.key_hash_changed.insert(id.clone());
+                    }
+                    if info.certificates_der() != new_info.certificates_der() {
+                        let _ = diff.certificates_changed.insert(id.clone());
+                    }
+                }
+            }
+        }
+        diff.added = new
+            .list
+            .data
+            .keys()
+            .filter(|id| !self.list.data.contains_key(*id))
+            .cloned()
+            .collect();
+        diff
     }
 
     pub fn counts(&self) -> NodeCounts {
@@ -181,47 +240,21 @@ impl NodesList {
     }
 
     /// Some(Next hop) if any, None if directly connected, error if not found
-    fn next_hop(&self, node_id: &NodeIdRef) -> Result<Option<NodeId>, ()> {
-        // nodeslist should not contain loops but just in case
-        // 20 levels of relays should be more than enough
-        const MAX_RELAY_LEVELS: u8 = 20;
-
-        if self.is_my_neighbor(node_id)? {
-            return Ok(None);
-        }
-
-        let mut current_id = node_id;
-        let mut current = self.list.data.get(current_id).ok_or(())?;
-        let mut next_hop = Err(());
-
-        for level in 0..MAX_RELAY_LEVELS {
-            if current.policy_server == self.my_id {
-                next_hop = Ok(Some(current_id.to_string()));
-                break;
-            }
-            current_id = &current.policy_server;
-            current = self.list.data.get(current_id).ok_or(())?;
-
-            if level == MAX_RELAY_LEVELS {
-                warn!(
-                    "Reached maximum level of relay ({}) for {}, there is probably a loop",
-                    MAX_RELAY_LEVELS, node_id
-                );
-            }
+    /// or not reachable
+    fn next_hop(&self, node_id: &NodeIdRef) -> Result<Option<&NodeIdRef>, ()> {
+        match self.topology.routes.get(node_id) {
+            Some(Route::Direct) => Ok(None),
+            Some(Route::Relay(relay)) => Ok(Some(relay.as_str())),
+            Some(Route::Unreachable) | Some(Route::Loop) | None => Err(()),
         }
-
-        next_hop
     }
 
-    // NOTE: Following methods could be made faster by pre-computing a graph in cache
-
     pub fn my_neighbors(&self) -> Vec<Host> {
-        self.list
-            .data
-            .values()
-            .filter(|k| k.policy_server == self.my_id)
-            .map(|k| k.hostname.clone())
-            .collect()
+        self.topology
+            .children
+            .get(&self.my_id)
+            .map(|children| children.iter().filter_map(|id| self.hostname(id)).collect())
+            .unwrap_or_default()
     }
 
     pub fn neighbors_from(&self, server: &NodeIdRef, nodes: &[NodeId]) -> Vec<Host> {
@@ -238,18 +271,17 @@ impl NodesList {
     }
 
     pub fn my_sub_relays(&self) -> Vec<Host> {
-        let mut relays = HashSet::new();
-        for policy_server in self
-            .list
-            .data
-            .values()
-            .filter_map(|v| self.list.data.get(&v.policy_server))
-            .filter(|v| v.policy_server == self.my_id)
-            .map(|v| v.hostname.clone())
-        {
-            let _ = relays.insert(policy_server);
-        }
-        relays.into_iter().collect()
+        self.topology
+            .children
+            .get(&self.my_id)
+            .map(|children| {
+                children
+                    .iter()
+                    .filter(|id| self.topology.children.contains_key(*id))
+                    .filter_map(|id| self.hostname(id))
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 
     /// Relays to contact to trigger given nodes, with the matching nodes
@@ -258,16 +290,13 @@ impl NodesList {
         let mut relays: HashMap<Host, Vec<NodeId>> = HashMap::new();
         for node in nodes.iter() {
             let hostname = match self.next_hop(node) {
-                Ok(Some(ref next_hop)) => self
-                    .list
-                    .data
-                    .get::<str>(next_hop)
-                    .map(|n| n.hostname.clone())
-                    // We are sure it is there at this point
+                Ok(Some(next_hop)) => self
+                    .hostname(next_hop)
+                    // Routes only contain known nodes
                     .unwrap(),
                 Ok(None) => continue,
                 Err(()) => {
-                    error!("Unknown node {}", node);
+                    error!("Unknown or unreachable node {}", node);
                     continue;
                 }
             };
@@ -281,6 +310,193 @@ impl NodesList {
 
         relays.into_iter().collect()
     }
+
+    /// Tree of the nodes reachable from this relay, with the anomalies
+    /// found when loading the nodes list
+    pub fn topology(&self) -> TopologyTree {
+        TopologyTree {
+            root: self.topology_node(&self.my_id),
+            unreachable: self.topology.unreachable.iter().cloned().collect(),
+            loops: self.topology.loops.clone(),
+        }
+    }
+
+    fn topology_node(&self, id: &NodeIdRef) -> TopologyNode {
+        TopologyNode {
+            id: id.to_string(),
+            hostname: self.hostname(id),
+            children: self
+                .topology
+                .children
+                .get(id)
+                .map(|children| {
+                    children
+                        .iter()
+                        // The root server is its own policy server
+                        .filter(|child| **child != self.my_id)
+                        .map(|child| self.topology_node(child))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Graphviz representation of the topology, with an edge from each node
+    /// to its policy server
+    pub fn topology_dot(&self) -> String {
+        let mut dot = "digraph topology {\n".to_string();
+        let mut ids: Vec<&NodeId> = self.list.data.keys().collect();
+        ids.sort();
+        for id in ids {
+            let info = &self.list.data[id];
+            dot.push_str(&format!(
+                "  \"{}\" [label=\"{}\"{}];\n",
+                id,
+                info.hostname,
+                match self.topology.routes.get(id) {
+                    Some(Route::Unreachable) => ", color=orange",
+                    Some(Route::Loop) => ", color=red",
+                    _ => "",
+                }
+            ));
+            if info.policy_server != *id {
+                dot.push_str(&format!("  \"{}\" -> \"{}\";\n", id, info.policy_server));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// How to reach a node from this relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Route {
+    /// Managed by this relay
+    Direct,
+    /// Behind the given sub-relay, directly managed by this relay
+    Relay(NodeId),
+    /// Not under this relay, or behind a loop
+    Unreachable,
+    /// Part of a policy server loop
+    Loop,
+}
+
+/// Relay topology, computed once when loading the nodes list
+#[derive(Debug, Default)]
+struct Topology {
+    routes: HashMap<NodeId, Route>,
+    /// Nodes by policy server
+    children: HashMap<NodeId, BTreeSet<NodeId>>,
+    unreachable: BTreeSet<NodeId>,
+    loops: Vec<Vec<NodeId>>,
+}
+
+impl Topology {
+    fn new(my_id: &NodeIdRef, list: &RawNodesList) -> Self {
+        let mut topology = Self::default();
+
+        for (id, info) in &list.data {
+            let _ = topology
+                .children
+                .entry(info.policy_server.clone())
+                .or_default()
+                .insert(id.clone());
+        }
+
+        for id in list.data.keys() {
+            if topology.routes.contains_key(id) {
+                continue;
+            }
+
+            // Follow policy servers until reaching a known route, this relay,
+            // a loop or an unknown node
+            let mut path: Vec<&NodeIdRef> = vec![];
+            let mut current: &NodeIdRef = id;
+            let route = loop {
+                if current == my_id {
+                    path.push(current);
+                    break Route::Direct;
+                }
+                if let Some(route) = topology.routes.get(current) {
+                    break match route {
+                        Route::Direct => Route::Relay(current.to_string()),
+                        Route::Loop => Route::Unreachable,
+                        route => route.clone(),
+                    };
+                }
+                let policy_server = match list.data.get(current) {
+                    Some(info) => info.policy_server.as_str(),
+                    // Unknown policy server
+                    None => break Route::Unreachable,
+                };
+                path.push(current);
+                if policy_server == my_id {
+                    // Managed by this relay, resolve it first
+                    let _ = topology.routes.insert(current.to_string(), Route::Direct);
+                    let _ = path.pop();
+                    break Route::Relay(current.to_string());
+                }
+                if policy_server == current {
+                    // Top of the tree, above this relay
+                    break Route::Unreachable;
+                }
+                if let Some(start) = path.iter().position(|n| *n == policy_server) {
+                    let members: Vec<NodeId> = path.drain(start..).map(|n| n.to_string()).collect();
+                    for member in &members {
+                        let _ = topology.routes.insert(member.clone(), Route::Loop);
+                    }
+                    topology.loops.push(members);
+                    break Route::Unreachable;
+                }
+                current = policy_server;
+            };
+
+            for node in path {
+                if route == Route::Unreachable {
+                    let _ = topology.unreachable.insert(node.to_string());
+                }
+                let _ = topology.routes.insert(node.to_string(), route.clone());
+            }
+        }
+
+        for members in &topology.loops {
+            warn!(
+                "Policy server loop in nodes list: {}, these nodes are unreachable",
+                members.join(" -> ")
+            );
+        }
+        if !topology.unreachable.is_empty() {
+            warn!(
+                "{} nodes are not reachable from this relay: {}",
+                topology.unreachable.len(),
+                topology
+                    .unreachable
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<NodeId>>()
+                    .join(", ")
+            );
+        }
+        topology
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TopologyNode {
+    pub id: NodeId,
+    /// Not present for this relay if it is not in the nodes list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<Host>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<TopologyNode>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TopologyTree {
+    pub root: TopologyNode,
+    /// Nodes with an unknown policy server, or behind a loop
+    pub unreachable: Vec<NodeId>,
+    pub loops: Vec<Vec<NodeId>>,
 }
 
 impl FromStr for RawNodesList {
@@ -291,6 +507,17 @@ impl FromStr for RawNodesList {
     }
 }
 
+/// Nodes list file format, without certificates
+impl fmt::Display for RawNodesList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            serde_json::to_string(self).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
 #[derive(Serialize, Debug, PartialEq, Eq)]
 pub struct NodeCounts {
     // Total nodes under this relays
@@ -299,9 +526,58 @@ pub struct NodeCounts {
     pub managed_nodes: usize,
 }
 
+/// Differences between two successive nodes lists
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct NodesListDiff {
+    pub added: BTreeSet<NodeId>,
+    pub removed: BTreeSet<NodeId>,
+    pub policy_server_changed: BTreeSet<NodeId>,
+    pub key_hash_changed: BTreeSet<NodeId>,
+    pub certificates_changed: BTreeSet<NodeId>,
+}
+
+impl NodesListDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.policy_server_changed.is_empty()
+            && self.key_hash_changed.is_empty()
+            && self.certificates_changed.is_empty()
+    }
+}
+
+impl fmt::Display for NodesListDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} with new policy server, {} with new key hash, {} with new certificates",
+            self.added.len(),
+            self.removed.len(),
+            self.policy_server_changed.len(),
+            self.key_hash_changed.len(),
+            self.certificates_changed.len()
+        )
+    }
+}
+
+/// Last nodes list reload, exposed in the status API
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct NodesListReload {
+    pub date: DateTime<Utc>,
+    pub changes: NodesListDiff,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hashing::HashType;
+    use proptest::{
+        collection::{btree_map, vec},
+        option,
+        prelude::any,
+        prop_assert, prop_assert_eq, proptest,
+    };
+    use std::collections::BTreeMap;
 
     #[test]
     fn it_parses_nodeslist() {
@@ -331,6 +607,172 @@ mod tests {
         assert_eq!(nodeslist.list.data.len(), 6);
     }
 
+    #[test]
+    fn it_computes_nodeslist_diff() {
+        let empty = NodesList::new("root".to_string(), "tests/files/notthere.json", None).unwrap();
+        let nodeslist =
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        let with_certs = NodesList::new(
+            "root".to_string(),
+            "tests/files/nodeslist.json",
+            Some("tests/files/keys/nodescerts.pem"),
+        )
+        .unwrap();
+
+        assert!(nodeslist.diff(&nodeslist).is_empty());
+        assert_eq!(empty.diff(&nodeslist).added.len(), 6);
+        assert_eq!(nodeslist.diff(&empty).removed.len(), 6);
+        assert_eq!(
+            nodeslist.diff(&with_certs),
+            NodesListDiff {
+                certificates_changed: vec![
+                    "37817c4d-fbf7-4850-a985-50021f4e8f41".to_string(),
+                    "e745a140-40bc-4b86-b6dc-084488fc906b".to_string()
+                ]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            }
+        );
+
+        let list: RawNodesList = r#"{
+                "37817c4d-fbf7-4850-a985-50021f4e8f41": {
+                    "hostname": "node2.rudder.local",
+                    "key-hash": "sha256:a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f",
+                    "policy-server": "a745a140-40bc-4b86-b6dc-084488fc906b"
+                },
+                "a745a140-40bc-4b86-b6dc-084488fc906b": {
+                    "hostname": "node3.rudder.local",
+                    "policy-server": "root"
+                },
+                "f745a140-40bc-4b86-b6dc-084488fc906b": {
+                    "hostname": "node6.rudder.local",
+                    "policy-server": "root"
+                }
+            }"#
+        .parse()
+        .unwrap();
+        let changed = NodesList {
+            topology: Topology::new("root", &list),
+            list,
+            my_id: "root".to_string(),
+        };
+        let diff = nodeslist.diff(&changed);
+        assert_eq!(
+            diff.added.into_iter().collect::<Vec<_>>(),
+            vec!["f745a140-40bc-4b86-b6dc-084488fc906b".to_string()]
+        );
+        assert_eq!(diff.removed.len(), 4);
+        assert_eq!(
+            diff.policy_server_changed.into_iter().collect::<Vec<_>>(),
+            vec![
+                "37817c4d-fbf7-4850-a985-50021f4e8f41".to_string(),
+                "a745a140-40bc-4b86-b6dc-084488fc906b".to_string()
+            ]
+        );
+        assert_eq!(
+            diff.key_hash_changed.into_iter().collect::<Vec<_>>(),
+            vec!["a745a140-40bc-4b86-b6dc-084488fc906b".to_string()]
+        );
+    }
+
+    #[test]
+    fn it_computes_topology() {
+        let nodeslist =
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        assert_eq!(
+            nodeslist.next_hop("b745a140-40bc-4b86-b6dc-084488fc906b"),
+            Ok(Some("e745a140-40bc-4b86-b6dc-084488fc906b"))
+        );
+        assert_eq!(
+            nodeslist.next_hop("37817c4d-fbf7-4850-a985-50021f4e8f41"),
+            Ok(None)
+        );
+        assert_eq!(nodeslist.next_hop("root"), Ok(None));
+        assert_eq!(nodeslist.next_hop("unknown"), Err(()));
+
+        let topology = nodeslist.topology();
+        assert!(topology.unreachable.is_empty());
+        assert!(topology.loops.is_empty());
+        assert_eq!(topology.root.id, "root");
+        assert_eq!(topology.root.children.len(), 2);
+        assert_eq!(
+            topology.root.children[1],
+            TopologyNode {
+                id: "e745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                hostname: Some("node1.rudder.local".to_string()),
+                children: vec![TopologyNode {
+                    id: "a745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                    hostname: Some("node3.rudder.local".to_string()),
+                    children: vec![TopologyNode {
+                        id: "b745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                        hostname: Some("node4.rudder.local".to_string()),
+                        children: vec![],
+                    }],
+                }],
+            }
+        );
+
+        let dot = nodeslist.topology_dot();
+        assert!(dot.starts_with("digraph topology {\n"));
+        assert!(dot.contains(
+            "  \"b745a140-40bc-4b86-b6dc-084488fc906b\" -> \"a745a140-40bc-4b86-b6dc-084488fc906b\";\n"
+        ));
+        assert!(!dot.contains("\"root\" -> \"root\""));
+    }
+
+    #[test]
+    fn it_detects_loops_and_unreachable_nodes() {
+        let list: RawNodesList = r#"{
+                "root": {
+                    "hostname": "server.rudder.local",
+                    "policy-server": "root"
+                },
+                "relay1": {
+                    "hostname": "relay1.rudder.local",
+                    "policy-server": "root"
+                },
+                "node1": {
+                    "hostname": "node1.rudder.local",
+                    "policy-server": "relay1"
+                },
+                "loop1": {
+                    "hostname": "loop1.rudder.local",
+                    "policy-server": "loop2"
+                },
+                "loop2": {
+                    "hostname": "loop2.rudder.local",
+                    "policy-server": "loop1"
+                },
+                "behind_loop": {
+                    "hostname": "behind.rudder.local",
+                    "policy-server": "loop1"
+                },
+                "orphan": {
+                    "hostname": "orphan.rudder.local",
+                    "policy-server": "unknown"
+                }
+            }"#
+        .parse()
+        .unwrap();
+        let topology = Topology::new("root", &list);
+
+        assert_eq!(topology.routes["node1"], Route::Relay("relay1".to_string()));
+        assert_eq!(topology.routes["relay1"], Route::Direct);
+        assert_eq!(topology.routes["loop1"], Route::Loop);
+        assert_eq!(topology.routes["loop2"], Route::Loop);
+        assert_eq!(topology.routes["behind_loop"], Route::Unreachable);
+        assert_eq!(topology.routes["orphan"], Route::Unreachable);
+        assert_eq!(topology.loops.len(), 1);
+        let mut members = topology.loops[0].clone();
+        members.sort();
+        assert_eq!(members, vec!["loop1".to_string(), "loop2".to_string()]);
+        assert_eq!(
+            topology.unreachable.into_iter().collect::<Vec<_>>(),
+            vec!["behind_loop".to_string(), "orphan".to_string()]
+        );
+    }
+
     #[test]
     fn it_parses_absent_nodeslist() {
         let nodeslist =
@@ -486,4 +928,89 @@ mod tests {
 
         assert_eq!(reference, actual);
     }
+
+    /// Comparable content of a nodes list
+    fn comparable(list: &RawNodesList) -> BTreeMap<&NodeId, (&Host, &NodeId, Option<&Hash>)> {
+        list.data
+            .iter()
+            .map(|(id, info)| {
+                (
+                    id,
+                    (&info.hostname, &info.policy_server, info.key_hash.as_ref()),
+                )
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn it_parses_formatted_nodes_lists(
+            ref nodes in btree_map(
+                "[a-z0-9-]{1,36}",
+                (".{0,20}", "[a-z0-9-]{1,36}", option::of(vec(any::<u8>(), 0..64))),
+                0..20
+            )
+        ) {
+            let list = RawNodesList {
+                data: nodes
+                    .iter()
+                    .map(|(id, (hostname, policy_server, key))| {
+                        (
+                            id.clone(),
+                            Info {
+                                hostname: hostname.clone(),
+                                policy_server: policy_server.clone(),
+                                key_hash: key.as_ref().map(|k| HashType::Sha256.hash(k)),
+                                certificates: None,
+                            },
+                        )
+                    })
+                    .collect(),
+            };
+            let parsed = RawNodesList::from_str(&list.to_string()).unwrap();
+            prop_assert_eq!(comparable(&parsed), comparable(&list));
+        }
+
+        #[test]
+        fn it_parses_generated_nodes_lists(
+            ref nodes in btree_map(
+                "[a-e]{1,2}",
+                ("[a-z0-9.]{1,20}", "(root|[a-e]{1,2})", option::of("[0-9a-f]{64}")),
+                0..20
+            )
+        ) {
+            let raw: BTreeMap<&String, serde_json::Value> = nodes
+                .iter()
+                .map(|(id, (hostname, policy_server, key_hash))| {
+                    (
+                        id,
+                        serde_json::json!({
+                            "hostname": hostname,
+                            "policy-server": policy_server,
+                            // Nodes without keys have an empty hash
+                            "key-hash": format!("sha256:{}", key_hash.clone().unwrap_or_default()),
+                        }),
+                    )
+                })
+                .collect();
+            let list = RawNodesList::from_str(&serde_json::to_string(&raw).unwrap()).unwrap();
+
+            prop_assert_eq!(list.data.len(), nodes.len());
+            for (id, (hostname, policy_server, key_hash)) in nodes {
+                let info = &list.data[id];
+                prop_assert_eq!(&info.hostname, hostname);
+                prop_assert_eq!(&info.policy_server, policy_server);
+                prop_assert_eq!(&info.key_hash.as_ref().map(|h| h.value.clone()), key_hash);
+            }
+
+            // Loops and unknown policy servers are allowed
+            let topology = Topology::new("root", &list);
+            for (id, info) in &list.data {
+                prop_assert!(topology.routes.contains_key(id));
+                if info.policy_server == "root" {
+                    prop_assert_eq!(&topology.routes[id], &Route::Direct);
+                }
+            }
+        }
+    }
 }
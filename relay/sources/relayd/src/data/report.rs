@@ -283,6 +283,47 @@ impl Display for Report {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::{collection::vec, prelude::any, prop_assert_eq, prop_compose, proptest};
+
+    /// Report fields cannot contain `@` or line breaks
+    const FIELD: &str = "[a-zA-Z0-9_ .-]{0,20}";
+
+    prop_compose! {
+        fn datetime()(timestamp in 0i64..4_000_000_000, offset in -12i32..13) -> DateTime<FixedOffset> {
+            FixedOffset::east(offset * 3600).timestamp(timestamp, 0)
+        }
+    }
+
+    prop_compose! {
+        fn generated_report()(
+            policy in FIELD,
+            event_type in FIELD,
+            rule_id in FIELD,
+            directive_id in FIELD,
+            serial in any::<i32>(),
+            component in FIELD,
+            key_value in FIELD,
+            start_datetime in datetime(),
+            node_id in "[a-zA-Z0-9-]{1,36}",
+            // Lines must not look like agent logs
+            msg in vec("[a-zA-Z][a-zA-Z0-9 ]{0,30}", 1..4),
+            execution_datetime in datetime()
+        ) -> Report {
+            Report {
+                start_datetime,
+                rule_id,
+                directive_id,
+                component,
+                key_value,
+                event_type,
+                msg: msg.join("\n"),
+                policy,
+                node_id,
+                execution_datetime,
+                serial,
+            }
+        }
+    }
 
     #[test]
     fn it_formats_report() {
@@ -529,4 +570,18 @@ mod tests {
             Err("test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
         );
     }
+
+    proptest! {
+        #[test]
+        fn it_parses_formatted_runlogs(ref reports in vec(generated_report(), 1..10)) {
+            let formatted: String = reports
+                .iter()
+                .map(|r| format!("{} R: {}\n", r.execution_datetime.to_rfc3339(), r))
+                .collect();
+            let (remaining, parsed) = runlog(&formatted).unwrap();
+            prop_assert_eq!(remaining, "");
+            let parsed: Vec<Report> = parsed.into_iter().map(|r| r.unwrap().report).collect();
+            prop_assert_eq!(&parsed, reports);
+        }
+    }
 }
@@ -2,12 +2,14 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
+    configuration::main::{FilterAction, FilterRule, RedactionRule, ReportField, ReportFilters},
     data::{
         report::{runlog, RawReport},
         Report, RunInfo,
     },
     error::Error,
 };
+use regex::NoExpand;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
@@ -61,6 +63,77 @@ impl RunLog {
                 .collect(),
         }
     }
+
+    /// Applies filter rules, then the limit of log reports. Returns the
+    /// number of removed reports.
+    pub fn filter(&mut self, filters: &ReportFilters) -> usize {
+        let before = self.reports.len();
+        let mut logs = 0;
+        self.reports.retain(|report| {
+            let keep = filters
+                .rules
+                .iter()
+                .find(|rule| matches(report, rule))
+                .map_or(true, |rule| rule.action == FilterAction::Keep);
+            if !keep || !report.event_type.starts_with("log_") {
+                return keep;
+            }
+            logs += 1;
+            filters.max_logs.map_or(true, |max| logs <= max)
+        });
+        before - self.reports.len()
+    }
+
+    /// Replaces the matches of redaction rules in reports, returns the
+    /// number of replacements
+    pub fn redact(&mut self, rules: &[RedactionRule]) -> usize {
+        let mut count = 0;
+        for report in &mut self.reports {
+            for rule in rules {
+                if rule
+                    .rule_id
+                    .as_ref()
+                    .map_or(false, |r| *r != report.rule_id)
+                    || rule
+                        .directive_id
+                        .as_ref()
+                        .map_or(false, |d| *d != report.directive_id)
+                {
+                    continue;
+                }
+                for field in &rule.fields {
+                    let value = match field {
+                        ReportField::Msg => &mut report.msg,
+                        ReportField::KeyValue => &mut report.key_value,
+                    };
+                    let matches = rule.regex.0.find_iter(value).count();
+                    if matches > 0 {
+                        *value = rule
+                            .regex
+                            .0
+                            .replace_all(value, NoExpand(&rule.replacement))
+                            .into_owned();
+                        count += matches;
+                    }
+                }
+            }
+        }
+        count
+    }
+}
+
+fn matches(report: &Report, rule: &FilterRule) -> bool {
+    let field =
+        |criterion: &Option<String>, value: &str| criterion.as_ref().map_or(true, |c| c == value);
+    field(&rule.rule_id, &report.rule_id)
+        && field(&rule.directive_id, &report.directive_id)
+        && field(&rule.component, &report.component)
+        && field(&rule.node_id, &report.node_id)
+        && field(&rule.event_type, &report.event_type)
+        && rule
+            .msg
+            .as_ref()
+            .map_or(true, |msg| msg.0.is_match(&report.msg))
 }
 
 impl TryFrom<(RunInfo, &str)> for RunLog {
@@ -146,6 +219,131 @@ mod tests {
         assert!(test_done > 1);
     }
 
+    #[test]
+    fn it_redacts_reports() {
+        use crate::configuration::main::Pattern;
+        use regex::Regex;
+
+        let mut runlog =
+            RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log").unwrap();
+        let reference = RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log")
+            .unwrap()
+            .reports;
+        let directive_id = reference[0].directive_id.clone();
+        let rules = vec![
+            RedactionRule {
+                regex: Pattern(Regex::new("[aeiou]").unwrap()),
+                fields: vec![ReportField::Msg].into_iter().collect(),
+                replacement: "$0*".to_string(),
+                rule_id: None,
+                directive_id: Some(directive_id.clone()),
+            },
+            // Never matches
+            RedactionRule {
+                regex: Pattern(Regex::new(".*").unwrap()),
+                fields: vec![ReportField::Msg, ReportField::KeyValue]
+                    .into_iter()
+                    .collect(),
+                replacement: "".to_string(),
+                rule_id: Some("unknown".to_string()),
+                directive_id: None,
+            },
+        ];
+
+        let count = runlog.redact(&rules);
+
+        let expected: usize = reference
+            .iter()
+            .filter(|r| r.directive_id == directive_id)
+            .map(|r| r.msg.matches(|c| "aeiou".contains(c)).count())
+            .sum();
+        assert!(count > 0);
+        assert_eq!(count, expected);
+        for (redacted, original) in runlog.reports.iter().zip(reference.iter()) {
+            assert_eq!(redacted.key_value, original.key_value);
+            if original.directive_id == directive_id {
+                // Replacement is not expanded
+                assert_eq!(
+                    redacted.msg.matches("$0*").count(),
+                    original.msg.matches(|c| "aeiou".contains(c)).count()
+                );
+            } else {
+                assert_eq!(redacted.msg, original.msg);
+            }
+        }
+    }
+
+    #[test]
+    fn it_filters_reports() {
+        use crate::configuration::main::Pattern;
+        use regex::Regex;
+
+        let reference = RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log")
+            .unwrap()
+            .reports;
+        let directive_id = "common-root".to_string();
+        let kept = reference
+            .iter()
+            .find(|r| r.directive_id == directive_id)
+            .unwrap()
+            .msg
+            .clone();
+        let logs = |reports: &[Report]| {
+            reports
+                .iter()
+                .filter(|r| r.event_type.starts_with("log_"))
+                .count()
+        };
+
+        let mut runlog =
+            RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log").unwrap();
+        assert_eq!(runlog.filter(&ReportFilters::default()), 0);
+        assert_eq!(runlog.reports, reference);
+
+        // Keep the first report of the directive, drop the others
+        let filters = ReportFilters {
+            rules: vec![
+                FilterRule {
+                    action: FilterAction::Keep,
+                    directive_id: Some(directive_id.clone()),
+                    msg: Some(Pattern(
+                        Regex::new(&format!("^{}$", regex::escape(&kept))).unwrap(),
+                    )),
+                    ..Default::default()
+                },
+                FilterRule {
+                    directive_id: Some(directive_id.clone()),
+                    ..Default::default()
+                },
+            ],
+            max_logs: None,
+        };
+        let removed = runlog.filter(&filters);
+        let same_msg = reference
+            .iter()
+            .filter(|r| r.directive_id == directive_id && r.msg == kept)
+            .count();
+        let expected = reference
+            .iter()
+            .filter(|r| r.directive_id == directive_id)
+            .count()
+            - same_msg;
+        assert_eq!(removed, expected);
+        assert_eq!(runlog.reports.len(), reference.len() - expected);
+
+        // Limit logs
+        let mut runlog =
+            RunLog::new("tests/files/runlogs/2019-12-02T14:24:20+00:00@root.log").unwrap();
+        assert!(logs(&reference) > 1);
+        let removed = runlog.filter(&ReportFilters {
+            rules: vec![],
+            max_logs: Some(1),
+        });
+        assert_eq!(removed, logs(&reference) - 1);
+        assert_eq!(logs(&runlog.reports), 1);
+        assert_eq!(runlog.reports.len(), reference.len() - logs(&reference) + 1);
+    }
+
     #[test]
     fn it_detect_invalid_node_in_runlog() {
         assert!(
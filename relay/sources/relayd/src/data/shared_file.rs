@@ -232,6 +232,11 @@ impl Metadata {
 mod tests {
     use super::*;
     use openssl::sign::Signer;
+    use proptest::{
+        collection::vec, option, prelude::any, prop_assert_eq, prop_oneof, proptest, strategy::Just,
+    };
+
+    const SHORT_PUBKEY: &str = "MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==";
 
     #[test]
     fn it_checks_shared_file() {
@@ -322,4 +327,29 @@ mod tests {
             Metadata::validate_signature_key(keypub, data, HashType::Sha512, &signature).unwrap()
         );
     }
+
+    proptest! {
+        #[test]
+        fn it_parses_formatted_metadata(
+            hash_type in prop_oneof![Just(HashType::Sha256), Just(HashType::Sha512)],
+            ref data in vec(any::<u8>(), 0..256),
+            ref digest in "([0-9a-f]{2}){0,512}",
+            ref hostname in "[^\r\n]*",
+            ref key_date in "[^\r\n]*",
+            ref key_id in "[^\r\n]*",
+            expires in option::of(any::<i64>())
+        ) {
+            let metadata = Metadata {
+                format: SignatureFormat::RudderV1,
+                digest: digest.clone(),
+                hash: hash_type.hash(data),
+                short_pubkey: SHORT_PUBKEY.to_string(),
+                expires,
+                hostname: hostname.clone(),
+                key_date: key_date.clone(),
+                key_id: key_id.clone(),
+            };
+            prop_assert_eq!(Metadata::from_str(&metadata.to_string()).unwrap(), metadata);
+        }
+    }
 }
@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::data::node::NodeId;
+use crate::{configuration::main::ApiScope, data::node::NodeId};
 use chrono;
 use diesel;
 use serde_json;
@@ -41,6 +41,8 @@ pub enum Error {
     Io(#[from] io::Error),
     #[error("configuration parsing error: {0}")]
     ConfigurationParsing(#[from] toml::de::Error),
+    #[error("configuration serialization error: {0}")]
+    ConfigurationSerialization(#[from] toml::ser::Error),
     #[error("date parsing error: {0}")]
     DateParsing(#[from] chrono::ParseError),
     #[error("json parsing error: {0}")]
@@ -98,4 +100,34 @@ pub enum Error {
     InvalidSharedFile(String),
     #[error("could not extract zip file: {0}")]
     Zip(#[from] zip::result::ZipError),
+    #[error("invalid file kind: {0} (available kinds: reports, inventories)")]
+    InvalidFileKind(String),
+    #[error("invalid output format: {0} (available formats: table, json)")]
+    InvalidOutputFormat(String),
+    #[error("signed run log, a certificate is needed to read it")]
+    SignedRunlog,
+    #[error("invalid syslog address: {0}")]
+    InvalidSyslogAddress(String),
+    #[error("invalid secret: {0}")]
+    InvalidSecret(String),
+    #[error("unknown user or group: {0}")]
+    UnknownOwner(String),
+    #[error("invalid trace context: {0}")]
+    InvalidTraceContext(String),
+    #[error("unknown source node: {0}")]
+    UnknownSourceNode(NodeId),
+    #[error("public key of {0} does not match its known hash")]
+    InvalidPublicKey(NodeId),
+    #[error("invalid file signature")]
+    InvalidSignature,
+    #[error("uploads refused as disk usage is over the high watermark")]
+    UploadsRefused,
+    #[error("archive of processed files is disabled")]
+    ArchiveDisabled,
+    #[error("missing API token")]
+    MissingApiToken,
+    #[error("invalid API token")]
+    InvalidApiToken,
+    #[error("API token {token:} does not have the {scope:} scope")]
+    MissingApiScope { token: String, scope: ApiScope },
 }
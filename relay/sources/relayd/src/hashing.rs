@@ -140,6 +140,10 @@ impl HashType {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::{
+        collection::vec, prelude::any, prop_assert, prop_assert_eq, prop_oneof, proptest,
+        strategy::Just,
+    };
 
     #[test]
     fn it_parses_hash_types() {
@@ -204,4 +208,22 @@ mod tests {
             .is_valid_hash("{301df08cfc11928ee30b4624fbbb6aba068f06faa1c4d5e7516cf7f7b7cb36e8a38d9095ecaadef97882f093921096e9340d452b0c47e9854414e7c05e0c6c4"));
         assert!(!sha512.is_valid_hash("test"));
     }
+
+    proptest! {
+        #[test]
+        fn it_parses_formatted_hashes(
+            hash_type in prop_oneof![Just(HashType::Sha256), Just(HashType::Sha512)],
+            ref data in vec(any::<u8>(), 0..256)
+        ) {
+            let hash = hash_type.hash(data);
+            prop_assert_eq!(Hash::from_str(&hash.to_string()).unwrap(), hash.clone());
+            prop_assert_eq!(Hash::new(hash_type.to_string(), hash.value.clone()).unwrap(), hash);
+        }
+
+        #[test]
+        fn it_refuses_truncated_hashes(ref value in "([0-9a-f]{2}){0,31}") {
+            prop_assert!(Hash::from_str(&format!("sha256:{}", value)).is_err());
+            prop_assert!(Hash::from_str(&format!("sha512:{}{}", value, value)).is_err());
+        }
+    }
 }
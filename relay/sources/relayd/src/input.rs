@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+pub mod pressure;
 pub mod watch;
 
 use crate::error::Error;
@@ -22,19 +23,26 @@ use zip::read::ZipArchive;
 pub fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
     let path = path.as_ref();
 
-    debug!("Reading {:#?} content", path);
-    let data = read(path)?;
+    let extension = path.extension().and_then(OsStr::to_str);
+    debug!(
+        "Reading {:#?} content, with {:?} extension",
+        path, extension
+    );
+    uncompress(read(path)?, extension)
+}
 
-    Ok(match path.extension().and_then(OsStr::to_str) {
+/// Extracts `data` according to the extension of the file it was read from
+pub fn uncompress(data: Vec<u8>, extension: Option<&str>) -> Result<Vec<u8>, Error> {
+    Ok(match extension {
         Some("gz") => {
-            debug!("{:?} has .gz extension, extracting", path);
+            debug!("gz extension, extracting");
             let mut gz = GzDecoder::new(data.as_slice());
             let mut uncompressed_data = vec![];
             gz.read_to_end(&mut uncompressed_data)?;
             uncompressed_data
         }
         Some("zip") => {
-            debug!("{:?} has .zip extension, extracting", path);
+            debug!("zip extension, extracting");
             let mut zip = ZipArchive::new(Cursor::new(data))?;
             // Considering only the first file in the zip
             // There should be only one anyway
@@ -45,10 +53,7 @@ pub fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
         }
         // Let's assume everything else is a text file
         _ => {
-            debug!(
-                "{:?} has no compressed file extension, no extraction needed",
-                path
-            );
+            debug!("no compressed file extension, no extraction needed");
             data
         }
     })
@@ -98,7 +103,10 @@ pub fn signature(input: &[u8], certs: &Stack<X509>) -> Result<String, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::read_to_string;
+    use flate2::{write::GzEncoder, Compression};
+    use proptest::{collection::vec, prelude::any, prop_assert_eq, proptest};
+    use std::{fs::read_to_string, io::Write};
+    use zip::{write::FileOptions, ZipWriter};
 
     #[test]
     fn it_reads_gzipped_files() {
@@ -177,4 +185,21 @@ mod tests {
 
         assert!(signature(&read("tests/files/smime/normal.signed").unwrap(), &certs,).is_err());
     }
+
+    proptest! {
+        #[test]
+        fn it_uncompresses_compressed_data(ref data in vec(any::<u8>(), 0..4096)) {
+            let mut gz = GzEncoder::new(vec![], Compression::default());
+            gz.write_all(data).unwrap();
+            prop_assert_eq!(&uncompress(gz.finish().unwrap(), Some("gz")).unwrap(), data);
+
+            let mut zip = ZipWriter::new(Cursor::new(vec![]));
+            zip.start_file("normal.log", FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+            let zip = zip.finish().unwrap().into_inner();
+            prop_assert_eq!(&uncompress(zip, Some("zip")).unwrap(), data);
+
+            prop_assert_eq!(&uncompress(data.clone(), Some("log")).unwrap(), data);
+        }
+    }
 }
@@ -0,0 +1,330 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::{DiskUsageConfig, PressureBehavior, WatchedDirectory},
+    error::Error,
+    processing::Queue,
+    JobConfig,
+};
+use futures::{
+    future::{poll_fn, Future},
+    stream,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{read_dir, remove_file},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Instant, SystemTime},
+};
+use tokio::{prelude::*, timer::Interval};
+use tokio_threadpool::blocking;
+use tracing::{debug, info, warn};
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DirectoryUsage {
+    pub files: u64,
+    pub bytes: u64,
+    /// Set when a high watermark is reached, until usage goes
+    /// back under the low watermarks
+    pub under_pressure: bool,
+    /// Files removed by the `drop-oldest` behavior since startup
+    pub dropped: u64,
+}
+
+/// Disk usage of the watched directories, shared between the monitoring
+/// jobs, the processing jobs and the API
+#[derive(Debug, Default)]
+pub struct DiskPressure {
+    directories: RwLock<HashMap<WatchedDirectory, (DirectoryUsage, PressureBehavior)>>,
+}
+
+impl DiskPressure {
+    fn usage(&self, path: &Path) -> Option<DirectoryUsage> {
+        self.directories
+            .read()
+            .expect("could not read disk pressure")
+            .get(path)
+            .map(|(usage, _)| *usage)
+    }
+
+    fn update(&self, path: &Path, usage: DirectoryUsage, behavior: PressureBehavior) {
+        let _ = self
+            .directories
+            .write()
+            .expect("could not write disk pressure")
+            .insert(path.to_path_buf(), (usage, behavior));
+    }
+
+    /// Behavior to apply to files of the given directory, if it is under pressure
+    pub fn behavior(&self, path: &Path) -> Option<PressureBehavior> {
+        self.directories
+            .read()
+            .expect("could not read disk pressure")
+            .get(path)
+            .filter(|(usage, _)| usage.under_pressure)
+            .map(|(_, behavior)| *behavior)
+    }
+
+    /// Returns true when new files must not be picked up in the directory,
+    /// i.e. when it is under pressure with the `pause` or `refuse` behavior
+    pub fn holds_intake(&self, path: &Path) -> bool {
+        match self.behavior(path) {
+            Some(PressureBehavior::Pause) | Some(PressureBehavior::Refuse) => true,
+            Some(PressureBehavior::DropOldest) | None => false,
+        }
+    }
+
+    /// Returns true when the API must not add files to the directory, i.e.
+    /// when it is under pressure with the `refuse` behavior
+    pub fn refuses_uploads(&self, path: &Path) -> bool {
+        self.behavior(path) == Some(PressureBehavior::Refuse)
+    }
+
+    pub fn status(&self) -> BTreeMap<String, DirectoryUsage> {
+        self.directories
+            .read()
+            .expect("could not read disk pressure")
+            .iter()
+            .map(|(path, (usage, _))| (path.display().to_string(), *usage))
+            .collect()
+    }
+}
+
+fn is_above_high(cfg: &DiskUsageConfig, files: u64, bytes: u64) -> bool {
+    cfg.high_files.map(|high| files >= high).unwrap_or(false)
+        || cfg.high_bytes.map(|high| bytes >= high).unwrap_or(false)
+}
+
+fn is_under_low(cfg: &DiskUsageConfig, files: u64, bytes: u64) -> bool {
+    let low_files = cfg
+        .low_files
+        .or_else(|| cfg.high_files.map(|high| high * 9 / 10));
+    let low_bytes = cfg
+        .low_bytes
+        .or_else(|| cfg.high_bytes.map(|high| high * 9 / 10));
+    low_files.map(|low| files <= low).unwrap_or(true)
+        && low_bytes.map(|low| bytes <= low).unwrap_or(true)
+}
+
+/// Files in the directory with their size and modification time
+fn list(path: &Path) -> Result<Vec<(PathBuf, u64, SystemTime)>, Error> {
+    let now = SystemTime::now();
+    Ok(read_dir(path)?
+        // Files may have been removed in the meantime, skip them
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok().map(|m| (entry.path(), m)))
+        .filter(|(_, metadata)| metadata.is_file())
+        .map(|(path, metadata)| (path, metadata.len(), metadata.modified().unwrap_or(now)))
+        .collect())
+}
+
+/// Updates the usage of the directory, and returns the oldest files to process
+/// to go back under the low watermarks when under pressure with the `pause` or
+/// `refuse` behavior
+fn check(
+    path: &Path,
+    cfg: DiskUsageConfig,
+    pressure: &DiskPressure,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut files = list(path)?;
+    let previous = pressure.usage(path).unwrap_or_default();
+    let mut usage = DirectoryUsage {
+        files: files.len() as u64,
+        bytes: files.iter().map(|(_, size, _)| size).sum(),
+        ..previous
+    };
+    debug!("{:?} usage: {:?}", path, usage);
+
+    if !usage.under_pressure && is_above_high(&cfg, usage.files, usage.bytes) {
+        warn!(
+            "{:?} reached its high watermark ({} files, {} bytes), applying {:?} behavior",
+            path, usage.files, usage.bytes, cfg.behavior
+        );
+        usage.under_pressure = true;
+    }
+
+    let mut drained = vec![];
+    if usage.under_pressure {
+        files.sort_by_key(|(_, _, modified)| *modified);
+        match cfg.behavior {
+            PressureBehavior::DropOldest => {
+                for (file, size, _) in files {
+                    if is_under_low(&cfg, usage.files, usage.bytes) {
+                        break;
+                    }
+                    match remove_file(&file) {
+                        Ok(()) => {
+                            debug!("removed old file: {:?}", file);
+                            usage.files -= 1;
+                            usage.bytes -= size;
+                            usage.dropped += 1;
+                        }
+                        Err(e) => warn!("could not remove {:?}: {}", file, e),
+                    }
+                }
+            }
+            // Files stay in the directory until they are processed
+            PressureBehavior::Pause | PressureBehavior::Refuse => {
+                let (mut files_left, mut bytes_left) = (usage.files, usage.bytes);
+                for (file, size, _) in files {
+                    if is_under_low(&cfg, files_left, bytes_left) {
+                        break;
+                    }
+                    files_left -= 1;
+                    bytes_left -= size;
+                    drained.push(file);
+                }
+            }
+        }
+    }
+
+    if usage.under_pressure && is_under_low(&cfg, usage.files, usage.bytes) {
+        info!(
+            "{:?} is back under its low watermark ({} files, {} bytes)",
+            path, usage.files, usage.bytes
+        );
+        usage.under_pressure = false;
+    }
+
+    pressure.update(path, usage, cfg.behavior);
+    Ok(drained)
+}
+
+/// Monitors the disk usage of the directory, and sends its oldest files to the
+/// queue while it is under pressure with the `pause` or `refuse` behavior
+pub fn monitor(
+    path: WatchedDirectory,
+    cfg: DiskUsageConfig,
+    queue: Queue,
+    job_config: Arc<JobConfig>,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), cfg.frequency)
+        .map_err(|e| warn!("interval error: {}", e))
+        .for_each(move |_instant| {
+            let path = path.clone();
+            let queue = queue.clone();
+            let job_config = job_config.clone();
+            poll_fn(move || {
+                blocking(|| check(&path, cfg, &job_config.pressure))
+                    .map_err(|_| panic!("the thread pool shut down"))
+            })
+            .and_then(move |res| {
+                let files = res.unwrap_or_else(|e| {
+                    warn!("disk usage error: {}", e);
+                    vec![]
+                });
+                stream::iter_ok(files).for_each(move |file| {
+                    debug!("draining {:?}", file);
+                    queue.send(file)
+                })
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::{set_file_mtime, FileTime};
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_drops_oldest_files_under_pressure() {
+        let dir = tempdir().unwrap();
+        for i in 0..10 {
+            let file = dir.path().join(format!("{}.log", i));
+            write(&file, "report").unwrap();
+            set_file_mtime(&file, FileTime::from_unix_time(1_000_000 + i, 0)).unwrap();
+        }
+        let cfg = DiskUsageConfig {
+            high_files: Some(10),
+            low_files: Some(6),
+            behavior: PressureBehavior::DropOldest,
+            ..Default::default()
+        };
+        let pressure = DiskPressure::default();
+
+        check(dir.path(), cfg, &pressure).unwrap();
+
+        assert_eq!(
+            pressure.usage(dir.path()).unwrap(),
+            DirectoryUsage {
+                files: 6,
+                bytes: 36,
+                under_pressure: false,
+                dropped: 4,
+            }
+        );
+        assert!(!dir.path().join("3.log").exists());
+        assert!(dir.path().join("4.log").exists());
+    }
+
+    #[test]
+    fn it_drains_oldest_files_while_paused() {
+        let dir = tempdir().unwrap();
+        for i in 0..12 {
+            let file = dir.path().join(format!("{}.log", i));
+            write(&file, "report").unwrap();
+            set_file_mtime(&file, FileTime::from_unix_time(1_000_000 + i, 0)).unwrap();
+        }
+        let cfg = DiskUsageConfig {
+            high_files: Some(10),
+            low_files: Some(6),
+            behavior: PressureBehavior::Pause,
+            ..Default::default()
+        };
+        let pressure = DiskPressure::default();
+
+        let drained = check(dir.path(), cfg, &pressure).unwrap();
+        assert!(pressure.holds_intake(dir.path()));
+        assert_eq!(
+            drained,
+            (0..6)
+                .map(|i| dir.path().join(format!("{}.log", i)))
+                .collect::<Vec<_>>()
+        );
+
+        // Files are removed once processed
+        for file in drained {
+            remove_file(file).unwrap();
+        }
+        assert!(check(dir.path(), cfg, &pressure).unwrap().is_empty());
+        assert!(!pressure.holds_intake(dir.path()));
+        assert_eq!(pressure.usage(dir.path()).unwrap().files, 6);
+    }
+
+    #[test]
+    fn it_keeps_pressure_until_low_watermark() {
+        let dir = tempdir().unwrap();
+        for i in 0..10 {
+            write(dir.path().join(format!("{}.log", i)), "report").unwrap();
+        }
+        let cfg = DiskUsageConfig {
+            high_bytes: Some(50),
+            behavior: PressureBehavior::Refuse,
+            ..Default::default()
+        };
+        let pressure = DiskPressure::default();
+
+        check(dir.path(), cfg, &pressure).unwrap();
+        assert_eq!(
+            pressure.behavior(dir.path()),
+            Some(PressureBehavior::Refuse)
+        );
+        assert!(pressure.refuses_uploads(dir.path()));
+
+        // 54 bytes is still above the default low watermark (45 bytes)
+        remove_file(dir.path().join("0.log")).unwrap();
+        check(dir.path(), cfg, &pressure).unwrap();
+        assert!(pressure.refuses_uploads(dir.path()));
+
+        remove_file(dir.path().join("1.log")).unwrap();
+        remove_file(dir.path().join("2.log")).unwrap();
+        check(dir.path(), cfg, &pressure).unwrap();
+        assert_eq!(pressure.behavior(dir.path()), None);
+        assert!(!pressure.refuses_uploads(dir.path()));
+    }
+}
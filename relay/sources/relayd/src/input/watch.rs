@@ -3,18 +3,20 @@
 
 use crate::{
     configuration::main::{CatchupConfig, CleanupConfig, WatchedDirectory},
-    processing::ReceivedFile,
+    error::Error,
+    processing::{Queue, ReceivedFile},
     JobConfig,
 };
 use futures::{
     future::{poll_fn, Future},
-    sync::mpsc,
-    Stream,
+    stream, Stream,
 };
 use inotify::{Inotify, WatchMask};
 use std::{
-    path::Path,
-    sync::Arc,
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
     time::{Duration, Instant, SystemTime},
 };
 use tokio::{
@@ -22,11 +24,28 @@ use tokio::{
     prelude::*,
     timer::Interval,
 };
-use tracing::{debug, info, span, warn, Level};
+use tokio_threadpool::blocking;
+use tracing::{debug, error, info, span, warn, Level};
 
-pub fn cleanup(path: WatchedDirectory, cfg: CleanupConfig) -> impl Future<Item = (), Error = ()> {
+/// Removes files older than the retention, unless `is_held` returns true
+pub fn cleanup<F>(
+    path: WatchedDirectory,
+    cfg: CleanupConfig,
+    is_held: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn() -> bool + Send + 'static,
+{
+    let held_path = path.clone();
     Interval::new(Instant::now(), cfg.frequency)
         .map_err(|e| warn!("interval error: {}", e))
+        .filter(move |_instant| {
+            let held = is_held();
+            if held {
+                debug!("skipping cleanup of {:?} as its files are held", held_path);
+            }
+            !held
+        })
         .for_each(move |_instant| {
             debug!("cleaning {:?}", path);
 
@@ -61,63 +80,117 @@ pub fn cleanup(path: WatchedDirectory, cfg: CleanupConfig) -> impl Future<Item =
         })
 }
 
-pub fn watch(
-    path: &WatchedDirectory,
-    job_config: &Arc<JobConfig>,
-    tx: &mpsc::Sender<ReceivedFile>,
-) {
+/// Watchers that stopped, with the reason. They are not restarted, files
+/// are still picked up by the catchup job but with a delay.
+#[derive(Debug, Default)]
+pub struct WatcherHealth {
+    stopped: RwLock<BTreeMap<WatchedDirectory, String>>,
+}
+
+impl WatcherHealth {
+    fn stopped(&self, path: &Path, reason: String) {
+        error!("file watcher on {:?} stopped: {}", path, reason);
+        let _ = self
+            .stopped
+            .write()
+            .expect("could not write watcher health")
+            .insert(path.to_path_buf(), reason);
+    }
+
+    pub fn failures(&self) -> BTreeMap<String, String> {
+        self.stopped
+            .read()
+            .expect("could not read watcher health")
+            .iter()
+            .map(|(path, reason)| (path.display().to_string(), reason.clone()))
+            .collect()
+    }
+}
+
+pub fn watch(path: &WatchedDirectory, queue: &Queue, job_config: &Arc<JobConfig>) {
     info!("Starting file watcher on {:#?}", &path);
     let report_span = span!(Level::TRACE, "watcher");
     let _report_enter = report_span.enter();
-    tokio::spawn(list_files(
-        path.clone(),
-        job_config.cfg.processing.reporting.catchup,
-        tx.clone(),
-    ));
-    tokio::spawn(watch_files(path.clone(), tx.clone()));
+    let job_config = job_config.clone();
+    let watched = path.clone();
+    tokio::spawn(watch_files(path.clone(), queue.clone()).then(move |res| {
+        job_config.watchers.stopped(
+            &watched,
+            res.err().unwrap_or_else(|| "no more events".to_string()),
+        );
+        Ok(())
+    }));
 }
 
-fn list_files(
-    path: WatchedDirectory,
+/// Files older than `min_age`, oldest first
+pub fn old_files(path: &Path, min_age: Duration, limit: u64) -> Result<Vec<ReceivedFile>, Error> {
+    let sys_time = SystemTime::now();
+    let mut files: Vec<(SystemTime, ReceivedFile)> = std::fs::read_dir(path)?
+        // Files may have been removed in the meantime, skip them
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok().map(|m| (entry.path(), m)))
+        .filter(|(_, metadata)| metadata.is_file())
+        // If modification time can't be fetched, consider it as new for now
+        .map(|(path, metadata)| (metadata.modified().unwrap_or(sys_time), path))
+        // An error indicates a file in the future
+        .filter(|(modified, _)| {
+            sys_time
+                .duration_since(*modified)
+                .map(|age| age > min_age)
+                .unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    Ok(files
+        .into_iter()
+        .take(limit as usize)
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Periodically sends files the watcher may have missed (during downtime or
+/// because of errors). Queues are given by decreasing priority, and share the
+/// `limit` of files sent at each run. Directories for which `is_held` returns
+/// true are skipped.
+pub fn catchup<F>(
+    queues: Vec<(WatchedDirectory, Queue)>,
     cfg: CatchupConfig,
-    tx: mpsc::Sender<ReceivedFile>,
-) -> impl Future<Item = (), Error = ()> {
+    is_held: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn(&Path) -> bool + Send + Sync + 'static,
+{
+    let is_held = Arc::new(is_held);
     Interval::new(Instant::now(), cfg.frequency)
         .map_err(|e| warn!("interval error: {}", e))
         .for_each(move |_instant| {
-            debug!("listing {:?}", path);
-
-            let tx = tx.clone();
-            let sys_time = SystemTime::now();
-
-            read_dir(path.clone())
-                .flatten_stream()
-                .take(cfg.limit)
-                .map_err(|e| warn!("list error: {}", e))
-                .filter(move |entry| {
-                    poll_fn(move || entry.poll_metadata())
-                        // If metadata can't be fetched, skip it for now
-                        .map(|metadata| metadata.modified().unwrap_or(sys_time))
-                        // An error indicates a file in the future, let's approximate it to now
-                        .map(|modified| {
-                            sys_time
-                                .duration_since(modified)
-                                .unwrap_or_else(|_| Duration::new(0, 0))
-                        })
-                        .map(|duration| duration > Duration::from_secs(30))
-                        .map_err(|e| warn!("list filter error: {}", e))
-                        // TODO async filter (https://github.com/rust-lang-nursery/futures-rs/pull/728)
-                        .wait()
-                        .unwrap_or(false)
+            let queues = queues.clone();
+            let is_held = is_held.clone();
+            poll_fn(move || {
+                blocking(|| {
+                    let mut files = vec![];
+                    for (path, queue) in &queues {
+                        if is_held(path) {
+                            debug!("skipping catchup of {:?} as its intake is held", path);
+                            continue;
+                        }
+                        debug!("listing {:?}", path);
+                        let remaining = cfg.limit.saturating_sub(files.len() as u64);
+                        match old_files(path, cfg.min_age, remaining) {
+                            Ok(old) => files.extend(old.into_iter().map(|f| (f, queue.clone()))),
+                            Err(e) => warn!("list error: {}", e),
+                        }
+                    }
+                    files
                 })
-                .for_each(move |entry| {
-                    let path = entry.path();
+                .map_err(|_| panic!("the thread pool shut down"))
+            })
+            .and_then(|files| {
+                stream::iter_ok(files).for_each(|(path, queue)| {
                     debug!("list: {:?}", path);
-                    tx.clone()
-                        .send(path)
-                        .map_err(|e| warn!("list error: {}", e))
-                        .map(|_| ())
+                    queue.send(path)
                 })
+            })
         })
 }
 
@@ -138,14 +211,12 @@ fn watch_stream<P: AsRef<Path>>(path: P) -> inotify::EventStream<Vec<u8>> {
     inotify.event_stream(Vec::from(&[0; 2048][..]))
 }
 
-fn watch_files<P: AsRef<Path>>(
-    path: P,
-    tx: mpsc::Sender<ReceivedFile>,
-) -> impl Future<Item = (), Error = ()> {
+fn watch_files<P: AsRef<Path>>(path: P, queue: Queue) -> impl Future<Item = (), Error = String> {
     let path_prefix = path.as_ref().to_path_buf();
     watch_stream(&path)
         .map_err(|e| {
             warn!("watch error: {}", e);
+            e.to_string()
         })
         .map(|entry| entry.name)
         // If it is None, it means it is not an event on a file in the directory, skipping
@@ -158,19 +229,138 @@ fn watch_files<P: AsRef<Path>>(
             full_path
         })
         .for_each(move |entry| {
-            tx.clone()
+            queue
                 .send(entry)
-                .map_err(|e| warn!("watch send error: {}", e))
-                .map(|_| ())
+                .map_err(|()| "processing queue is closed".to_string())
         })
 }
 
+/// Returns true if there were changes, and none during the debounce delay
+fn is_due(last_change: &Mutex<Option<Instant>>, debounce: Duration) -> bool {
+    let mut last_change = last_change.lock().expect("could not lock last change");
+    match *last_change {
+        Some(instant) if instant.elapsed() >= debounce => {
+            *last_change = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn reload_nodes_list(job_config: &JobConfig) {
+    // Files are likely being replaced, a missing file would give an empty list.
+    // The certificates file is optional, nodes are loaded without certificates.
+    let nodes_list_file = &job_config.cfg.general.nodes_list_file;
+    if !nodes_list_file.exists() {
+        warn!(
+            "{:?} does not exist, keeping current nodes list until next change",
+            nodes_list_file
+        );
+        return;
+    }
+    info!("Nodes list or certificates changed, reloading");
+    if let Err(e) = job_config.reload_nodeslist() {
+        error!("could not reload nodes list, keeping current one: {}", e);
+    }
+}
+
+/// Watches the nodes list and certificates files, and reloads them once they
+/// have not changed during the debounce delay
+pub fn watch_nodes_list(job_config: &Arc<JobConfig>) {
+    let debounce = job_config.cfg.general.nodes_list_debounce;
+    let last_change = Arc::new(Mutex::new(None));
+
+    // Watch parent directories as files are generally replaced
+    let mut directories: HashMap<PathBuf, Vec<OsString>> = HashMap::new();
+    for file in &[
+        &job_config.cfg.general.nodes_list_file,
+        &job_config.cfg.general.nodes_certs_file,
+    ] {
+        if let Some(name) = file.file_name() {
+            let directory = match file.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            directories
+                .entry(directory)
+                .or_default()
+                .push(name.to_os_string());
+        }
+    }
+
+    for (directory, names) in directories {
+        info!("Starting nodes list watcher on {:#?}", &directory);
+        let mut inotify = match Inotify::init().and_then(|mut inotify| {
+            inotify
+                .add_watch(&directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+                .map(|_| inotify)
+        }) {
+            Ok(inotify) => inotify,
+            Err(e) => {
+                warn!("could not watch {:?}, skipping: {}", directory, e);
+                continue;
+            }
+        };
+        let last_change = last_change.clone();
+        tokio::spawn(
+            inotify
+                .event_stream(Vec::from(&[0; 2048][..]))
+                .map_err(|e| warn!("watch error: {}", e))
+                .filter_map(|event| event.name)
+                .filter(move |name| names.contains(name))
+                .for_each(move |name| {
+                    debug!("inotify: {:?}", name);
+                    *last_change.lock().expect("could not lock last change") = Some(Instant::now());
+                    Ok(())
+                }),
+        );
+    }
+
+    let job_config = job_config.clone();
+    tokio::spawn(
+        Interval::new(Instant::now(), debounce)
+            .map_err(|e| warn!("interval error: {}", e))
+            .filter(move |_instant| is_due(&last_change, debounce))
+            .for_each(move |_instant| {
+                let job_config = job_config.clone();
+                poll_fn(move || {
+                    blocking(|| reload_nodes_list(&job_config))
+                        .map_err(|_| panic!("the thread pool shut down"))
+                })
+            }),
+    );
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{fs::File, path::PathBuf, str::FromStr};
+    use filetime::{set_file_mtime, FileTime};
+    use std::{fs::File, str::FromStr};
     use tempfile::tempdir;
 
+    #[test]
+    fn it_lists_old_files_first() {
+        let dir = tempdir().unwrap();
+        for (name, age) in &[("new.log", 0), ("old.log", 3600), ("older.log", 7200)] {
+            let file = dir.path().join(name);
+            File::create(&file).unwrap();
+            let modified = SystemTime::now() - Duration::from_secs(*age);
+            set_file_mtime(&file, FileTime::from_system_time(modified)).unwrap();
+        }
+
+        assert_eq!(
+            old_files(dir.path(), Duration::from_secs(30), 10).unwrap(),
+            vec![dir.path().join("older.log"), dir.path().join("old.log")]
+        );
+        assert_eq!(
+            old_files(dir.path(), Duration::from_secs(30), 1).unwrap(),
+            vec![dir.path().join("older.log")]
+        );
+        assert!(old_files(dir.path(), Duration::from_secs(30), 0)
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn it_watches_files() {
         let dir = tempdir().unwrap();
@@ -190,4 +380,16 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn it_debounces_changes() {
+        let last_change = Mutex::new(None);
+        assert!(!is_due(&last_change, Duration::from_secs(0)));
+
+        *last_change.lock().unwrap() = Some(Instant::now());
+        assert!(!is_due(&last_change, Duration::from_secs(60)));
+        assert!(is_due(&last_change, Duration::from_secs(0)));
+        // Only once per change
+        assert!(!is_due(&last_change, Duration::from_secs(0)));
+    }
 }
@@ -15,27 +15,57 @@ pub mod input;
 pub mod output;
 pub mod processing;
 pub mod stats;
+pub mod systemd;
+pub mod telemetry;
 
 use crate::{
     configuration::{
+        check::ConfigurationCheck,
         cli::CliConfiguration,
         logging::LogConfig,
-        main::{Configuration, InventoryOutputSelect, OutputSelect, ReportingOutputSelect},
+        main::{
+            Configuration, InventoryOutputSelect, OutputSelect, ReportFilters,
+            ReportingOutputSelect,
+        },
+    },
+    data::{
+        node::{NodesList, NodesListReload},
+        RunInfo, RunLog,
     },
-    data::node::NodesList,
     error::Error,
-    output::database::{pg_pool, PgPool},
-    processing::{inventory, reporting},
+    input::{
+        pressure::DiskPressure,
+        read_compressed_file, signature,
+        watch::{watch_nodes_list, WatcherHealth},
+    },
+    output::{
+        database::{pg_pool, DatabaseHealth, PgPool},
+        sink::{sinks, Sinks},
+        upstream::UpstreamHealth,
+    },
+    processing::{
+        archive::{self, ArchivedFile},
+        inventory,
+        replay::{self, replay_reports_from, ReplayFilter, ReplayKind, ReplayStatus, ReplayedFile},
+        reporting,
+        runs::Runs,
+        Gauges, InFlight,
+    },
     stats::Stats,
+    telemetry::TraceExport,
 };
+use chrono::Utc;
 use futures::{
     future::{lazy, Future},
     stream::Stream,
     sync::mpsc,
 };
+use openssl::{stack::Stack, x509::X509};
 use reqwest::r#async::Client;
 use std::{
-    fs::create_dir_all,
+    collections::HashSet,
+    convert::TryFrom,
+    fs::{create_dir_all, read},
     path::Path,
     process::exit,
     string::ToString,
@@ -43,7 +73,7 @@ use std::{
 };
 use structopt::clap::crate_version;
 use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
     filter::EnvFilter,
@@ -52,6 +82,7 @@ use tracing_subscriber::{
         format::{Format, Full, NewRecorder},
         Formatter,
     },
+    layer::SubscriberExt,
     reload::Handle,
 };
 
@@ -74,6 +105,10 @@ pub enum ExitStatus {
     Crash,
     /// Could not start properly due to an error
     StartError(Error),
+    /// Configuration test found problems preventing normal operation
+    InvalidConfiguration,
+    /// Configuration test only found warnings
+    ConfigurationWarnings,
 }
 
 impl ExitStatus {
@@ -84,6 +119,8 @@ impl ExitStatus {
             ExitStatus::Crash => 1,
             ExitStatus::StartError(Error::ConfigurationParsing(_)) => 2,
             ExitStatus::StartError(_) => 3,
+            ExitStatus::InvalidConfiguration => 4,
+            ExitStatus::ConfigurationWarnings => 5,
         }
     }
 }
@@ -98,7 +135,8 @@ pub fn init_logger() -> Result<LogHandle, Error> {
         .with_env_filter("error")
         .with_filter_reloading();
     let reload_handle = builder.reload_handle();
-    let subscriber = builder.finish();
+    // Spans export is started with the configuration
+    let subscriber = builder.finish().with(TraceExport::default());
     // Set logger for global context
     tracing::subscriber::set_global_default(subscriber)?;
 
@@ -108,10 +146,77 @@ pub fn init_logger() -> Result<LogHandle, Error> {
     Ok(reload_handle)
 }
 
-pub fn check_configuration(cfg_dir: &Path) -> Result<(), Error> {
-    Configuration::new(&cfg_dir)?;
-    LogConfig::new(&cfg_dir)?;
-    Ok(())
+/// Parses the configuration and looks for problems in it
+pub fn check_configuration(cfg_dir: &Path) -> Result<ConfigurationCheck, Error> {
+    ConfigurationCheck::new(cfg_dir)
+}
+
+/// Moves failed files back into incoming directories, does not require
+/// a running relayd
+pub fn replay_failed(
+    cfg_dir: &Path,
+    kind: ReplayKind,
+    filter: &ReplayFilter,
+) -> Result<Vec<ReplayedFile>, Error> {
+    let cfg = Configuration::new(&cfg_dir)?;
+    replay::replay(&cfg.processing, kind, filter)
+}
+
+/// Lists archived files, does not require a running relayd
+pub fn archived_files(
+    cfg_dir: &Path,
+    kind: ReplayKind,
+    filter: &ReplayFilter,
+) -> Result<Vec<ArchivedFile>, Error> {
+    let cfg = Configuration::new(&cfg_dir)?;
+    archive::list(
+        &cfg.processing.archive.ok_or(Error::ArchiveDisabled)?,
+        kind,
+        filter,
+    )
+}
+
+/// Parses a runlog like the reporting job does, without database.
+///
+/// When no event types to skip are given, uses the configured ones
+/// if the configuration is readable.
+pub fn validate_runlog(
+    cfg_dir: &Path,
+    file: &Path,
+    certificate: Option<&Path>,
+    skip_event_types: Option<&str>,
+) -> Result<RunLog, Error> {
+    let run_info = RunInfo::try_from(file)?;
+    let data = read_compressed_file(file)?;
+
+    let runlog = match certificate {
+        Some(certificate) => {
+            let mut certs = Stack::new()?;
+            for cert in X509::stack_from_pem(&read(certificate)?)? {
+                certs.push(cert)?;
+            }
+            signature(&data, &certs)?
+        }
+        None if data.starts_with(b"MIME-Version:") => return Err(Error::SignedRunlog),
+        None => String::from_utf8(data)?,
+    };
+    let parsed_runlog = RunLog::try_from((run_info, runlog.as_ref()))?;
+
+    let skip_event_types: HashSet<String> = match skip_event_types {
+        Some(types) => types
+            .split(',')
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string())
+            .collect(),
+        None => Configuration::new(cfg_dir)
+            .map(|cfg| cfg.processing.reporting.skip_event_types)
+            .unwrap_or_default(),
+    };
+    Ok(if skip_event_types.is_empty() {
+        parsed_runlog
+    } else {
+        parsed_runlog.without_types(&skip_event_types)
+    })
 }
 
 #[allow(clippy::cognitive_complexity)]
@@ -128,8 +233,25 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
     // ---- Setup data structures ----
 
     let cfg = Configuration::new(cli_cfg.configuration_dir.clone())?;
-    let stats = Arc::new(RwLock::new(Stats::default()));
+    if let Some(ref traces) = cfg.output.traces {
+        info!("Exporting spans to {}", traces.endpoint);
+        telemetry::start(traces, &cfg.general.node_id)?;
+    }
+    let stats = Arc::new(RwLock::new(match cfg.stats {
+        Some(ref stats_cfg) => Stats::load(&stats_cfg.file).unwrap_or_else(|e| {
+            warn!(
+                "could not load statistics from {:?}, starting from zero: {}",
+                stats_cfg.file, e
+            );
+            Stats::default()
+        }),
+        None => Stats::default(),
+    }));
     let job_config = JobConfig::new(cli_cfg, cfg, reload_handle)?;
+    let unix_socket = match job_config.cfg.general.unix_socket {
+        Some(ref cfg) => Some(api::bind_unix_socket(cfg)?),
+        None => None,
+    };
 
     // ---- Setup signal handlers ----
 
@@ -137,17 +259,29 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
 
     // SIGINT or SIGTERM: immediate shutdown
     // TODO: graceful shutdown
+    let stats_shutdown = stats.clone();
+    let stats_cfg = job_config.cfg.stats.clone();
     let shutdown = Signal::new(SIGINT)
         .flatten_stream()
         .select(Signal::new(SIGTERM).flatten_stream())
         .into_future()
-        .map(|_sig| {
+        .map(move |_sig| {
             info!("Signal received: shutdown requested");
+            if let Some(stats_cfg) = stats_cfg {
+                if let Err(e) = stats_shutdown
+                    .read()
+                    .expect("could not read stats")
+                    .save(&stats_cfg.file)
+                {
+                    error!("could not save statistics: {}", e);
+                }
+            }
+            systemd::notify(systemd::STOPPING);
             exit(ExitStatus::Shutdown.code());
         })
         .map_err(|e| error!("signal error {}", e.0));
 
-    // SIGHUP: reload logging configuration + nodes list
+    // SIGHUP: reload logging configuration + nodes list + secrets from files + report filters
     let job_config_reload = job_config.clone();
 
     let reload = Signal::new(SIGHUP)
@@ -173,11 +307,19 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
         tokio::spawn(reload);
         tokio::spawn(shutdown);
 
+        if job_config.cfg.general.watch_nodes_list {
+            watch_nodes_list(&job_config);
+        }
+
         let (tx_stats, rx_stats) = mpsc::channel(1_024);
 
         tokio::spawn(Stats::receiver(stats.clone(), rx_stats));
+        if let Some(ref stats_cfg) = job_config.cfg.stats {
+            tokio::spawn(Stats::persist(stats.clone(), stats_cfg.clone()));
+        }
         tokio::spawn(api::run(
             job_config.cfg.general.listen,
+            unix_socket,
             job_config.clone(),
             stats.clone(),
         ));
@@ -194,7 +336,16 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
             info!("Skipping inventory as it is disabled");
         }
 
+        if let Some(ref archive_cfg) = job_config.cfg.processing.archive {
+            tokio::spawn(archive::cleanup(archive_cfg.clone()));
+        }
+
+        if let Some(watchdog) = systemd::watchdog() {
+            tokio::spawn(watchdog);
+        }
+
         info!("Server started");
+        systemd::notify(systemd::READY);
         Ok(())
     }));
 
@@ -207,8 +358,24 @@ pub struct JobConfig {
     pub cli_cfg: CliConfiguration,
     pub cfg: Configuration,
     pub nodes: RwLock<NodesList>,
+    /// Changes made by the last nodes list reload
+    pub nodes_reload: RwLock<Option<NodesListReload>>,
     pub pool: Option<PgPool>,
     pub client: Client,
+    pub pressure: DiskPressure,
+    pub database_health: DatabaseHealth,
+    pub upstream_health: UpstreamHealth,
+    pub watchers: WatcherHealth,
+    /// Files currently being processed
+    pub in_flight: InFlight,
+    /// Processing queues state
+    pub gauges: Gauges,
+    /// Last runs of nodes
+    pub runs: Runs,
+    /// Additional outputs for parsed reports
+    pub sinks: Sinks,
+    /// Report filters, reloaded with the configuration
+    pub filters: RwLock<ReportFilters>,
     handle: LogHandle,
 }
 
@@ -244,6 +411,9 @@ impl JobConfig {
             .danger_accept_invalid_certs(!cfg.output.upstream.verify_certificates)
             .build()?;
 
+        let sinks = sinks(&cfg.processing.reporting.sinks)?;
+        let filters = RwLock::new(cfg.processing.reporting.filters.clone());
+
         let nodes = RwLock::new(NodesList::new(
             cfg.general.node_id.to_string(),
             &cfg.general.nodes_list_file,
@@ -254,19 +424,64 @@ impl JobConfig {
             cli_cfg,
             cfg,
             nodes,
+            nodes_reload: RwLock::new(None),
             pool,
             handle,
             client,
+            pressure: DiskPressure::default(),
+            database_health: DatabaseHealth::default(),
+            upstream_health: UpstreamHealth::default(),
+            watchers: WatcherHealth::default(),
+            in_flight: InFlight::default(),
+            gauges: Gauges::default(),
+            runs: Runs::default(),
+            sinks,
+            filters,
         }))
     }
 
     fn reload_nodeslist(&self) -> Result<(), Error> {
-        let mut nodes = self.nodes.write().expect("could not write nodes list");
-        *nodes = NodesList::new(
+        let new_nodes = NodesList::new(
             self.cfg.general.node_id.to_string(),
             &self.cfg.general.nodes_list_file,
             Some(&self.cfg.general.nodes_certs_file),
         )?;
+        let changes = {
+            let mut nodes = self.nodes.write().expect("could not write nodes list");
+            let changes = nodes.diff(&new_nodes);
+            *nodes = new_nodes;
+            changes
+        };
+
+        if changes.is_empty() {
+            info!("Nodes list reloaded, no changes");
+        } else {
+            info!("Nodes list reloaded: {}", changes);
+            debug!("Nodes list changes: {:?}", changes);
+        }
+
+        // Reports from new nodes were refused until now
+        if !changes.added.is_empty() && self.cfg.processing.reporting.output.is_enabled() {
+            match replay_reports_from(&self.cfg.processing, &changes.added) {
+                Ok(replayed) => {
+                    for file in replayed
+                        .iter()
+                        .filter(|f| f.status == ReplayStatus::Requeued)
+                    {
+                        info!("Report from new node {}", file);
+                    }
+                }
+                Err(e) => error!("could not requeue reports from new nodes: {}", e),
+            }
+        }
+
+        *self
+            .nodes_reload
+            .write()
+            .expect("could not write nodes list reload") = Some(NodesListReload {
+            date: Utc::now(),
+            changes,
+        });
         Ok(())
     }
 
@@ -278,13 +493,50 @@ impl JobConfig {
         })
     }
 
+    /// Secrets read from files. The sources themselves are not reloaded, and the
+    /// database password is only used to create the connection pool at startup.
+    fn reload_secrets(&self) -> Result<(), Error> {
+        for (name, secret) in self.cfg.secrets() {
+            if name == "output.database.password" {
+                continue;
+            }
+            if secret.reload()? {
+                info!("Secret {} reloaded from {}", name, secret.source());
+            }
+        }
+        Ok(())
+    }
+
+    /// Other parts of the main configuration need a restart
+    fn reload_filters(&self) -> Result<(), Error> {
+        let filters = Configuration::new(&self.cli_cfg.configuration_dir)?
+            .processing
+            .reporting
+            .filters;
+        let mut current = self.filters.write().expect("could not write filters");
+        if *current != filters {
+            info!("Report filters reloaded");
+            debug!("Report filters: {:?}", filters);
+            *current = filters;
+        }
+        Ok(())
+    }
+
     pub fn reload(&self) -> Result<(), Error> {
         info!("Configuration reload requested");
-        self.reload_logging()
-            .and_then(|_| self.reload_nodeslist())
-            .map_err(|e| {
-                error!("reload error {}", e);
-                e
-            })
+        // Parts are reloaded independently, the first error is returned
+        vec![
+            ("logging", self.reload_logging()),
+            ("nodes list", self.reload_nodeslist()),
+            ("secrets", self.reload_secrets()),
+            ("report filters", self.reload_filters()),
+        ]
+        .into_iter()
+        .fold(Ok(()), |reload, (part, result)| {
+            if let Err(ref e) = result {
+                error!("reload error for {}: {}", part, e);
+            }
+            reload.and(result)
+        })
     }
 }
@@ -2,4 +2,5 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 pub mod database;
+pub mod sink;
 pub mod upstream;
@@ -6,13 +6,16 @@ use crate::{
     data::{report::QueryableReport, RunLog},
     Error,
 };
+use chrono::prelude::*;
 use diesel::{
     insert_into,
     pg::PgConnection,
     prelude::*,
     r2d2::{ConnectionManager, Pool},
 };
-use tracing::{debug, error, span, trace, Level};
+use serde::Serialize;
+use std::sync::RwLock;
+use tracing::{debug, error, info, span, trace, warn, Level};
 
 pub mod schema {
     table! {
@@ -61,6 +64,99 @@ pub enum InsertionBehavior {
     AllowDuplicate,
 }
 
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DatabaseOutage {
+    pub since: DateTime<Utc>,
+    /// The database is available again, and reports received during the
+    /// outage are being inserted
+    pub resuming: bool,
+}
+
+/// Availability of the database, shared between the health check, the
+/// reporting job and the API.
+///
+/// Reports are kept in the incoming directory from the beginning of an outage
+/// until the end of the resume phase.
+#[derive(Debug, Default)]
+pub struct DatabaseHealth {
+    outage: RwLock<Option<DatabaseOutage>>,
+}
+
+impl DatabaseHealth {
+    pub fn outage(&self) -> Option<DatabaseOutage> {
+        *self.outage.read().expect("could not read database health")
+    }
+
+    /// Insertion is paused when false
+    pub fn is_available(&self) -> bool {
+        self.outage().map(|o| o.resuming).unwrap_or(true)
+    }
+
+    /// Received files must not be removed when true
+    pub fn holds_files(&self) -> bool {
+        self.outage().is_some()
+    }
+
+    pub fn unavailable(&self, error: &Error) {
+        let mut outage = self
+            .outage
+            .write()
+            .expect("could not write database health");
+        match *outage {
+            None => {
+                warn!(
+                    "database is unavailable, pausing reports insertion: {}",
+                    error
+                );
+                *outage = Some(DatabaseOutage {
+                    since: Utc::now(),
+                    resuming: false,
+                });
+            }
+            Some(ref mut o) if o.resuming => {
+                warn!(
+                    "database is unavailable again, pausing reports insertion: {}",
+                    error
+                );
+                o.resuming = false;
+            }
+            Some(_) => (),
+        }
+    }
+
+    /// Returns true when the database was unavailable, reports received
+    /// in the meantime should then be resumed
+    pub fn available(&self) -> bool {
+        let mut outage = self
+            .outage
+            .write()
+            .expect("could not write database health");
+        match *outage {
+            Some(ref mut o) if !o.resuming => {
+                info!(
+                    "database is available again after an outage since {}, resuming reports insertion",
+                    o.since
+                );
+                o.resuming = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// All reports received during the outage were sent for insertion
+    pub fn resumed(&self) {
+        let mut outage = self
+            .outage
+            .write()
+            .expect("could not write database health");
+        if (*outage).map(|o| o.resuming).unwrap_or(false) {
+            info!("reports received during the database outage were resumed");
+            *outage = None;
+        }
+    }
+}
+
 pub fn ping(pool: &PgPool) -> Result<(), Error> {
     use self::schema::ruddersysevents::dsl::*;
     let connection = &*pool.get()?;
@@ -145,10 +241,45 @@ mod tests {
             url: "postgres://rudderreports:@127.0.0.1/rudder".to_string(),
             password: Secret::new("PASSWORD".to_string()),
             max_pool_size: 5,
+            ..Default::default()
         };
         pg_pool(&db_config).unwrap()
     }
 
+    #[test]
+    fn it_tracks_database_outages() {
+        let health = DatabaseHealth::default();
+        let error = Error::InconsistentRunlog;
+        assert!(health.is_available());
+        assert!(!health.available());
+
+        health.unavailable(&error);
+        let since = health.outage().unwrap().since;
+        assert!(!health.is_available());
+        assert!(health.holds_files());
+
+        // Still down, nothing changes
+        health.unavailable(&error);
+        assert_eq!(health.outage().unwrap().since, since);
+        health.resumed();
+        assert!(health.holds_files());
+
+        assert!(health.available());
+        assert!(!health.available());
+        assert!(health.is_available());
+        assert!(health.holds_files());
+
+        // Down again while resuming, the outage continues
+        health.unavailable(&error);
+        assert!(!health.is_available());
+        assert_eq!(health.outage().unwrap().since, since);
+
+        assert!(health.available());
+        health.resumed();
+        assert!(health.is_available());
+        assert!(!health.holds_files());
+    }
+
     #[test]
     fn it_inserts_runlog() {
         let pool = db();
@@ -0,0 +1,364 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::{FileSinkConfig, SinkConfig, SyslogProtocol, SyslogSinkConfig},
+    data::{Report, RunLog},
+    error::Error,
+    stats::Event,
+};
+use futures::{sync::mpsc, Future, Sink};
+use std::{
+    ffi::OsString,
+    fs::{create_dir_all, rename, File, OpenOptions},
+    io::Write,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    process,
+    sync::Mutex,
+    time::Duration,
+};
+use tracing::{debug, error, warn};
+
+/// Name used in syslog messages
+const APP_NAME: &str = "rudder-relayd";
+/// local0
+const SYSLOG_FACILITY: u8 = 16;
+
+/// A destination for parsed reports, used in addition to the main output.
+///
+/// Sinks are called from blocking sections and must be usable from
+/// several threads at once.
+pub trait ReportSink: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn send(&self, runlog: &RunLog) -> Result<(), Error>;
+}
+
+pub type Sinks = Vec<Box<dyn ReportSink>>;
+
+pub fn sinks(cfg: &[SinkConfig]) -> Result<Sinks, Error> {
+    cfg.iter()
+        .map(|sink| {
+            Ok(match sink {
+                SinkConfig::File(cfg) => {
+                    Box::new(FileSink::new(cfg.clone())) as Box<dyn ReportSink>
+                }
+                SinkConfig::Syslog(cfg) => Box::new(SyslogSink::new(cfg)?),
+            })
+        })
+        .collect()
+}
+
+/// Sink errors are only logged and counted, they never prevent the
+/// report from being sent to the main output.
+pub fn send_to_sinks(sinks: &[Box<dyn ReportSink>], runlog: &RunLog, stats: &mpsc::Sender<Event>) {
+    for sink in sinks {
+        let event = match sink.send(runlog) {
+            Ok(()) => {
+                debug!("sent {} reports to {}", runlog.reports.len(), sink.name());
+                Event::SinkSent(sink.name().to_string())
+            }
+            Err(e) => {
+                warn!("could not send reports to {}: {}", sink.name(), e);
+                Event::SinkFailed(sink.name().to_string())
+            }
+        };
+        tokio::spawn(
+            stats
+                .clone()
+                .send(event)
+                .map(|_| ())
+                .map_err(|e| error!("send error: {}", e)),
+        );
+    }
+}
+
+/// Writes newline-delimited JSON reports, and rotates the file when it reaches
+/// its maximal size (`file.1` being the most recent rotated file).
+pub struct FileSink {
+    name: String,
+    cfg: FileSinkConfig,
+    /// Currently open file and its size
+    current: Mutex<Option<(File, u64)>>,
+}
+
+impl FileSink {
+    pub fn new(cfg: FileSinkConfig) -> Self {
+        Self {
+            name: SinkConfig::File(cfg.clone()).name(),
+            cfg,
+            current: Mutex::new(None),
+        }
+    }
+
+    fn rotated(&self, index: u32) -> PathBuf {
+        let mut path = OsString::from(self.cfg.path.as_os_str());
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn open(&self) -> Result<(File, u64), Error> {
+        if let Some(parent) = self.cfg.path.parent() {
+            create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.cfg.path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    fn rotate(&self) -> Result<(), Error> {
+        debug!("rotating {:?}", self.cfg.path);
+        if self.cfg.max_files == 0 {
+            File::create(&self.cfg.path)?;
+            return Ok(());
+        }
+        for index in (1..self.cfg.max_files).rev() {
+            let rotated = self.rotated(index);
+            if rotated.exists() {
+                rename(rotated, self.rotated(index + 1))?;
+            }
+        }
+        rename(&self.cfg.path, self.rotated(1))?;
+        Ok(())
+    }
+}
+
+impl ReportSink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, runlog: &RunLog) -> Result<(), Error> {
+        let mut data = vec![];
+        for report in &runlog.reports {
+            serde_json::to_writer(&mut data, report)?;
+            data.push(b'\n');
+        }
+
+        let mut current = self.current.lock().expect("could not lock file sink");
+        if current.is_none() {
+            *current = Some(self.open()?);
+        }
+        let size = current.as_ref().map(|(_, size)| *size).unwrap_or(0);
+        if size > 0 && size + data.len() as u64 > self.cfg.max_size {
+            *current = None;
+            self.rotate()?;
+            *current = Some(self.open()?);
+        }
+
+        let (file, size) = current.as_mut().expect("file sink should be open");
+        if let Err(e) = file.write_all(&data) {
+            // Reopen the file on next call
+            *current = None;
+            return Err(e.into());
+        }
+        *size += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Sends one RFC 5424 message per report, with the report as JSON
+/// in the message part.
+pub struct SyslogSink {
+    name: String,
+    address: SocketAddr,
+    protocol: SyslogProtocol,
+    timeout: Duration,
+    udp: Option<UdpSocket>,
+    /// Connected lazily, and reconnected after errors
+    tcp: Mutex<Option<TcpStream>>,
+}
+
+impl SyslogSink {
+    pub fn new(cfg: &SyslogSinkConfig) -> Result<Self, Error> {
+        let address = cfg
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::InvalidSyslogAddress(cfg.address.clone()))?;
+        let udp = match cfg.protocol {
+            SyslogProtocol::Udp => Some(UdpSocket::bind(if address.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            })?),
+            SyslogProtocol::Tcp => None,
+        };
+        Ok(Self {
+            name: SinkConfig::Syslog(cfg.clone()).name(),
+            address,
+            protocol: cfg.protocol,
+            timeout: cfg.timeout,
+            udp,
+            tcp: Mutex::new(None),
+        })
+    }
+
+    fn send_tcp(&self, messages: &[String]) -> Result<(), Error> {
+        let mut tcp = self.tcp.lock().expect("could not lock syslog sink");
+        if tcp.is_none() {
+            // Called from blocking sections, an unresponsive server must not
+            // hold a thread of the pool forever
+            let stream = TcpStream::connect_timeout(&self.address, self.timeout)?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            *tcp = Some(stream);
+        }
+        let stream = tcp.as_mut().expect("syslog sink should be connected");
+        for message in messages {
+            // Octet counting framing (RFC 6587)
+            if let Err(e) = write!(stream, "{} {}", message.len(), message) {
+                *tcp = None;
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Severity from the report type, results are generally the most interesting
+fn severity(event_type: &str) -> u8 {
+    if event_type.contains("error") {
+        // error
+        3
+    } else if event_type.contains("repaired") || event_type.contains("noncompliant") {
+        // notice
+        5
+    } else if event_type.starts_with("log_") {
+        // debug
+        7
+    } else {
+        // informational
+        6
+    }
+}
+
+/// Message id is limited to 32 printable ASCII characters
+fn message_id(event_type: &str) -> String {
+    let id: String = event_type
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(32)
+        .collect();
+    if id.is_empty() {
+        "-".to_string()
+    } else {
+        id
+    }
+}
+
+fn syslog_message(report: &Report) -> Result<String, Error> {
+    Ok(format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        SYSLOG_FACILITY * 8 + severity(&report.event_type),
+        report.execution_datetime.to_rfc3339(),
+        report.node_id,
+        APP_NAME,
+        process::id(),
+        message_id(&report.event_type),
+        serde_json::to_string(report)?,
+    ))
+}
+
+impl ReportSink for SyslogSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&self, runlog: &RunLog) -> Result<(), Error> {
+        let messages = runlog
+            .reports
+            .iter()
+            .map(syslog_message)
+            .collect::<Result<Vec<String>, Error>>()?;
+
+        match self.protocol {
+            SyslogProtocol::Udp => {
+                let socket = self.udp.as_ref().expect("missing UDP socket");
+                for message in messages {
+                    socket.send_to(message.as_bytes(), self.address)?;
+                }
+                Ok(())
+            }
+            SyslogProtocol::Tcp => self.send_tcp(&messages),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::read_to_string, str};
+    use tempfile::tempdir;
+
+    fn runlog() -> RunLog {
+        RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_writes_and_rotates_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("reports.json");
+        let runlog = runlog();
+        let sink = FileSink::new(FileSinkConfig {
+            path: path.clone(),
+            max_size: 1,
+            max_files: 2,
+        });
+
+        for _ in 0..4 {
+            sink.send(&runlog).unwrap();
+        }
+
+        let content = read_to_string(&path).unwrap();
+        let reports = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect::<Vec<Report>>();
+        assert_eq!(reports, runlog.reports);
+        assert!(dir.path().join("reports.json.1").exists());
+        assert!(dir.path().join("reports.json.2").exists());
+        assert!(!dir.path().join("reports.json.3").exists());
+    }
+
+    #[test]
+    fn it_sends_syslog_messages() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let runlog = runlog();
+        let sink = SyslogSink::new(&SyslogSinkConfig {
+            address: server.local_addr().unwrap().to_string(),
+            protocol: SyslogProtocol::Udp,
+            timeout: Duration::from_secs(10),
+        })
+        .unwrap();
+
+        sink.send(&runlog).unwrap();
+
+        let mut buffer = [0; 65_536];
+        let size = server.recv(&mut buffer).unwrap();
+        let message = str::from_utf8(&buffer[..size]).unwrap();
+        let first = &runlog.reports[0];
+        assert!(message.starts_with(&format!(
+            "<{}>1 {} e745a140-40bc-4b86-b6dc-084488fc906b rudder-relayd ",
+            SYSLOG_FACILITY * 8 + severity(&first.event_type),
+            first.execution_datetime.to_rfc3339()
+        )));
+        let json = message.splitn(2, " - ").nth(1).unwrap();
+        assert_eq!(&serde_json::from_str::<Report>(json).unwrap(), first);
+    }
+
+    #[test]
+    fn it_computes_severity() {
+        assert_eq!(severity("result_error"), 3);
+        assert_eq!(severity("audit_noncompliant"), 5);
+        assert_eq!(severity("result_repaired"), 5);
+        assert_eq!(severity("log_info"), 7);
+        assert_eq!(severity("result_success"), 6);
+    }
+}
@@ -1,10 +1,46 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::{configuration::Secret, processing::inventory::InventoryType, Error, JobConfig};
+use crate::{
+    configuration::Secret, processing::inventory::InventoryType, telemetry, Error, JobConfig,
+};
 use futures::Future;
-use std::{path::PathBuf, sync::Arc};
-use tracing::{debug, span, Level};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::{debug, info, span, Level};
+
+/// Result of the last request to the upstream relay, used for readiness
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    error: RwLock<Option<String>>,
+}
+
+impl UpstreamHealth {
+    /// Error of the last request, if it failed
+    pub fn error(&self) -> Option<String> {
+        self.error
+            .read()
+            .expect("could not read upstream health")
+            .clone()
+    }
+
+    fn update(&self, result: &Result<(), Error>) {
+        let mut error = self.error.write().expect("could not write upstream health");
+        match result {
+            Ok(()) => {
+                if error.is_some() {
+                    info!("upstream server is reachable again");
+                }
+                *error = None;
+            }
+            // Only errors in the communication with upstream
+            Err(e @ Error::HttpClient(_)) => *error = Some(e.to_string()),
+            Err(_) => (),
+        }
+    }
+}
 
 pub fn send_report(
     job_config: Arc<JobConfig>,
@@ -47,27 +83,34 @@ fn forward_file(
     path: PathBuf,
     password: Secret,
 ) -> impl Future<Item = (), Error = Error> + '_ {
+    // The request is built outside of the current span
+    let trace_context = telemetry::current_context();
     tokio::fs::read(path.clone())
         .map_err(|e| e.into())
         .and_then(move |d| {
-            job_config
-                .client
-                .clone()
-                .put(&format!(
+            let job_config_health = job_config.clone();
+            telemetry::inject(
+                job_config.client.clone().put(&format!(
                     "{}/{}/{}",
                     job_config.cfg.output.upstream.url,
                     endpoint,
                     path.file_name().expect("not a file").to_string_lossy()
-                ))
-                .basic_auth(
-                    &job_config.cfg.output.upstream.user,
-                    Some(&password.value()),
-                )
-                .body(d)
-                .send()
-                // HTTP error -> Err()
-                .and_then(|r| r.error_for_status())
-                .map(|r| debug!("Server response: {:#?}", r))
-                .map_err(|e| e.into())
+                )),
+                trace_context,
+            )
+            .basic_auth(
+                &job_config.cfg.output.upstream.user,
+                Some(&password.value()),
+            )
+            .body(d)
+            .send()
+            // HTTP error -> Err()
+            .and_then(|r| r.error_for_status())
+            .map(|r| debug!("Server response: {:#?}", r))
+            .map_err(|e| e.into())
+            .then(move |res| {
+                job_config_health.upstream_health.update(&res);
+                res
+            })
         })
 }
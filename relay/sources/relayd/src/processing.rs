@@ -1,21 +1,232 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::{error::Error, stats::Event};
-use futures::{future::Future, sync::mpsc};
-use std::path::PathBuf;
+use crate::{
+    configuration::main::ArchiveConfig, error::Error, processing::replay::ReplayKind, stats::Event,
+    JobConfig,
+};
+use chrono::Utc;
+use futures::{
+    future::{poll_fn, Future},
+    sync::mpsc,
+    Sink, Stream,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 use tokio::{
     fs::{remove_file, rename},
     prelude::*,
 };
-use tracing::{debug, error};
+use tokio_threadpool::blocking;
+use tracing::{debug, error, warn};
 
+pub mod archive;
 pub mod inventory;
+pub mod replay;
 pub mod reporting;
+pub mod runs;
 
 pub type ReceivedFile = PathBuf;
 pub type RootDirectory = PathBuf;
 
+/// Size of the channel between file producers (watcher and catchup)
+/// and processing
+const QUEUE_CAPACITY: usize = 1_024;
+
+#[derive(Debug, Default)]
+pub struct QueueGauges {
+    /// Sent by producers but not yet picked by a worker
+    queued: AtomicUsize,
+    /// Being processed by a worker
+    in_flight: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct QueueStats {
+    pub queued: usize,
+    pub in_flight: usize,
+}
+
+impl QueueGauges {
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Gauges of all processing queues
+#[derive(Debug, Default)]
+pub struct Gauges {
+    pub reports: Arc<QueueGauges>,
+    pub inventories: Arc<QueueGauges>,
+    pub inventory_updates: Arc<QueueGauges>,
+}
+
+impl Gauges {
+    pub fn stats(&self) -> BTreeMap<String, QueueStats> {
+        vec![
+            ("reports".to_string(), self.reports.stats()),
+            ("inventories".to_string(), self.inventories.stats()),
+            (
+                "inventory_updates".to_string(),
+                self.inventory_updates.stats(),
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+}
+
+/// Sending side of a processing queue
+#[derive(Debug, Clone)]
+pub struct Queue {
+    tx: mpsc::Sender<ReceivedFile>,
+    gauges: Arc<QueueGauges>,
+}
+
+impl Queue {
+    pub fn new(gauges: Arc<QueueGauges>) -> (Self, mpsc::Receiver<ReceivedFile>) {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        (Self { tx, gauges }, rx)
+    }
+
+    /// Waits for room in the queue when it is full
+    pub fn send(&self, file: ReceivedFile) -> impl Future<Item = (), Error = ()> {
+        let _ = self.gauges.queued.fetch_add(1, Ordering::Relaxed);
+        let gauges = self.gauges.clone();
+        self.tx.clone().send(file).map(|_| ()).map_err(move |e| {
+            let _ = gauges.queued.fetch_sub(1, Ordering::Relaxed);
+            warn!("send error: {}", e)
+        })
+    }
+}
+
+/// Runs the processing of received files, with at most `concurrency` files processed at the
+/// same time. Each file is processed in its own task, spawned with one of the `concurrency`
+/// tokens, which is given back once the processing is done. When all tokens are taken, files
+/// stay in the queue, and producers wait once it is full.
+fn run_workers<F>(
+    rx: mpsc::Receiver<ReceivedFile>,
+    gauges: Arc<QueueGauges>,
+    concurrency: usize,
+    process: F,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Fn(ReceivedFile) -> Box<dyn Future<Item = (), Error = ()> + Send>,
+{
+    let (tokens_tx, tokens_rx) = mpsc::unbounded();
+    for _ in 0..concurrency.max(1) {
+        tokens_tx
+            .unbounded_send(())
+            .expect("tokens receiver is alive");
+    }
+
+    rx.zip(tokens_rx).for_each(move |(file, token)| {
+        let _ = gauges.queued.fetch_sub(1, Ordering::Relaxed);
+        let _ = gauges.in_flight.fetch_add(1, Ordering::Relaxed);
+        let gauges = gauges.clone();
+        let tokens_tx = tokens_tx.clone();
+        // Errors are handled by the processing, they must not stop the workers
+        tokio::spawn(process(file).then(move |_| {
+            let _ = gauges.in_flight.fetch_sub(1, Ordering::Relaxed);
+            // Fails when the queue was closed in the meantime
+            let _ = tokens_tx.unbounded_send(token);
+            Ok(())
+        }));
+        Ok(())
+    })
+}
+
+/// Files currently being processed.
+///
+/// Files can be received from both the watcher and the catchup job, the registry
+/// ensures each file is only processed by one worker at a time.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    files: Arc<Mutex<HashSet<ReceivedFile>>>,
+}
+
+impl InFlight {
+    /// Registers the file, returns None if it is already being processed.
+    /// The file is unregistered when the guard is dropped.
+    pub fn start(&self, file: &ReceivedFile) -> Option<InFlightGuard> {
+        if self
+            .files
+            .lock()
+            .expect("could not lock in-flight files")
+            .insert(file.clone())
+        {
+            Some(InFlightGuard {
+                files: self.files.clone(),
+                file: file.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files
+            .lock()
+            .expect("could not lock in-flight files")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlightGuard {
+    files: Arc<Mutex<HashSet<ReceivedFile>>>,
+    file: ReceivedFile,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let _ = self
+            .files
+            .lock()
+            .expect("could not lock in-flight files")
+            .remove(&self.file);
+    }
+}
+
+/// Registers the file as being processed, or returns None if it is already being
+/// processed or was already processed (in which case the event is counted)
+fn start_processing(
+    file: &ReceivedFile,
+    job_config: &JobConfig,
+    event: Event,
+    stats: &mpsc::Sender<Event>,
+) -> Option<InFlightGuard> {
+    let guard = job_config.in_flight.start(file);
+    // Checked after registration, as the file may have been processed in the meantime
+    if guard.is_some() && file.exists() {
+        return guard;
+    }
+    debug!("skipping {:#?} as it is already being processed", file);
+    // FIXME: no need for a spawn
+    tokio::spawn(
+        stats
+            .clone()
+            .send(event)
+            .map_err(|e| error!("send error: {}", e))
+            .map(|_| ()),
+    );
+    None
+}
+
 #[derive(Debug, Copy, Clone)]
 enum OutputError {
     Transient,
@@ -25,16 +236,21 @@ enum OutputError {
 impl From<Error> for OutputError {
     fn from(err: Error) -> Self {
         match err {
-            Error::Database(_) | Error::DatabaseConnection(_) | Error::HttpClient(_) => {
-                OutputError::Transient
-            }
+            Error::Database(_)
+            | Error::DatabaseConnection(_)
+            | Error::Pool(_)
+            | Error::HttpClient(_) => OutputError::Transient,
             _ => OutputError::Permanent,
         }
     }
 }
 
+/// Removes the processed file, or moves it into the archive when enabled
 fn success(
     file: ReceivedFile,
+    directory: RootDirectory,
+    kind: ReplayKind,
+    archive_cfg: Option<ArchiveConfig>,
     event: Event,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
@@ -42,14 +258,59 @@ fn success(
         stats
             .send(event)
             .map_err(|e| error!("send error: {}", e))
-            .then(|_| {
-                remove_file(file.clone())
-                    .map(move |_| debug!("deleted: {:#?}", file))
-                    .map_err(|e| error!("error: {}", e))
+            .then(move |_| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                match archive_cfg {
+                    Some(cfg) => {
+                        let archived_file = file.clone();
+                        Box::new(
+                            poll_fn(move || {
+                                blocking(|| {
+                                    archive::archive(&cfg, kind, &archived_file, Utc::now())
+                                })
+                                .map_err(|_| panic!("the thread pool shut down"))
+                            })
+                            .and_then(
+                                move |res| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                                    match res {
+                                        Ok(_) => Box::new(futures::future::ok(())),
+                                        // Keeping the file would make it processed again,
+                                        // the failed directory keeps it for audit
+                                        Err(e) => {
+                                            error!(
+                                                "could not archive {:#?}, moving it to the failed directory: {}",
+                                                file, e
+                                            );
+                                            Box::new(move_to_failed(file, directory))
+                                        }
+                                    }
+                                },
+                            ),
+                        )
+                    }
+                    None => Box::new(remove(file)),
+                }
             }),
     )
 }
 
+fn remove(file: ReceivedFile) -> impl Future<Item = (), Error = ()> {
+    remove_file(file.clone())
+        .map(move |_| debug!("deleted: {:#?}", file))
+        .map_err(|e| error!("error: {}", e))
+}
+
+fn move_to_failed(
+    file: ReceivedFile,
+    directory: RootDirectory,
+) -> impl Future<Item = (), Error = ()> {
+    let destination = directory
+        .join("failed")
+        .join(file.file_name().expect("not a file"));
+    rename(file.clone(), destination.clone())
+        .map(move |_| debug!("moved: {:#?} to {:#?}", file, destination))
+        .map_err(|e| error!("error: {}", e))
+}
+
 fn failure(
     file: ReceivedFile,
     directory: RootDirectory,
@@ -60,25 +321,95 @@ fn failure(
         stats
             .send(event)
             .map_err(|e| error!("send error: {}", e))
-            .then(move |_| {
-                rename(
-                    file.clone(),
-                    directory
-                        .join("failed")
-                        .join(file.file_name().expect("not a file")),
-                )
-                .map(move |_| {
-                    debug!(
-                        "moved: {:#?} to {:#?}",
-                        file,
-                        directory
-                            .join("failed")
-                            .join(file.file_name().expect("not a file"))
-                    )
-                })
-                .map_err(|e| error!("error: {}", e))
-            })
+            .then(move |_| move_to_failed(file, directory))
             // Hack for easier chaining
             .and_then(|_| Box::new(futures::future::err::<(), ()>(()))),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_registers_in_flight_files() {
+        let in_flight = InFlight::default();
+        let file = PathBuf::from("2019-01-24T15:55:01+00:00@root.log");
+
+        let guard = in_flight.start(&file);
+        assert!(guard.is_some());
+        assert!(in_flight.start(&file).is_none());
+        assert_eq!(in_flight.len(), 1);
+
+        drop(guard);
+        assert!(in_flight.is_empty());
+        assert!(in_flight.start(&file).is_some());
+    }
+
+    #[test]
+    fn it_limits_concurrency() {
+        use futures::future;
+        use std::{sync::Condvar, thread::sleep, time::Duration};
+
+        #[derive(Default)]
+        struct Running {
+            current: usize,
+            max: usize,
+            started: usize,
+            /// First files that saw the other one running
+            overlapped: usize,
+        }
+
+        let gauges = Arc::new(QueueGauges::default());
+        let (queue, rx) = Queue::new(gauges.clone());
+        let running = Arc::new((Mutex::new(Running::default()), Condvar::new()));
+
+        let running_clone = running.clone();
+        let workers = run_workers(rx, gauges.clone(), 2, move |_file| {
+            let running = running_clone.clone();
+            // Same as database inserts
+            Box::new(poll_fn(move || -> futures::Poll<(), ()> {
+                blocking(|| {
+                    {
+                        let (lock, started) = &*running;
+                        let mut state = lock.lock().unwrap();
+                        state.current += 1;
+                        state.max = state.max.max(state.current);
+                        state.started += 1;
+                        started.notify_all();
+                        // The first two files wait for each other, which only
+                        // happens when they are processed at the same time
+                        if state.started <= 2 {
+                            let (mut state, timeout) = started
+                                .wait_timeout_while(state, Duration::from_secs(5), |s| {
+                                    s.started < 2
+                                })
+                                .unwrap();
+                            if !timeout.timed_out() {
+                                state.overlapped += 1;
+                            }
+                        }
+                    }
+                    sleep(Duration::from_millis(50));
+                    running.0.lock().unwrap().current -= 1;
+                })
+                .map_err(|_| panic!("the thread pool shut down"))
+            }))
+        });
+
+        let producer = future::join_all(
+            (0..10)
+                .map(|i| queue.send(PathBuf::from(format!("{}.log", i))))
+                .collect::<Vec<_>>(),
+        )
+        .map(move |_| drop(queue));
+
+        tokio::run(producer.join(workers).map(|_| ()));
+
+        // Blocking work overlaps, but never goes over the limit
+        let state = running.0.lock().unwrap();
+        assert_eq!(state.overlapped, 2);
+        assert!(state.max <= 2);
+        assert_eq!(gauges.stats(), QueueStats::default());
+    }
+}
@@ -0,0 +1,357 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Archive of successfully processed files, for audit. Files are partitioned
+//! by day (in UTC):
+//!
+//! * `<directory>/reports/<run date>/<node id>/<run log>`
+//! * `<directory>/inventories/<archival date>/<archival time>-<inventory>`
+//!
+//! Uncompressed files are gzipped, files received compressed are kept as is.
+
+use crate::{
+    configuration::main::ArchiveConfig,
+    data::RunInfo,
+    error::Error,
+    processing::replay::{ReplayFilter, ReplayKind},
+};
+use chrono::prelude::*;
+use flate2::{write::GzEncoder, Compression};
+use futures::{future::poll_fn, Future, Stream};
+use serde::Serialize;
+use std::{
+    convert::TryFrom,
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, error, info, warn};
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+fn kind_directory(cfg: &ArchiveConfig, kind: ReplayKind) -> PathBuf {
+    cfg.directory.join(match kind {
+        ReplayKind::Reports => "reports",
+        ReplayKind::Inventories => "inventories",
+    })
+}
+
+fn day(path: &Path) -> Option<NaiveDate> {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .and_then(|day| NaiveDate::parse_from_str(day, DATE_FORMAT).ok())
+}
+
+fn is_compressed(file: &Path) -> bool {
+    matches!(
+        file.extension().and_then(OsStr::to_str),
+        Some("gz") | Some("zip")
+    )
+}
+
+/// Name of an archived inventory. Several inventories of a node can be received
+/// the same day, they are prefixed with their archival time, and a counter when
+/// received in the same second.
+fn inventory_name(directory: &Path, name: &str, now: DateTime<Utc>) -> String {
+    let time = now.format("%H%M%S");
+    (0..)
+        .map(|i| match i {
+            0 => format!("{}-{}", time, name),
+            _ => format!("{}-{}-{}", time, i, name),
+        })
+        .find(|name| {
+            !directory.join(name).exists() && !directory.join(format!("{}.gz", name)).exists()
+        })
+        .expect("could not find an archive file name")
+}
+
+/// Moves a processed file into the archive, returns its new path
+pub fn archive(
+    cfg: &ArchiveConfig,
+    kind: ReplayKind,
+    file: &Path,
+    now: DateTime<Utc>,
+) -> Result<PathBuf, Error> {
+    let name = file
+        .file_name()
+        .and_then(OsStr::to_str)
+        .ok_or(Error::InvalidFileName)?;
+    let (directory, name) = match kind {
+        // Run logs are unique by node and run date
+        ReplayKind::Reports => {
+            let info = RunInfo::try_from(file)?;
+            let directory = kind_directory(cfg, kind)
+                .join(
+                    info.timestamp
+                        .with_timezone(&Utc)
+                        .format(DATE_FORMAT)
+                        .to_string(),
+                )
+                .join(info.node_id);
+            (directory, name.to_string())
+        }
+        ReplayKind::Inventories => {
+            let directory = kind_directory(cfg, kind).join(now.format(DATE_FORMAT).to_string());
+            let name = inventory_name(&directory, name, now);
+            (directory, name)
+        }
+    };
+    fs::create_dir_all(&directory)?;
+
+    let destination = if is_compressed(file) {
+        let destination = directory.join(name);
+        // Fails when the archive is on another file system
+        if fs::rename(file, &destination).is_err() {
+            let _ = fs::copy(file, &destination)?;
+            fs::remove_file(file)?;
+        }
+        destination
+    } else {
+        let destination = directory.join(format!("{}.gz", name));
+        let mut encoder = GzEncoder::new(File::create(&destination)?, Compression::default());
+        let _ = io::copy(&mut File::open(file)?, &mut encoder)?;
+        let _ = encoder.finish()?;
+        fs::remove_file(file)?;
+        destination
+    };
+    debug!("archived: {:#?} to {:#?}", file, destination);
+    Ok(destination)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchivedFile {
+    pub file: String,
+    /// Day of the partition
+    pub date: NaiveDate,
+    pub size: u64,
+}
+
+impl Display for ArchivedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file)
+    }
+}
+
+/// Files in a day partition, in the node directories for reports
+fn day_files(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(day_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Archived files matching the filter, oldest days first. Reports are filtered
+/// using their run info, inventories using their file name and archival date.
+pub fn list(
+    cfg: &ArchiveConfig,
+    kind: ReplayKind,
+    filter: &ReplayFilter,
+) -> Result<Vec<ArchivedFile>, Error> {
+    let root = kind_directory(cfg, kind);
+    let mut archived = vec![];
+    if !root.exists() {
+        return Ok(archived);
+    }
+
+    for entry in fs::read_dir(&root)? {
+        let path = entry?.path();
+        let date = match day(&path) {
+            Some(date) => date,
+            None => continue,
+        };
+        // Skip whole days out of the filter range
+        if filter
+            .after
+            .map(|after| date < after.with_timezone(&Utc).date().naive_utc())
+            .unwrap_or(false)
+            || filter
+                .before
+                .map(|before| date > before.with_timezone(&Utc).date().naive_utc())
+                .unwrap_or(false)
+        {
+            continue;
+        }
+
+        for file in day_files(&path)? {
+            let matches = match kind {
+                ReplayKind::Reports => filter.matches_report(&file),
+                ReplayKind::Inventories => filter.matches_inventory(&file),
+            };
+            match matches {
+                Ok(true) => archived.push(ArchivedFile {
+                    size: file.metadata()?.len(),
+                    file: file.display().to_string(),
+                    date,
+                }),
+                Ok(false) => (),
+                Err(e) => warn!("skipping archived file {:#?}: {}", file, e),
+            }
+        }
+    }
+    archived.sort_by(|a, b| (a.date, &a.file).cmp(&(b.date, &b.file)));
+    Ok(archived)
+}
+
+/// Copies archived files as is into the output directory, returns the copies
+pub fn extract(files: &[ArchivedFile], output: &Path) -> Result<Vec<PathBuf>, Error> {
+    fs::create_dir_all(output)?;
+    let mut extracted = vec![];
+    for archived in files {
+        let file = Path::new(&archived.file);
+        let destination = output.join(file.file_name().ok_or(Error::InvalidFileName)?);
+        let _ = fs::copy(file, &destination)?;
+        extracted.push(destination);
+    }
+    Ok(extracted)
+}
+
+/// Removes the days older than the retention, returns the number of removed days
+pub fn clean(cfg: &ArchiveConfig, now: DateTime<Utc>) -> Result<usize, Error> {
+    let limit = (now
+        - chrono::Duration::from_std(cfg.retention).expect("Unexpectedly large duration"))
+    .date()
+    .naive_utc();
+    let mut removed = 0;
+    for kind in &[ReplayKind::Reports, ReplayKind::Inventories] {
+        let root = kind_directory(cfg, *kind);
+        if !root.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(&root)? {
+            let path = entry?.path();
+            match day(&path) {
+                Some(date) if date < limit => {
+                    debug!("removing archived {:?} from {}", kind, date);
+                    fs::remove_dir_all(&path)?;
+                    removed += 1;
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Periodically applies the archive retention
+pub fn cleanup(cfg: ArchiveConfig) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), cfg.frequency)
+        .map_err(|e| warn!("interval error: {}", e))
+        .for_each(move |_instant| {
+            let cfg = cfg.clone();
+            poll_fn(move || {
+                blocking(|| clean(&cfg, Utc::now()))
+                    .map_err(|_| panic!("the thread pool shut down"))
+            })
+            .map(|res| match res {
+                Ok(0) => (),
+                Ok(removed) => info!("removed {} days of archived files", removed),
+                Err(e) => error!("archive cleanup error: {}", e),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::read_compressed_file;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_archives_files() {
+        let dir = tempdir().unwrap();
+        let cfg = ArchiveConfig {
+            directory: dir.path().join("archive"),
+            frequency: Duration::from_secs(3600),
+            retention: Duration::from_secs(3600 * 24 * 30),
+        };
+        let now = DateTime::parse_from_rfc3339("2018-09-10T12:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let runlog = "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let source = "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.signed";
+        let file = dir.path().join(runlog);
+        let _ = fs::copy(source, &file).unwrap();
+        let inventory = dir.path().join("node1-e745a140.ocs");
+        fs::write(&inventory, "inventory").unwrap();
+
+        let archived = archive(&cfg, ReplayKind::Reports, &file, now).unwrap();
+        assert!(!file.exists());
+        assert_eq!(
+            archived,
+            cfg.directory
+                .join("reports/2018-08-24/e745a140-40bc-4b86-b6dc-084488fc906b")
+                .join(format!("{}.gz", runlog))
+        );
+        // Exact received content
+        assert_eq!(
+            read_compressed_file(&archived).unwrap(),
+            fs::read(source).unwrap()
+        );
+        let archived_inventory = archive(&cfg, ReplayKind::Inventories, &inventory, now).unwrap();
+        assert_eq!(
+            archived_inventory,
+            cfg.directory
+                .join("inventories/2018-09-10/120000-node1-e745a140.ocs.gz")
+        );
+        // A second inventory from the same node does not replace the first one
+        fs::write(&inventory, "inventory update").unwrap();
+        let archived_update = archive(&cfg, ReplayKind::Inventories, &inventory, now).unwrap();
+        assert_eq!(
+            archived_update,
+            cfg.directory
+                .join("inventories/2018-09-10/120000-1-node1-e745a140.ocs.gz")
+        );
+        assert_eq!(
+            read_compressed_file(&archived_inventory).unwrap(),
+            b"inventory"
+        );
+
+        let node = ReplayFilter {
+            node_id: Some("e745a140-40bc-4b86-b6dc-084488fc906b".to_string()),
+            ..Default::default()
+        };
+        let files = list(&cfg, ReplayKind::Reports, &node).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].file, archived.display().to_string());
+        let later = ReplayFilter {
+            after: Some(DateTime::parse_from_rfc3339("2018-08-25T00:00:00+00:00").unwrap()),
+            ..Default::default()
+        };
+        assert!(list(&cfg, ReplayKind::Reports, &later).unwrap().is_empty());
+        assert_eq!(
+            list(&cfg, ReplayKind::Inventories, &ReplayFilter::default())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let extracted = extract(&files, &dir.path().join("extracted")).unwrap();
+        assert_eq!(
+            fs::read(&extracted[0]).unwrap(),
+            fs::read(&archived).unwrap()
+        );
+
+        // Only the report day is older than the retention
+        assert_eq!(clean(&cfg, now).unwrap(), 1);
+        assert!(list(&cfg, ReplayKind::Reports, &node).unwrap().is_empty());
+        assert_eq!(
+            list(&cfg, ReplayKind::Inventories, &ReplayFilter::default())
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -3,13 +3,20 @@
 
 use crate::{
     configuration::main::InventoryOutputSelect,
-    input::watch::*,
+    input::{pressure, watch::*},
     output::upstream::send_inventory,
-    processing::{failure, success, OutputError, ReceivedFile},
+    processing::{
+        failure, replay::ReplayKind, run_workers, start_processing, success, OutputError, Queue,
+        ReceivedFile,
+    },
     stats::Event,
     JobConfig,
 };
-use futures::{future::Future, lazy, sync::mpsc, Stream};
+use futures::{
+    future::{self, Future},
+    lazy,
+    sync::mpsc,
+};
 use md5::{Digest, Md5};
 use std::{os::unix::ffi::OsStrExt, sync::Arc};
 use tokio::prelude::*;
@@ -27,7 +34,7 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
     let span = span!(Level::TRACE, "inventory");
     let _enter = span.enter();
 
-    let (sender, receiver) = mpsc::channel(1_024);
+    let (incoming_queue, receiver) = Queue::new(job_config.gauges.inventories.clone());
 
     let incoming_path = job_config
         .cfg
@@ -44,8 +51,17 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
     tokio::spawn(cleanup(
         incoming_path.clone(),
         job_config.cfg.processing.inventory.cleanup,
+        || false,
     ));
-    watch(&incoming_path, &job_config, &sender);
+    if job_config.cfg.processing.inventory.disk_usage.is_enabled() {
+        tokio::spawn(pressure::monitor(
+            incoming_path.clone(),
+            job_config.cfg.processing.inventory.disk_usage,
+            incoming_queue.clone(),
+            job_config.clone(),
+        ));
+    }
+    watch(&incoming_path, &incoming_queue, job_config);
 
     let updates_path = job_config
         .cfg
@@ -53,7 +69,7 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
         .inventory
         .directory
         .join("accepted-nodes-updates");
-    let (sender, receiver) = mpsc::channel(1_024);
+    let (updates_queue, receiver) = Queue::new(job_config.gauges.inventory_updates.clone());
     tokio::spawn(serve(
         job_config.clone(),
         receiver,
@@ -63,8 +79,28 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
     tokio::spawn(cleanup(
         updates_path.clone(),
         job_config.cfg.processing.inventory.cleanup,
+        || false,
+    ));
+    if job_config.cfg.processing.inventory.disk_usage.is_enabled() {
+        tokio::spawn(pressure::monitor(
+            updates_path.clone(),
+            job_config.cfg.processing.inventory.disk_usage,
+            updates_queue.clone(),
+            job_config.clone(),
+        ));
+    }
+    watch(&updates_path, &updates_queue, job_config);
+
+    // Inventories from already accepted nodes first
+    let job_config_catchup = job_config.clone();
+    tokio::spawn(catchup(
+        vec![
+            (updates_path, updates_queue),
+            (incoming_path, incoming_queue),
+        ],
+        job_config.cfg.processing.inventory.catchup,
+        move |path| job_config_catchup.pressure.holds_intake(path),
     ));
-    watch(&updates_path, &job_config, &sender);
 }
 
 fn serve(
@@ -73,7 +109,12 @@ fn serve(
     inventory_type: InventoryType,
     stats: mpsc::Sender<Event>,
 ) -> impl Future<Item = (), Error = ()> {
-    rx.for_each(move |file| {
+    let gauges = match inventory_type {
+        InventoryType::New => job_config.gauges.inventories.clone(),
+        InventoryType::Update => job_config.gauges.inventory_updates.clone(),
+    };
+    let concurrency = job_config.cfg.processing.inventory.concurrency;
+    run_workers(rx, gauges, concurrency, move |file| {
         // allows skipping temporary .dav files
         if !file
             .extension()
@@ -84,9 +125,15 @@ fn serve(
                 "skipping {:#?} as it does not have a known inventory extension",
                 file
             );
-            return Ok(());
+            return Box::new(future::ok(()));
         }
 
+        let guard = match start_processing(&file, &job_config, Event::InventoryDeduplicated, &stats)
+        {
+            Some(guard) => guard,
+            None => return Box::new(future::ok(())),
+        };
+
         let queue_id = format!(
             "{:X}",
             Md5::digest(
@@ -125,8 +172,11 @@ fn serve(
             InventoryOutputSelect::Disabled => unreachable!("Inventory server should be disabled"),
         };
 
-        tokio::spawn(lazy(|| treat_file));
-        Ok(())
+        // Keep the file registered until the end of its processing
+        Box::new(treat_file.then(move |res| {
+            drop(guard);
+            res
+        }))
     })
 }
 
@@ -139,6 +189,8 @@ fn output_inventory_upstream(
     let job_config_clone = job_config.clone();
     let path_clone2 = path.clone();
     let stats_clone = stats.clone();
+    let archive_cfg = job_config.cfg.processing.archive.clone();
+    let directory = job_config.cfg.processing.inventory.directory.clone();
     Box::new(
         send_inventory(job_config, path.clone(), inventory_type)
             .map_err(|e| {
@@ -163,6 +215,15 @@ fn output_inventory_upstream(
                     Box::new(futures::future::err::<(), ()>(()))
                 }
             })
-            .and_then(move |_| success(path.clone(), Event::InventorySent, stats_clone)),
+            .and_then(move |_| {
+                success(
+                    path.clone(),
+                    directory,
+                    ReplayKind::Inventories,
+                    archive_cfg,
+                    Event::InventorySent,
+                    stats_clone,
+                )
+            }),
     )
 }
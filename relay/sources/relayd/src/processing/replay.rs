@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::ProcessingConfig,
+    data::{node::NodeId, RunInfo},
+    error::Error,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    fmt::{self, Display},
+    fs::{read_dir, rename},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayKind {
+    Reports,
+    Inventories,
+}
+
+impl FromStr for ReplayKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reports" => Ok(ReplayKind::Reports),
+            "inventories" => Ok(ReplayKind::Inventories),
+            _ => Err(Error::InvalidFileKind(s.to_string())),
+        }
+    }
+}
+
+impl ReplayKind {
+    /// Directory the files are replayed into
+    pub fn incoming(self, cfg: &ProcessingConfig) -> PathBuf {
+        match self {
+            ReplayKind::Reports => cfg.reporting.directory.join("incoming"),
+            ReplayKind::Inventories => cfg.inventory.directory.join("incoming"),
+        }
+    }
+}
+
+/// Only files matching all given criteria are replayed.
+///
+/// Reports are filtered using their run info, inventories using their file
+/// name and modification date.
+#[derive(StructOpt, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayFilter {
+    /// Only replay files from this node
+    #[structopt(long = "node")]
+    pub node_id: Option<NodeId>,
+    /// Only replay files more recent than this date (RFC 3339)
+    #[structopt(long)]
+    pub after: Option<DateTime<FixedOffset>>,
+    /// Only replay files older than this date (RFC 3339)
+    #[structopt(long)]
+    pub before: Option<DateTime<FixedOffset>>,
+}
+
+impl ReplayFilter {
+    fn is_empty(&self) -> bool {
+        self.node_id.is_none() && self.after.is_none() && self.before.is_none()
+    }
+
+    fn matches_date(&self, date: DateTime<FixedOffset>) -> bool {
+        self.after.map(|after| date >= after).unwrap_or(true)
+            && self.before.map(|before| date <= before).unwrap_or(true)
+    }
+
+    pub(crate) fn matches_report(&self, file: &Path) -> Result<bool, Error> {
+        if self.is_empty() {
+            return Ok(true);
+        }
+        let info = RunInfo::try_from(file)?;
+        Ok(self
+            .node_id
+            .as_ref()
+            .map(|node_id| *node_id == info.node_id)
+            .unwrap_or(true)
+            && self.matches_date(info.timestamp))
+    }
+
+    pub(crate) fn matches_inventory(&self, file: &Path) -> Result<bool, Error> {
+        if self.is_empty() {
+            return Ok(true);
+        }
+        let name = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(Error::InvalidFileName)?;
+        let modified: DateTime<Utc> = file.metadata()?.modified()?.into();
+        Ok(self
+            .node_id
+            .as_ref()
+            .map(|node_id| name.contains(node_id.as_str()))
+            .unwrap_or(true)
+            && self.matches_date(modified.into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayStatus {
+    /// Moved back to the incoming directory
+    Requeued,
+    /// Not matching the filter
+    Skipped,
+    Error,
+}
+
+impl Display for ReplayStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ReplayStatus::Requeued => "requeued",
+                ReplayStatus::Skipped => "skipped",
+                ReplayStatus::Error => "error",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReplayedFile {
+    pub file: String,
+    pub status: ReplayStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+}
+
+impl Display for ReplayedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.file)?;
+        if let Some(ref details) = self.details {
+            write!(f, " ({})", details)?;
+        }
+        Ok(())
+    }
+}
+
+/// Moves failed files back into the incoming directory, where they will
+/// be picked up by the watchers like newly received files.
+///
+/// Failed inventories are always replayed as new inventories, as the
+/// original directory is not known anymore.
+pub fn replay(
+    cfg: &ProcessingConfig,
+    kind: ReplayKind,
+    filter: &ReplayFilter,
+) -> Result<Vec<ReplayedFile>, Error> {
+    replay_matching(cfg, kind, |path| match kind {
+        ReplayKind::Reports => filter.matches_report(path),
+        ReplayKind::Inventories => filter.matches_inventory(path),
+    })
+}
+
+/// Replays failed reports from the given nodes, used when they
+/// were refused because the nodes were not known yet
+pub fn replay_reports_from(
+    cfg: &ProcessingConfig,
+    nodes: &BTreeSet<NodeId>,
+) -> Result<Vec<ReplayedFile>, Error> {
+    replay_matching(cfg, ReplayKind::Reports, |path| {
+        Ok(nodes.contains(&RunInfo::try_from(path)?.node_id))
+    })
+}
+
+fn replay_matching<F>(
+    cfg: &ProcessingConfig,
+    kind: ReplayKind,
+    matches: F,
+) -> Result<Vec<ReplayedFile>, Error>
+where
+    F: Fn(&Path) -> Result<bool, Error>,
+{
+    let directory = match kind {
+        ReplayKind::Reports => &cfg.reporting.directory,
+        ReplayKind::Inventories => &cfg.inventory.directory,
+    };
+    let failed = directory.join("failed");
+    let incoming = kind.incoming(cfg);
+    info!("Replaying {:?} failed files from {:?}", kind, failed);
+
+    let mut replayed = vec![];
+    for entry in read_dir(&failed)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let file = path.display().to_string();
+
+        let result = match matches(&path) {
+            Ok(true) => {
+                let destination = incoming.join(path.file_name().expect("not a file"));
+                if destination.exists() {
+                    ReplayedFile {
+                        file,
+                        status: ReplayStatus::Error,
+                        details: Some("already in the incoming directory".to_string()),
+                    }
+                } else {
+                    match rename(&path, &destination) {
+                        Ok(()) => {
+                            debug!("moved: {:#?} to {:#?}", path, destination);
+                            ReplayedFile {
+                                file,
+                                status: ReplayStatus::Requeued,
+                                details: None,
+                            }
+                        }
+                        Err(e) => ReplayedFile {
+                            file,
+                            status: ReplayStatus::Error,
+                            details: Some(e.to_string()),
+                        },
+                    }
+                }
+            }
+            Ok(false) => ReplayedFile {
+                file,
+                status: ReplayStatus::Skipped,
+                details: None,
+            },
+            Err(e) => ReplayedFile {
+                file,
+                status: ReplayStatus::Skipped,
+                details: Some(e.to_string()),
+            },
+        };
+        if result.status == ReplayStatus::Error {
+            warn!("{}", result);
+        }
+        replayed.push(result);
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_replays_failed_reports() {
+        let dir = tempdir().unwrap();
+        let mut cfg = ProcessingConfig::default();
+        cfg.reporting.directory = dir.path().to_path_buf();
+        create_dir_all(dir.path().join("failed")).unwrap();
+        create_dir_all(dir.path().join("incoming")).unwrap();
+
+        let node1 = "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let node1_old = "2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let node2 = "2018-08-24T15:55:01+00:00@37817c4d-fbf7-4850-a985-50021f4e8f41.log";
+        for file in &[node1, node1_old, node2, "broken.log"] {
+            write(dir.path().join("failed").join(file), "").unwrap();
+        }
+
+        let filter = ReplayFilter {
+            node_id: Some("e745a140-40bc-4b86-b6dc-084488fc906b".to_string()),
+            after: Some(DateTime::parse_from_rfc3339("2018-01-01T00:00:00+00:00").unwrap()),
+            before: None,
+        };
+        let mut replayed = replay(&cfg, ReplayKind::Reports, &filter).unwrap();
+        replayed.sort_by(|a, b| a.file.cmp(&b.file));
+
+        assert_eq!(
+            replayed.iter().map(|f| f.status).collect::<Vec<_>>(),
+            vec![
+                ReplayStatus::Skipped,
+                ReplayStatus::Skipped,
+                ReplayStatus::Requeued,
+                ReplayStatus::Skipped
+            ]
+        );
+        assert!(dir.path().join("incoming").join(node1).exists());
+        assert!(!dir.path().join("failed").join(node1).exists());
+        assert!(dir.path().join("failed").join(node1_old).exists());
+
+        // Without filter, everything goes back
+        let replayed = replay(&cfg, ReplayKind::Reports, &ReplayFilter::default()).unwrap();
+        assert_eq!(replayed.len(), 3);
+        assert!(replayed.iter().all(|f| f.status == ReplayStatus::Requeued));
+    }
+
+    #[test]
+    fn it_replays_reports_from_nodes() {
+        let dir = tempdir().unwrap();
+        let mut cfg = ProcessingConfig::default();
+        cfg.reporting.directory = dir.path().to_path_buf();
+        create_dir_all(dir.path().join("failed")).unwrap();
+        create_dir_all(dir.path().join("incoming")).unwrap();
+
+        let node1 = "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let node2 = "2018-08-24T15:55:01+00:00@37817c4d-fbf7-4850-a985-50021f4e8f41.log";
+        for file in &[node1, node2] {
+            write(dir.path().join("failed").join(file), "").unwrap();
+        }
+
+        let nodes = vec!["37817c4d-fbf7-4850-a985-50021f4e8f41".to_string()]
+            .into_iter()
+            .collect();
+        let replayed = replay_reports_from(&cfg, &nodes).unwrap();
+        assert_eq!(
+            replayed
+                .iter()
+                .filter(|f| f.status == ReplayStatus::Requeued)
+                .count(),
+            1
+        );
+        assert!(dir.path().join("incoming").join(node2).exists());
+        assert!(dir.path().join("failed").join(node1).exists());
+    }
+}
@@ -2,27 +2,35 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
-    configuration::main::ReportingOutputSelect,
+    configuration::main::{ReportingOutputSelect, WatchedDirectory},
     data::{RunInfo, RunLog},
     error::Error,
-    input::{read_compressed_file, signature, watch::*},
+    input::{pressure, read_compressed_file, signature, watch::*},
     output::{
-        database::{insert_runlog, InsertionBehavior},
+        database::{insert_runlog, ping, InsertionBehavior},
+        sink::send_to_sinks,
         upstream::send_report,
     },
-    processing::{failure, success, OutputError, ReceivedFile},
+    processing::{
+        failure, replay::ReplayKind, run_workers, runs::received_at, start_processing, success,
+        OutputError, Queue, ReceivedFile,
+    },
     stats::Event,
     JobConfig,
 };
 use futures::{
-    future::{poll_fn, Future},
-    lazy,
+    future::{self, poll_fn, Future},
+    lazy, stream,
     sync::mpsc,
-    Stream,
 };
 use md5::{Digest, Md5};
-use std::{convert::TryFrom, os::unix::ffi::OsStrExt, sync::Arc};
-use tokio::prelude::*;
+use std::{
+    convert::TryFrom,
+    os::unix::ffi::OsStrExt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{prelude::*, timer::Interval};
 use tokio_threadpool::blocking;
 use tracing::{debug, error, info, span, warn, Level};
 
@@ -39,13 +47,115 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
         .directory
         .join("incoming");
 
-    let (sender, receiver) = mpsc::channel(1_024);
+    let (queue, receiver) = Queue::new(job_config.gauges.reports.clone());
     tokio::spawn(serve(job_config.clone(), receiver, stats.clone()));
+    let job_config_cleanup = job_config.clone();
     tokio::spawn(cleanup(
         path.clone(),
         job_config.cfg.processing.reporting.cleanup,
+        move || job_config_cleanup.database_health.holds_files(),
+    ));
+    if job_config.cfg.processing.reporting.disk_usage.is_enabled() {
+        tokio::spawn(pressure::monitor(
+            path.clone(),
+            job_config.cfg.processing.reporting.disk_usage,
+            queue.clone(),
+            job_config.clone(),
+        ));
+    }
+    if job_config.cfg.processing.reporting.output == ReportingOutputSelect::Database {
+        tokio::spawn(monitor_database(
+            job_config.clone(),
+            path.clone(),
+            queue.clone(),
+        ));
+    }
+    let job_config_catchup = job_config.clone();
+    tokio::spawn(catchup(
+        vec![(path.clone(), queue.clone())],
+        job_config.cfg.processing.reporting.catchup,
+        // Reports received during a database outage are sent by `resume`,
+        // at its own rate
+        move |path| {
+            job_config_catchup.pressure.holds_intake(path)
+                || job_config_catchup.database_health.holds_files()
+        },
     ));
-    watch(&path, &job_config, &sender);
+    watch(&path, &queue, job_config);
+}
+
+/// Pauses insertion while the database is unavailable, and sends the reports
+/// received in the meantime once it is back
+fn monitor_database(
+    job_config: Arc<JobConfig>,
+    path: WatchedDirectory,
+    queue: Queue,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(
+        Instant::now(),
+        job_config.cfg.output.database.health_check_frequency,
+    )
+    .map_err(|e| warn!("interval error: {}", e))
+    .for_each(move |_instant| {
+        let job_config_check = job_config.clone();
+        let job_config = job_config.clone();
+        let path = path.clone();
+        let queue = queue.clone();
+        poll_fn(move || {
+            blocking(|| {
+                let pool = job_config_check
+                    .pool
+                    .as_ref()
+                    .expect("output uses database but no config provided");
+                match ping(pool) {
+                    Ok(()) => job_config_check.database_health.available(),
+                    Err(e) => {
+                        job_config_check.database_health.unavailable(&e);
+                        false
+                    }
+                }
+            })
+            .map_err(|_| panic!("the thread pool shut down"))
+        })
+        .map(move |recovered| {
+            if recovered {
+                tokio::spawn(resume(job_config, path, queue));
+            }
+        })
+    })
+}
+
+/// Sends reports received during a database outage, oldest first and
+/// limited to `resume_rate` per second to avoid flooding the database
+fn resume(
+    job_config: Arc<JobConfig>,
+    path: WatchedDirectory,
+    queue: Queue,
+) -> impl Future<Item = (), Error = ()> {
+    let period = Duration::from_secs(1) / job_config.cfg.output.database.resume_rate.max(1);
+    let job_config_end = job_config.clone();
+    poll_fn(move || {
+        blocking(|| old_files(&path, Duration::from_secs(0), u64::max_value()))
+            .map_err(|_| panic!("the thread pool shut down"))
+    })
+    .and_then(|files| files.map_err(|e| warn!("list error: {}", e)))
+    .and_then(move |files| {
+        info!(
+            "resuming {} reports received during the outage",
+            files.len()
+        );
+        Interval::new(Instant::now(), period)
+            .map_err(|e| warn!("interval error: {}", e))
+            .zip(stream::iter_ok(files))
+            // Stop if the database becomes unavailable again, files will be
+            // resumed after next recovery
+            .take_while(move |_| Ok(job_config.database_health.is_available()))
+            .for_each(move |(_instant, file)| queue.send(file))
+    })
+    .then(move |_: Result<(), ()>| {
+        job_config_end.database_health.resumed();
+        Ok(())
+    })
 }
 
 fn serve(
@@ -53,7 +163,9 @@ fn serve(
     rx: mpsc::Receiver<ReceivedFile>,
     stats: mpsc::Sender<Event>,
 ) -> impl Future<Item = (), Error = ()> {
-    rx.for_each(move |file| {
+    let gauges = job_config.gauges.reports.clone();
+    let concurrency = job_config.cfg.processing.reporting.concurrency;
+    run_workers(rx, gauges, concurrency, move |file| {
         // allows skipping temporary .dav files
         if !file
             .extension()
@@ -64,7 +176,19 @@ fn serve(
                 "skipping {:#?} as it does not have a known report extension",
                 file
             );
-            return Ok(());
+            return Box::new(future::ok(()));
+        }
+
+        let guard = match start_processing(&file, &job_config, Event::ReportDeduplicated, &stats) {
+            Some(guard) => guard,
+            None => return Box::new(future::ok(())),
+        };
+
+        if job_config.cfg.processing.reporting.output == ReportingOutputSelect::Database
+            && !job_config.database_health.is_available()
+        {
+            debug!("skipping {:#?} as the database is unavailable", file);
+            return Box::new(future::ok(()));
         }
 
         let queue_id = format!(
@@ -91,7 +215,13 @@ fn serve(
         tokio::spawn(lazy(|| stat_event));
 
         // Check run info
-        let info = RunInfo::try_from(file.as_ref()).map_err(|e| warn!("received: {}", e))?;
+        let info = match RunInfo::try_from(file.as_ref()) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("received: {}", e);
+                return Box::new(future::ok(()));
+            }
+        };
 
         let node_span = span!(
             Level::INFO,
@@ -113,14 +243,20 @@ fn serve(
                 stats.clone(),
             );
 
-            // FIXME: no need for a spawn
-            tokio::spawn(lazy(|| fail));
             error!("refused: report from {:?}, unknown id", &info.node_id);
             // this is actually expected behavior
-            return Ok(());
+            return Box::new(fail.then(move |_| {
+                drop(guard);
+                Ok(())
+            }));
         }
 
         debug!("received: {:?}", file);
+        job_config.runs.received(
+            &info,
+            received_at(&file),
+            &job_config.cfg.processing.reporting.runs,
+        );
 
         let treat_file: Box<dyn Future<Item = (), Error = ()> + Send> =
             match job_config.cfg.processing.reporting.output {
@@ -128,14 +264,17 @@ fn serve(
                     output_report_database(file, info, job_config.clone(), stats.clone())
                 }
                 ReportingOutputSelect::Upstream => {
-                    output_report_upstream(file, job_config.clone(), stats.clone())
+                    output_report_upstream(file, info, job_config.clone(), stats.clone())
                 }
                 // The job should not be started in this case
                 ReportingOutputSelect::Disabled => unreachable!("Report server should be disabled"),
             };
 
-        tokio::spawn(lazy(|| treat_file));
-        Ok(())
+        // Keep the file registered until the end of its processing
+        Box::new(treat_file.then(move |res| {
+            drop(guard);
+            res
+        }))
     })
 }
 
@@ -153,15 +292,25 @@ fn output_report_database(
     let path_clone = path.clone();
     let path_clone2 = path.clone();
     let stats_clone = stats.clone();
+    let archive_cfg = job_config.cfg.processing.archive.clone();
+    let directory = job_config.cfg.processing.reporting.directory.clone();
+    let stats_clone2 = stats.clone();
     Box::new(
         poll_fn(move || {
             blocking(|| {
-                output_report_database_inner(&path_clone.clone(), &run_info, &job_config).map_err(
-                    |e| {
-                        error!("output error: {}", e);
-                        OutputError::from(e)
-                    },
+                output_report_database_inner(
+                    &path_clone.clone(),
+                    &run_info,
+                    &job_config,
+                    &stats_clone2,
                 )
+                .map_err(|e| {
+                    error!("output error: {}", e);
+                    if let Error::DatabaseConnection(_) | Error::Pool(_) = e {
+                        job_config.database_health.unavailable(&e);
+                    }
+                    OutputError::from(e)
+                })
             })
             .map_err(|_| {
                 panic!("the thread pool shut down");
@@ -189,20 +338,36 @@ fn output_report_database(
                 Box::new(futures::future::err::<(), ()>(()))
             }
         })
-        .and_then(move |_| success(path.clone(), Event::ReportInserted, stats_clone)),
+        .and_then(move |_| {
+            success(
+                path.clone(),
+                directory,
+                ReplayKind::Reports,
+                archive_cfg,
+                Event::ReportInserted,
+                stats_clone,
+            )
+        }),
     )
 }
 
 fn output_report_upstream(
     path: ReceivedFile,
+    run_info: RunInfo,
     job_config: Arc<JobConfig>,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
     let job_config_clone = job_config.clone();
+    let job_config_clone2 = job_config.clone();
+    let path_clone = path.clone();
     let path_clone2 = path.clone();
+    let path_clone3 = path.clone();
     let stats_clone = stats.clone();
+    let stats_clone2 = stats.clone();
+    let archive_cfg = job_config.cfg.processing.archive.clone();
+    let directory = job_config.cfg.processing.reporting.directory.clone();
     Box::new(
-        send_report(job_config, path.clone())
+        send_report(job_config, path_clone)
             .map_err(|e| {
                 error!("output error: {}", e);
                 OutputError::from(e)
@@ -225,17 +390,53 @@ fn output_report_upstream(
                     Box::new(futures::future::err::<(), ()>(()))
                 }
             })
-            .and_then(move |_| success(path.clone(), Event::ReportSent, stats_clone)),
+            // Only once sent, as transient errors make the file be processed again.
+            // Sinks need the parsed reports, which are otherwise not read on this side.
+            .and_then(move |_| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                if job_config_clone2.sinks.is_empty() {
+                    Box::new(futures::future::ok(()))
+                } else {
+                    output_report_sinks(path_clone3, run_info, job_config_clone2, stats_clone2)
+                }
+            })
+            .and_then(move |_| {
+                success(
+                    path.clone(),
+                    directory,
+                    ReplayKind::Reports,
+                    archive_cfg,
+                    Event::ReportSent,
+                    stats_clone,
+                )
+            }),
     )
 }
 
-fn output_report_database_inner(
+fn output_report_sinks(
+    path: ReceivedFile,
+    run_info: RunInfo,
+    job_config: Arc<JobConfig>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    Box::new(poll_fn(move || {
+        blocking(|| match parse_runlog(&path, &run_info, &job_config) {
+            // The run log is forwarded unfiltered, filtered reports are not counted here
+            Ok((runlog, _filtered)) => send_to_sinks(&job_config.sinks, &runlog, &stats),
+            // Let the upstream relay decide what to do with the file
+            Err(e) => warn!("could not parse run log for sinks: {}", e),
+        })
+        .map_err(|_| panic!("the thread pool shut down"))
+    }))
+}
+
+/// Checks the signature, parses the run log, removes skipped event types
+/// and filtered reports, and applies redaction rules. Also gives the number
+/// of removed reports.
+fn parse_runlog(
     path: &ReceivedFile,
     run_info: &RunInfo,
     job_config: &Arc<JobConfig>,
-) -> Result<(), Error> {
-    debug!("Starting insertion of {:#?}", path);
-
+) -> Result<(RunLog, usize), Error> {
     let signed_runlog = signature(
         &read_compressed_file(&path)?,
         job_config
@@ -248,8 +449,14 @@ fn output_report_database_inner(
     )?;
 
     let parsed_runlog = RunLog::try_from((run_info.clone(), signed_runlog.as_ref()))?;
+    job_config.runs.parsed(
+        &parsed_runlog,
+        received_at(path),
+        &job_config.cfg.processing.reporting.runs,
+    );
+    let parsed = parsed_runlog.reports.len();
 
-    let filtered_runlog = if !job_config
+    let mut runlog = if !job_config
         .cfg
         .processing
         .reporting
@@ -261,6 +468,43 @@ fn output_report_database_inner(
         parsed_runlog
     };
 
+    let filtered = {
+        let filters = job_config.filters.read().expect("could not read filters");
+        parsed - runlog.reports.len() + runlog.filter(&filters)
+    };
+    if filtered > 0 {
+        debug!(
+            "filtered {} reports out of {} in {}",
+            filtered, parsed, run_info
+        );
+    }
+
+    let redactions = runlog.redact(&job_config.cfg.processing.reporting.redactions);
+    if redactions > 0 {
+        info!("redacted {} values in {}", redactions, run_info);
+    }
+    Ok((runlog, filtered))
+}
+
+fn output_report_database_inner(
+    path: &ReceivedFile,
+    run_info: &RunInfo,
+    job_config: &Arc<JobConfig>,
+    stats: &mpsc::Sender<Event>,
+) -> Result<(), Error> {
+    debug!("Starting insertion of {:#?}", path);
+
+    let (filtered_runlog, filtered) = parse_runlog(path, run_info, job_config)?;
+    if filtered > 0 {
+        tokio::spawn(
+            stats
+                .clone()
+                .send(Event::ReportFiltered(filtered as u64))
+                .map(|_| ())
+                .map_err(|e| error!("send error: {}", e)),
+        );
+    }
+
     let _inserted = insert_runlog(
         &job_config
             .pool
@@ -269,5 +513,8 @@ fn output_report_database_inner(
         &filtered_runlog,
         InsertionBehavior::SkipDuplicate,
     )?;
+
+    // Only once inserted, as transient errors make the file be processed again
+    send_to_sinks(&job_config.sinks, &filtered_runlog, stats);
     Ok(())
 }
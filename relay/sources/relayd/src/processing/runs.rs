@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Tracking of agent runs, to detect nodes with a skewed clock or
+//! which stopped sending reports. Only nodes which sent a run log since
+//! relayd started are known.
+
+use crate::{
+    configuration::main::RunsConfig,
+    data::{node::NodeId, RunInfo, RunLog},
+};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    fs::metadata,
+    path::Path,
+    sync::RwLock,
+};
+use tracing::{info, warn};
+
+/// Reception time of a file, from the relay's clock. Files can be processed
+/// long after their reception, for example after a restart.
+pub fn received_at(path: &Path) -> DateTime<Utc> {
+    metadata(path)
+        .and_then(|m| m.modified())
+        .map(DateTime::from)
+        .unwrap_or_else(|_| Utc::now())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NodeRun {
+    /// Timestamp of the run, from the agent's clock
+    last_run: DateTime<FixedOffset>,
+    /// Reception of the run log, from the relay's clock
+    last_received: DateTime<Utc>,
+    /// Between the two last runs
+    gap: Option<Duration>,
+    /// Positive when the agent's clock is ahead
+    clock_offset: Option<Duration>,
+}
+
+impl NodeRun {
+    fn is_skewed(&self, cfg: &RunsConfig) -> bool {
+        self.clock_offset
+            .map(|offset| offset.num_seconds().abs() as u64 > cfg.max_clock_offset.as_secs())
+            .unwrap_or(false)
+    }
+
+    fn is_missing(&self, now: DateTime<Utc>, cfg: &RunsConfig) -> bool {
+        now.signed_duration_since(self.last_received).num_seconds()
+            > cfg.missing_after.as_secs() as i64
+    }
+
+    fn set_clock_offset(&mut self, node_id: &NodeId, offset: Duration, cfg: &RunsConfig) {
+        let was_skewed = self.is_skewed(cfg);
+        self.clock_offset = Some(offset);
+        match (was_skewed, self.is_skewed(cfg)) {
+            (false, true) => warn!(
+                "clock of node {} is off by {}s",
+                node_id,
+                offset.num_seconds()
+            ),
+            (true, false) => info!("clock of node {} is correct again", node_id),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct NodeRunStatus {
+    pub last_run: DateTime<FixedOffset>,
+    pub last_received: DateTime<Utc>,
+    /// Seconds between the two last runs
+    pub gap: Option<i64>,
+    /// Seconds, positive when the agent's clock is ahead
+    pub clock_offset: Option<i64>,
+    pub clock_skew: bool,
+    pub missing: bool,
+}
+
+/// Number of flagged nodes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct RunsStats {
+    pub nodes: usize,
+    pub clock_skew: usize,
+    pub missing: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Runs {
+    nodes: RwLock<HashMap<NodeId, NodeRun>>,
+}
+
+impl Runs {
+    /// Registers a received run log. Older runs, received late, are ignored.
+    ///
+    /// The clock offset is first estimated from the run timestamp, which
+    /// also includes the duration of the run, and refined once the run log
+    /// is parsed.
+    pub fn received(&self, info: &RunInfo, now: DateTime<Utc>, cfg: &RunsConfig) {
+        let mut nodes = self.nodes.write().expect("could not write runs");
+        let run = match nodes.entry(info.node_id.clone()) {
+            Entry::Occupied(entry) => {
+                let run = entry.into_mut();
+                if info.timestamp <= run.last_run {
+                    return;
+                }
+                run.gap = Some(info.timestamp.signed_duration_since(run.last_run));
+                run.last_run = info.timestamp;
+                run.last_received = now;
+                run
+            }
+            Entry::Vacant(entry) => entry.insert(NodeRun {
+                last_run: info.timestamp,
+                last_received: now,
+                gap: None,
+                clock_offset: None,
+            }),
+        };
+        run.set_clock_offset(
+            &info.node_id,
+            info.timestamp.signed_duration_since(now),
+            cfg,
+        );
+    }
+
+    /// Compares the time of the last report of the last run with the
+    /// reception time of the run log
+    pub fn parsed(&self, runlog: &RunLog, received: DateTime<Utc>, cfg: &RunsConfig) {
+        let last_report = match runlog.reports.iter().map(|r| r.execution_datetime).max() {
+            Some(last_report) => last_report,
+            None => return,
+        };
+        let mut nodes = self.nodes.write().expect("could not write runs");
+        match nodes.get_mut(&runlog.info.node_id) {
+            Some(run) if run.last_run == runlog.info.timestamp => run.set_clock_offset(
+                &runlog.info.node_id,
+                last_report.signed_duration_since(received),
+                cfg,
+            ),
+            _ => (),
+        }
+    }
+
+    pub fn status(&self, now: DateTime<Utc>, cfg: &RunsConfig) -> BTreeMap<NodeId, NodeRunStatus> {
+        self.nodes
+            .read()
+            .expect("could not read runs")
+            .iter()
+            .map(|(id, run)| {
+                (
+                    id.clone(),
+                    NodeRunStatus {
+                        last_run: run.last_run,
+                        last_received: run.last_received,
+                        gap: run.gap.map(|g| g.num_seconds()),
+                        clock_offset: run.clock_offset.map(|o| o.num_seconds()),
+                        clock_skew: run.is_skewed(cfg),
+                        missing: run.is_missing(now, cfg),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    pub fn stats(&self, now: DateTime<Utc>, cfg: &RunsConfig) -> RunsStats {
+        let nodes = self.nodes.read().expect("could not read runs");
+        RunsStats {
+            nodes: nodes.len(),
+            clock_skew: nodes.values().filter(|r| r.is_skewed(cfg)).count(),
+            missing: nodes.values().filter(|r| r.is_missing(now, cfg)).count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::report::Report;
+    use std::{str::FromStr, time};
+
+    fn cfg() -> RunsConfig {
+        RunsConfig {
+            max_clock_offset: time::Duration::from_secs(600),
+            missing_after: time::Duration::from_secs(3600),
+        }
+    }
+
+    fn runlog(info: &RunInfo, execution: &str) -> RunLog {
+        let report = Report {
+            start_datetime: info.timestamp,
+            rule_id: "rudder".to_string(),
+            directive_id: "run".to_string(),
+            component: "CRON Daemon".to_string(),
+            key_value: "None".to_string(),
+            event_type: "log_info".to_string(),
+            msg: "".to_string(),
+            policy: "Common".to_string(),
+            node_id: info.node_id.clone(),
+            execution_datetime: DateTime::parse_from_rfc3339(execution).unwrap(),
+            serial: 0,
+        };
+        RunLog {
+            info: info.clone(),
+            reports: vec![report],
+        }
+    }
+
+    #[test]
+    fn it_tracks_runs() {
+        let runs = Runs::default();
+        let first = RunInfo::from_str("2018-08-24T15:55:01+00:00@root.log").unwrap();
+        let second = RunInfo::from_str("2018-08-24T16:00:01+00:00@root.log").unwrap();
+        let received = DateTime::parse_from_rfc3339("2018-08-24T16:01:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        runs.received(&first, received, &cfg());
+        runs.received(&second, received, &cfg());
+        // Late run log
+        runs.received(&first, received, &cfg());
+
+        let status = runs.status(received, &cfg());
+        let root = status.get("root").unwrap();
+        assert_eq!(root.last_run, second.timestamp);
+        assert_eq!(root.gap, Some(300));
+        // Estimated from the run timestamp
+        assert_eq!(root.clock_offset, Some(-59));
+        assert!(!root.clock_skew);
+        assert!(!root.missing);
+
+        // Agent clock is 15 minutes ahead
+        runs.parsed(
+            &runlog(&second, "2018-08-24T16:16:00+00:00"),
+            received,
+            &cfg(),
+        );
+        // Older run is ignored
+        runs.parsed(
+            &runlog(&first, "2018-08-24T16:01:00+00:00"),
+            received,
+            &cfg(),
+        );
+        let status = runs.status(received, &cfg());
+        assert_eq!(status.get("root").unwrap().clock_offset, Some(900));
+        assert!(status.get("root").unwrap().clock_skew);
+
+        // Runs started 20 minutes after their reception, without parsing
+        let ahead = RunInfo::from_str("2018-08-24T16:21:00+00:00@node1.log").unwrap();
+        runs.received(&ahead, received, &cfg());
+        let status = runs.status(received, &cfg());
+        assert_eq!(status.get("node1").unwrap().clock_offset, Some(1200));
+        assert!(status.get("node1").unwrap().clock_skew);
+
+        let later = received + Duration::hours(2);
+        assert_eq!(
+            runs.stats(later, &cfg()),
+            RunsStats {
+                nodes: 2,
+                clock_skew: 2,
+                missing: 2
+            }
+        );
+    }
+}
@@ -2,12 +2,74 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use relayd::{
-    check_configuration, configuration::cli::CliConfiguration, init_logger, start, ExitStatus,
+    archived_files, check_configuration,
+    configuration::cli::{CliConfiguration, Command, OutputFormat},
+    data::Report,
+    init_logger,
+    processing::archive::extract,
+    replay_failed, start, validate_runlog, ExitStatus,
 };
 use std::{env, process::exit};
 use structopt::StructOpt;
 use tracing::error;
 
+fn print_table(reports: &[Report]) {
+    let rows: Vec<[String; 7]> = reports
+        .iter()
+        .map(|r| {
+            [
+                r.execution_datetime.to_rfc3339(),
+                r.event_type.clone(),
+                r.rule_id.clone(),
+                r.directive_id.clone(),
+                r.component.clone(),
+                r.key_value.clone(),
+                // Only display the first line of multi-line messages
+                r.msg.lines().next().unwrap_or("").to_string(),
+            ]
+        })
+        .collect();
+    let header = [
+        "execution date",
+        "event type",
+        "rule",
+        "directive",
+        "component",
+        "key",
+        "message",
+    ];
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = *width))
+            .collect::<Vec<String>>()
+            .join(" | ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(header.to_vec()));
+    println!(
+        "{}",
+        widths
+            .iter()
+            .map(|w| "-".repeat(*w))
+            .collect::<Vec<String>>()
+            .join("-+-")
+    );
+    for row in &rows {
+        println!("{}", line(row.iter().map(|c| c.as_str()).collect()));
+    }
+}
+
 /// Everything in a lib to allow extensive testing
 fn main() {
     // https://www.reddit.com/r/rust/comments/bnqina/why_does_not_rust_give_a_backtrace_by_default/
@@ -21,11 +83,101 @@ fn main() {
 
     let cli_cfg = CliConfiguration::from_args();
     if cli_cfg.check_configuration {
-        if let Err(e) = check_configuration(&cli_cfg.configuration_dir) {
-            println!("{}", e);
-            exit(ExitStatus::StartError(e).code());
+        match check_configuration(&cli_cfg.configuration_dir) {
+            Ok(check) => {
+                println!("Syntax: OK");
+                for (name, secret) in check.configuration.secrets() {
+                    println!("Secret {}: {}", name, secret.source());
+                }
+                match check.effective() {
+                    Ok(effective) => println!("\nEffective configuration:\n\n{}", effective),
+                    Err(e) => println!("Could not display effective configuration: {}", e),
+                }
+                for warning in &check.warnings {
+                    println!("Warning: {}", warning);
+                }
+                for error in &check.errors {
+                    println!("Error: {}", error);
+                }
+                if !check.is_valid() {
+                    exit(ExitStatus::InvalidConfiguration.code());
+                } else if !check.warnings.is_empty() {
+                    exit(ExitStatus::ConfigurationWarnings.code());
+                }
+            }
+            Err(e) => {
+                println!("{}", e);
+                exit(ExitStatus::StartError(e).code());
+            }
+        }
+    } else if let Some(ref command) = cli_cfg.command {
+        match command {
+            Command::Replay { kind, filter } => {
+                match replay_failed(&cli_cfg.configuration_dir, *kind, filter) {
+                    Ok(files) => {
+                        for file in files {
+                            println!("{}", file);
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        exit(ExitStatus::StartError(e).code());
+                    }
+                }
+            }
+            Command::Archive {
+                kind,
+                filter,
+                output,
+            } => {
+                let result =
+                    archived_files(&cli_cfg.configuration_dir, *kind, filter).and_then(|files| {
+                        match output {
+                            Some(output) => extract(&files, output).map(|copies| {
+                                copies.iter().map(|c| c.display().to_string()).collect()
+                            }),
+                            None => Ok(files.iter().map(|f| f.to_string()).collect::<Vec<_>>()),
+                        }
+                    });
+                match result {
+                    Ok(files) => {
+                        for file in files {
+                            println!("{}", file);
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        exit(ExitStatus::StartError(e).code());
+                    }
+                }
+            }
+            Command::Validate {
+                file,
+                certificate,
+                skip_event_types,
+                format,
+            } => {
+                match validate_runlog(
+                    &cli_cfg.configuration_dir,
+                    file,
+                    certificate.as_ref().map(|c| c.as_path()),
+                    skip_event_types.as_ref().map(|s| s.as_str()),
+                ) {
+                    Ok(runlog) => match format {
+                        OutputFormat::Json => println!(
+                            "{}",
+                            serde_json::to_string_pretty(&runlog.reports)
+                                .expect("could not serialize reports")
+                        ),
+                        OutputFormat::Table => print_table(&runlog.reports),
+                    },
+                    Err(e) => {
+                        println!("{}", e);
+                        exit(ExitStatus::StartError(e).code());
+                    }
+                }
+            }
         }
-        println!("Syntax: OK");
     } else {
         let reload_handle = match init_logger() {
             Ok(handle) => handle,
@@ -1,10 +1,28 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use futures::{stream::Stream, sync::mpsc, Future};
+use crate::{
+    configuration::main::StatsConfig,
+    error::Error,
+    processing::{runs::RunsStats, QueueStats},
+};
+use chrono::prelude::*;
+use futures::{future::poll_fn, stream::Stream, sync::mpsc, Future};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, RwLock};
-use tracing::trace;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, error, info, trace, warn};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct Stats {
@@ -12,32 +30,184 @@ pub struct Stats {
     pub report_refused: u64,
     pub report_sent: u64,
     pub report_inserted: u64,
+    /// Received while already being processed
+    pub report_deduplicated: u64,
+    /// Reports removed from parsed run logs by filters and skipped event types
+    #[serde(default)]
+    pub report_filtered: u64,
     pub inventory_received: u64,
     pub inventory_refused: u64,
     pub inventory_sent: u64,
+    pub inventory_deduplicated: u64,
+    /// Per report sink, by name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub sinks: BTreeMap<String, SinkStats>,
+    /// Current state of processing queues, by name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub queues: BTreeMap<String, QueueStats>,
+    /// Nodes flagged from their last runs
+    #[serde(default)]
+    pub runs: RunsStats,
+    /// Received, inserted or sent, and refused reports and inventories in
+    /// the last minute, hour and day, by counter name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rates: BTreeMap<String, Rates>,
+    /// Used to compute the rates, only persisted
+    #[serde(skip)]
+    pub history: History,
+}
+
+/// Counted per run log
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct SinkStats {
+    pub sent: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Rates {
+    pub last_minute: u64,
+    pub last_hour: u64,
+    pub last_day: u64,
+}
+
+/// Non-empty buckets of a counter, oldest first, as (start timestamp, count)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+struct Window {
+    /// One bucket per second for the last minute
+    seconds: VecDeque<(i64, u64)>,
+    /// One bucket per minute for the last day
+    minutes: VecDeque<(i64, u64)>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+impl Window {
+    fn increment(buckets: &mut VecDeque<(i64, u64)>, start: i64) {
+        match buckets.back_mut() {
+            // Events older than the last bucket (e.g. after a clock change) are counted
+            // in it, to keep the buckets ordered for expiration
+            Some((last, count)) if *last >= start => *count += 1,
+            _ => buckets.push_back((start, 1)),
+        }
+    }
+
+    fn record(&mut self, now: i64) {
+        Self::increment(&mut self.seconds, now);
+        Self::increment(&mut self.minutes, now - now.rem_euclid(MINUTE));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: i64) {
+        while self.seconds.front().map(|(t, _)| *t <= now - MINUTE) == Some(true) {
+            let _ = self.seconds.pop_front();
+        }
+        while self.minutes.front().map(|(t, _)| *t <= now - DAY) == Some(true) {
+            let _ = self.minutes.pop_front();
+        }
+    }
+
+    /// Minute buckets are counted as soon as they overlap the window
+    fn rates(&self, now: i64) -> Rates {
+        let sum = |buckets: &VecDeque<(i64, u64)>, since: i64| {
+            buckets
+                .iter()
+                .filter(|(t, _)| *t > since && *t <= now)
+                .map(|(_, count)| count)
+                .sum()
+        };
+        Rates {
+            last_minute: sum(&self.seconds, now - MINUTE),
+            last_hour: sum(&self.minutes, now - HOUR),
+            last_day: sum(&self.minutes, now - DAY),
+        }
+    }
+}
+
+/// Recent events, by counter name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct History {
+    windows: BTreeMap<String, Window>,
+}
+
+impl History {
+    fn record(&mut self, name: &str, now: DateTime<Utc>) {
+        self.windows
+            .entry(name.to_string())
+            .or_default()
+            .record(now.timestamp())
+    }
+
+    /// Counters without events in the last day are not included
+    pub fn rates(&self, now: DateTime<Utc>) -> BTreeMap<String, Rates> {
+        let now = now.timestamp();
+        self.windows
+            .iter()
+            .map(|(name, window)| (name.clone(), window.rates(now)))
+            .filter(|(_, rates)| rates.last_day > 0)
+            .collect()
+    }
+}
+
+/// Content of the statistics file
+#[derive(Debug, Serialize, Deserialize)]
+struct Persisted {
+    stats: Stats,
+    history: History,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Event {
     ReportReceived,
     ReportSent,
     ReportInserted,
     ReportRefused,
+    ReportDeduplicated,
+    /// Number of removed reports
+    ReportFiltered(u64),
     InventoryReceived,
     InventorySent,
     InventoryRefused,
+    InventoryDeduplicated,
+    SinkSent(String),
+    SinkFailed(String),
+}
+
+impl Event {
+    /// Name of the counter, for events with rates
+    fn windowed(&self) -> Option<&'static str> {
+        match self {
+            Event::ReportReceived => Some("report_received"),
+            Event::ReportSent => Some("report_sent"),
+            Event::ReportInserted => Some("report_inserted"),
+            Event::ReportRefused => Some("report_refused"),
+            Event::InventoryReceived => Some("inventory_received"),
+            Event::InventorySent => Some("inventory_sent"),
+            Event::InventoryRefused => Some("inventory_refused"),
+            _ => None,
+        }
+    }
 }
 
 impl Stats {
-    pub fn event(&mut self, event: Event) {
+    pub fn event(&mut self, event: Event, now: DateTime<Utc>) {
+        if let Some(name) = event.windowed() {
+            self.history.record(name, now);
+        }
         match event {
             Event::ReportReceived => self.report_received += 1,
             Event::ReportSent => self.report_sent += 1,
             Event::ReportInserted => self.report_inserted += 1,
             Event::ReportRefused => self.report_refused += 1,
+            Event::ReportDeduplicated => self.report_deduplicated += 1,
+            Event::ReportFiltered(reports) => self.report_filtered += reports,
             Event::InventoryReceived => self.inventory_received += 1,
             Event::InventorySent => self.inventory_sent += 1,
             Event::InventoryRefused => self.inventory_refused += 1,
+            Event::InventoryDeduplicated => self.inventory_deduplicated += 1,
+            Event::SinkSent(sink) => self.sinks.entry(sink).or_default().sent += 1,
+            Event::SinkFailed(sink) => self.sinks.entry(sink).or_default().failed += 1,
         }
     }
 
@@ -46,12 +216,175 @@ impl Stats {
         rx: mpsc::Receiver<Event>,
     ) -> impl Future<Item = (), Error = ()> {
         rx.for_each(move |event| {
+            trace!("Received stat event: {:?}", event);
             stats
                 .write()
                 .expect("could not write lock stats")
-                .event(event);
-            trace!("Received stat event: {:?}", event);
+                .event(event, Utc::now());
             Ok(())
         })
     }
+
+    /// Copy with the rates instead of the history, for display
+    pub fn with_rates(&self, now: DateTime<Utc>) -> Self {
+        Self {
+            rates: self.history.rates(now),
+            history: Default::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Starts from empty statistics when the file does not exist
+    pub fn load(file: &Path) -> Result<Self, Error> {
+        if !file.exists() {
+            info!(
+                "Statistics file {:?} does not exist, starting from zero",
+                file
+            );
+            return Ok(Self::default());
+        }
+        let persisted: Persisted = serde_json::from_str(&fs::read_to_string(file)?)?;
+        Ok(Self {
+            history: persisted.history,
+            ..persisted.stats
+        })
+    }
+
+    /// Counters and history, without the current state
+    pub fn save(&self, file: &Path) -> Result<(), Error> {
+        let persisted = Persisted {
+            stats: Self {
+                queues: Default::default(),
+                runs: Default::default(),
+                rates: Default::default(),
+                history: Default::default(),
+                ..self.clone()
+            },
+            history: self.history.clone(),
+        };
+        // Write then rename, not to leave a truncated file
+        let tmp = file.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&persisted)?)?;
+        fs::rename(&tmp, file)?;
+        debug!("saved statistics to {:?}", file);
+        Ok(())
+    }
+
+    /// Periodically saves the statistics
+    pub fn persist(
+        stats: Arc<RwLock<Self>>,
+        cfg: StatsConfig,
+    ) -> impl Future<Item = (), Error = ()> {
+        Interval::new(Instant::now() + cfg.save_frequency, cfg.save_frequency)
+            .map_err(|e| warn!("interval error: {}", e))
+            .for_each(move |_instant| {
+                let stats = stats.clone();
+                let file = cfg.file.clone();
+                poll_fn(move || {
+                    blocking(|| stats.read().expect("could not read stats").save(&file))
+                        .map_err(|_| panic!("the thread pool shut down"))
+                })
+                .map(|res| {
+                    if let Err(e) = res {
+                        error!("could not save statistics: {}", e)
+                    }
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_computes_rates() {
+        let now = DateTime::parse_from_rfc3339("2020-05-12T10:00:30+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut stats = Stats::default();
+        for seconds_ago in &[2 * DAY, 3 * HOUR, 50 * MINUTE, 90, 10, 10, 0] {
+            stats.event(
+                Event::ReportReceived,
+                now - chrono::Duration::seconds(*seconds_ago),
+            );
+        }
+        stats.event(Event::ReportSent, now - chrono::Duration::minutes(5));
+        stats.event(Event::ReportDeduplicated, now);
+        stats.event(Event::InventoryRefused, now - chrono::Duration::hours(25));
+
+        assert_eq!(stats.report_received, 7);
+        assert_eq!(
+            stats.with_rates(now).rates,
+            vec![
+                (
+                    "report_received".to_string(),
+                    Rates {
+                        last_minute: 3,
+                        last_hour: 5,
+                        last_day: 6,
+                    }
+                ),
+                (
+                    "report_sent".to_string(),
+                    Rates {
+                        last_minute: 0,
+                        last_hour: 1,
+                        last_day: 1,
+                    }
+                )
+            ]
+            .into_iter()
+            .collect()
+        );
+        // Buckets older than the windows are dropped
+        let window = &stats.history.windows["report_received"];
+        assert_eq!(window.seconds.len(), 2);
+        assert_eq!(window.minutes.len(), 4);
+    }
+
+    #[test]
+    fn it_counts_out_of_order_events_in_the_last_bucket() {
+        let mut window = Window::default();
+        window.record(1000);
+        window.record(900);
+        window.record(1001);
+        assert_eq!(
+            window.seconds.iter().cloned().collect::<Vec<_>>(),
+            vec![(1000, 2), (1001, 1)]
+        );
+
+        window.expire(1060);
+        assert_eq!(
+            window.seconds.iter().cloned().collect::<Vec<_>>(),
+            vec![(1001, 1)]
+        );
+    }
+
+    #[test]
+    fn it_persists_stats() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("stats.json");
+        assert_eq!(Stats::load(&file).unwrap(), Stats::default());
+
+        let now = Utc::now();
+        let mut stats = Stats::default();
+        stats.event(Event::InventorySent, now);
+        stats.event(Event::SinkFailed("file:reports.json".to_string()), now);
+        stats
+            .queues
+            .insert("reports".to_string(), QueueStats::default());
+        stats.save(&file).unwrap();
+
+        let loaded = Stats::load(&file).unwrap();
+        // Current state is not persisted
+        assert!(loaded.queues.is_empty());
+        stats.queues.clear();
+        assert_eq!(loaded, stats);
+        assert_eq!(
+            loaded.with_rates(now).rates["inventory_sent"].last_minute,
+            1
+        );
+    }
 }
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Service manager notifications, see `sd_notify(3)`.
+//!
+//! Only sends messages when started by systemd with `Type=notify`,
+//! does nothing otherwise.
+
+use crate::error::Error;
+use futures::{Future, Stream};
+use std::{
+    env,
+    os::unix::net::UnixDatagram,
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use tracing::{debug, warn};
+
+/// Startup is finished
+pub const READY: &str = "READY=1";
+/// Service is shutting down
+pub const STOPPING: &str = "STOPPING=1";
+/// Keep-alive ping
+pub const WATCHDOG: &str = "WATCHDOG=1";
+
+fn notify_to(socket: &Path, state: &str) -> Result<(), Error> {
+    let _ = UnixDatagram::unbound()?.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+/// Sends a state change to the service manager, if any
+pub fn notify(state: &str) {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    // Abstract sockets are not supported by std
+    if socket.to_string_lossy().starts_with('@') {
+        debug!("skipping notification on abstract socket {:?}", socket);
+        return;
+    }
+    debug!("notifying service manager: {}", state);
+    if let Err(e) = notify_to(Path::new(&socket), state) {
+        warn!("could not notify service manager: {}", e);
+    }
+}
+
+/// Watchdog timeout, when enabled for this process
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid != process::id().to_string() {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Pings the watchdog at half its timeout. Pings stop when the runtime
+/// does not make progress anymore, which makes systemd restart the service.
+pub fn watchdog() -> Option<impl Future<Item = (), Error = ()>> {
+    watchdog_timeout().map(|timeout| {
+        debug!("enabling watchdog with a {:?} timeout", timeout);
+        Interval::new(Instant::now(), timeout / 2)
+            .map_err(|e| warn!("interval error: {}", e))
+            .for_each(|_instant| {
+                notify(WATCHDOG);
+                Ok(())
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_notifies_service_manager() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("notify");
+        let server = UnixDatagram::bind(&socket).unwrap();
+
+        notify_to(&socket, READY).unwrap();
+
+        let mut buffer = [0; 64];
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], READY.as_bytes());
+    }
+}
@@ -0,0 +1,499 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Export of spans to an OpenTelemetry collector, using OTLP over HTTP
+//! with JSON encoding, and propagation of trace context in HTTP requests
+//! using the W3C `traceparent` header.
+//!
+//! The layer is always installed but does nothing until an exporter is started.
+//! Only spans enabled by the logging configuration are exported.
+
+use crate::{configuration::main::TracesConfig, data::node::NodeId, error::Error};
+use openssl::rand::rand_bytes;
+use reqwest::r#async::RequestBuilder;
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    dispatcher,
+    field::{Field, Visit},
+    span, warn, Span, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// HTTP header used for propagation
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Finished spans waiting for export, dropped when full
+const QUEUE_SIZE: usize = 2_048;
+/// Maximal number of spans in one request
+const BATCH_SIZE: usize = 512;
+
+thread_local! {
+    /// Spans entered on this thread
+    static ENTERED: RefCell<Vec<u64>> = RefCell::new(vec![]);
+}
+
+/// W3C trace context, see https://www.w3.org/TR/trace-context/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl TraceContext {
+    fn random_id(buf: &mut [u8]) {
+        // Zero ids are invalid
+        while buf.iter().all(|b| *b == 0) {
+            rand_bytes(buf).expect("could not generate random id");
+        }
+    }
+
+    /// New span in the given trace, or in a new trace
+    fn child_of(parent: Option<&TraceContext>) -> Self {
+        let mut span_id = [0; 8];
+        Self::random_id(&mut span_id);
+        let trace_id = match parent {
+            Some(parent) => parent.trace_id,
+            None => {
+                let mut trace_id = [0; 16];
+                Self::random_id(&mut trace_id);
+                trace_id
+            }
+        };
+        Self { trace_id, span_id }
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidTraceContext(s.to_string());
+        let parts: Vec<&str> = s.trim().split('-').collect();
+        // Future versions can add fields
+        if parts.len() < 4 || parts[0] == "ff" || (parts[0] == "00" && parts.len() != 4) {
+            return Err(invalid());
+        }
+        let mut trace_id = [0; 16];
+        let mut span_id = [0; 8];
+        hex::decode_to_slice(parts[1], &mut trace_id).map_err(|_| invalid())?;
+        hex::decode_to_slice(parts[2], &mut span_id).map_err(|_| invalid())?;
+        if trace_id.iter().all(|b| *b == 0) || span_id.iter().all(|b| *b == 0) {
+            return Err(invalid());
+        }
+        Ok(Self { trace_id, span_id })
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Exported spans are always sampled
+        write!(
+            f,
+            "00-{}-{}-01",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    name: &'static str,
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<KeyValue>,
+}
+
+struct Attributes<'a>(&'a mut Vec<KeyValue>);
+
+impl<'a> Visit for Attributes<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push(KeyValue::new(field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push(KeyValue::new(field.name(), format!("{:?}", value)));
+    }
+}
+
+/// Keeps track of spans and sends them to the exporter once closed
+#[derive(Debug, Default)]
+pub struct TraceExport {
+    spans: Mutex<HashMap<u64, SpanData>>,
+    exporter: Mutex<Option<SyncSender<OtlpSpan>>>,
+}
+
+impl TraceExport {
+    fn is_enabled(&self) -> bool {
+        self.exporter
+            .lock()
+            .expect("could not lock exporter")
+            .is_some()
+    }
+
+    fn current() -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().cloned())
+    }
+
+    fn context(&self, id: u64) -> Option<TraceContext> {
+        self.spans
+            .lock()
+            .expect("could not lock spans")
+            .get(&id)
+            .map(|span| span.context)
+    }
+
+    fn set_parent(&self, id: u64, parent: TraceContext) {
+        if let Some(span) = self
+            .spans
+            .lock()
+            .expect("could not lock spans")
+            .get_mut(&id)
+        {
+            span.context.trace_id = parent.trace_id;
+            span.parent_span_id = Some(parent.span_id);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for TraceExport {
+    fn new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _ctx: Context<'_, S>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let parent = if attrs.is_contextual() {
+            Self::current()
+        } else {
+            attrs.parent().map(|parent| parent.into_u64())
+        };
+        let mut attributes = vec![];
+        attrs.record(&mut Attributes(&mut attributes));
+
+        let mut spans = self.spans.lock().expect("could not lock spans");
+        let parent = parent
+            .and_then(|parent| spans.get(&parent))
+            .map(|p| p.context);
+        let _ = spans.insert(
+            id.into_u64(),
+            SpanData {
+                name: attrs.metadata().name(),
+                context: TraceContext::child_of(parent.as_ref()),
+                parent_span_id: parent.map(|p| p.span_id),
+                start: SystemTime::now(),
+                attributes,
+            },
+        );
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        if let Some(span) = self
+            .spans
+            .lock()
+            .expect("could not lock spans")
+            .get_mut(&id.into_u64())
+        {
+            values.record(&mut Attributes(&mut span.attributes));
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        ENTERED.with(|entered| entered.borrow_mut().push(id.into_u64()));
+    }
+
+    fn on_exit(&self, id: &span::Id, _ctx: Context<'_, S>) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|e| *e == id.into_u64()) {
+                let _ = entered.remove(position);
+            }
+        });
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        let span = match self
+            .spans
+            .lock()
+            .expect("could not lock spans")
+            .remove(&id.into_u64())
+        {
+            Some(span) => span,
+            None => return,
+        };
+        if let Some(ref exporter) = *self.exporter.lock().expect("could not lock exporter") {
+            // Spans are dropped when the exporter is late
+            let _ = exporter.try_send(OtlpSpan::new(span, SystemTime::now()));
+        }
+    }
+}
+
+/// Starts exporting spans in a dedicated thread
+pub fn start(cfg: &TracesConfig, node_id: &NodeId) -> Result<(), Error> {
+    let (tx, rx) = sync_channel(QUEUE_SIZE);
+    let started = dispatcher::get_default(|dispatch| {
+        dispatch.downcast_ref::<TraceExport>().map(|layer| {
+            *layer.exporter.lock().expect("could not lock exporter") = Some(tx.clone());
+        })
+    });
+    if started.is_none() {
+        warn!("trace export is not available in this logger, skipping");
+        return Ok(());
+    }
+
+    let resource = Resource {
+        attributes: vec![
+            KeyValue::new("service.name", cfg.service_name.clone()),
+            KeyValue::new("service.instance.id", node_id.clone()),
+        ],
+    };
+    let endpoint = cfg.endpoint.clone();
+    let interval = cfg.export_interval;
+    let _ = thread::Builder::new()
+        .name("trace-export".to_string())
+        .spawn(move || export(&endpoint, resource, &rx, interval))?;
+    Ok(())
+}
+
+/// Sends batches of finished spans
+fn export(endpoint: &str, resource: Resource, rx: &Receiver<OtlpSpan>, interval: Duration) {
+    let client = reqwest::Client::new();
+    // Wait for the first span of each batch
+    while let Ok(first) = rx.recv() {
+        let mut spans = vec![first];
+        let deadline = Instant::now() + interval;
+        while spans.len() < BATCH_SIZE {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => spans.push(span),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let request = ExportRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: resource.clone(),
+                instrumentation_library_spans: vec![InstrumentationLibrarySpans {
+                    instrumentation_library: InstrumentationLibrary { name: "relayd" },
+                    spans,
+                }],
+            }],
+        };
+        if let Err(e) = client
+            .post(endpoint)
+            .json(&request)
+            .send()
+            .and_then(|r| r.error_for_status())
+        {
+            warn!("could not export spans: {}", e);
+        }
+    }
+}
+
+/// Trace context of the current span, if exported
+pub fn current_context() -> Option<TraceContext> {
+    TraceExport::current().and_then(|id| {
+        dispatcher::get_default(|dispatch| {
+            dispatch
+                .downcast_ref::<TraceExport>()
+                .and_then(|layer| layer.context(id))
+        })
+    })
+}
+
+/// Adds a trace context, usually the current one, to an outgoing request
+pub fn inject(request: RequestBuilder, context: Option<TraceContext>) -> RequestBuilder {
+    match context {
+        Some(context) => request.header(TRACEPARENT, context.to_string()),
+        None => request,
+    }
+}
+
+/// Makes the span part of the trace of a received request. Invalid
+/// trace contexts are ignored.
+pub fn set_parent(span: &Span, traceparent: Option<&str>) {
+    let parent = match traceparent.map(TraceContext::from_str) {
+        Some(Ok(parent)) => parent,
+        Some(Err(e)) => {
+            warn!("{}", e);
+            return;
+        }
+        None => return,
+    };
+    if let Some(id) = span.id() {
+        dispatcher::get_default(|dispatch| {
+            if let Some(layer) = dispatch.downcast_ref::<TraceExport>() {
+                layer.set_parent(id.into_u64(), parent)
+            }
+        });
+    }
+}
+
+// OTLP JSON encoding, see
+// https://github.com/open-telemetry/opentelemetry-proto/blob/master/opentelemetry/proto/trace/v1/trace.proto
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    instrumentation_library_spans: Vec<InstrumentationLibrarySpans>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InstrumentationLibrarySpans {
+    instrumentation_library: InstrumentationLibrary,
+    spans: Vec<OtlpSpan>,
+}
+
+#[derive(Serialize, Debug)]
+struct InstrumentationLibrary {
+    name: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+impl KeyValue {
+    fn new(key: &str, value: String) -> Self {
+        Self {
+            key: key.to_string(),
+            value: AnyValue {
+                string_value: value,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: &'static str,
+    /// SPAN_KIND_INTERNAL
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+}
+
+impl OtlpSpan {
+    fn unix_nano(time: SystemTime) -> String {
+        time.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+            .to_string()
+    }
+
+    fn new(span: SpanData, end: SystemTime) -> Self {
+        Self {
+            trace_id: hex::encode(span.context.trace_id),
+            span_id: hex::encode(span.context.span_id),
+            parent_span_id: span.parent_span_id.map(hex::encode),
+            name: span.name,
+            kind: 1,
+            start_time_unix_nano: Self::unix_nano(span.start),
+            end_time_unix_nano: Self::unix_nano(end),
+            attributes: span.attributes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{dispatcher::Dispatch, span, Level};
+    use tracing_subscriber::{fmt::Subscriber, layer::SubscriberExt};
+
+    #[test]
+    fn it_parses_trace_context() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let context = TraceContext::from_str(traceparent).unwrap();
+        assert_eq!(context.to_string(), traceparent);
+
+        assert!(
+            TraceContext::from_str("00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01")
+                .is_err()
+        );
+        assert!(
+            TraceContext::from_str("00-00000000000000000000000000000000-b7ad6b7169203331-01")
+                .is_err()
+        );
+        assert!(
+            TraceContext::from_str("ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn it_exports_spans() {
+        let (tx, rx) = sync_channel(10);
+        let layer = TraceExport::default();
+        *layer.exporter.lock().unwrap() = Some(tx);
+        let subscriber = Subscriber::builder()
+            .with_env_filter("trace")
+            .finish()
+            .with(layer);
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        dispatcher::with_default(&Dispatch::new(subscriber), || {
+            let span = span!(Level::INFO, "report", queue_id = "42");
+            set_parent(&span, Some(traceparent));
+            let _enter = span.enter();
+            let child = span!(Level::INFO, "node");
+            let _child_enter = child.enter();
+            assert_eq!(
+                current_context().unwrap().trace_id,
+                TraceContext::from_str(traceparent).unwrap().trace_id
+            );
+        });
+
+        let child = rx.recv().unwrap();
+        let parent = rx.recv().unwrap();
+        assert_eq!(child.name, "node");
+        assert_eq!(parent.name, "report");
+        assert_eq!(parent.trace_id, "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_eq!(parent.parent_span_id, Some("b7ad6b7169203331".to_string()));
+        assert_eq!(child.parent_span_id, Some(parent.span_id));
+        assert_eq!(
+            parent.attributes,
+            vec![KeyValue::new("queue_id", "42".to_string())]
+        );
+    }
+}
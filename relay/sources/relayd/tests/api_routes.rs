@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+mod common;
+
+use relayd::{configuration::cli::CliConfiguration, init_logger, start};
+use reqwest::{self, Method, StatusCode};
+use std::{fs::read_to_string, path::Path, thread};
+
+/// Values of the path parameters of the documented routes
+const PARAMETERS: &[(&str, &str)] = &[
+    ("{kind}", "reports"),
+    (
+        "{path}",
+        "c745a140-40bc-4b86-b6dc-084488fc906b/37817c4d-fbf7-4850-a985-50021f4e8f41/file",
+    ),
+    ("{targetNodeId}", "37817c4d-fbf7-4850-a985-50021f4e8f41"),
+    ("{sourceNodeId}", "e745a140-40bc-4b86-b6dc-084488fc906b"),
+    ("{fileId}", "file"),
+    ("{nodeId}", "root"),
+];
+
+/// Required query parameters, by method and route
+const QUERIES: &[(&str, &str, &str)] = &[
+    (
+        "HEAD",
+        "/shared-folder/{path}",
+        "hash_type=sha256&hash=181210f8f9c779c26da1d9b2075bde0127302ee0e3fca38c9a83f5b1dd8e5d3b",
+    ),
+    (
+        "HEAD",
+        "/shared-files/{targetNodeId}/{sourceNodeId}/{fileId}",
+        "hash=dda78e9b97a69aca3cff21de266246bde0d91bc4b61df72bfb0387564ac0c7bd64dd4caca39ce1ef400f32aa711ec4909789705beec93314eb65fabd5183bbfe",
+    ),
+    (
+        "PUT",
+        "/shared-files/{targetNodeId}/{sourceNodeId}/{fileId}",
+        "ttl=1d",
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_serves_all_documented_routes() {
+        let cli_cfg = CliConfiguration::new("tests/files/config/", false);
+        thread::spawn(move || {
+            start(cli_cfg, init_logger().unwrap()).unwrap();
+        });
+        assert!(common::start_api().is_ok());
+        let client = reqwest::Client::new();
+
+        let doc = read_to_string("../api-doc/openapi.src.yml").unwrap();
+        let mut lines = doc.lines();
+        while let Some(line) = lines.next() {
+            if !line.starts_with("  \"/") {
+                continue;
+            }
+            let route = line.trim().trim_end_matches(':').trim_matches('"');
+            let reference = lines.next().unwrap().trim().trim_start_matches("$ref: ");
+            // Policies are served by the web server
+            if route.starts_with("/policies/") {
+                continue;
+            }
+            let path = PARAMETERS
+                .iter()
+                .fold(route.to_string(), |path, (name, value)| {
+                    path.replace(name, value)
+                });
+
+            let operations = read_to_string(Path::new("../api-doc").join(reference)).unwrap();
+            for method in operations
+                .lines()
+                .filter(|l| !l.starts_with(' ') && !l.starts_with('#') && l.ends_with(':'))
+                .map(|l| l.trim_end_matches(':').to_uppercase())
+            {
+                let query = QUERIES
+                    .iter()
+                    .find(|(m, r, _)| *m == method && *r == route)
+                    .map(|(_, _, q)| *q)
+                    .unwrap_or("");
+                let url = format!("http://127.0.0.1:3030/rudder/relay-api/2{}?{}", path, query);
+                let method = Method::from_bytes(method.as_bytes()).unwrap();
+                let request = client.request(method.clone(), &url);
+                let request = if method == Method::POST {
+                    request.form(&[("asynchronous", "true"), ("nodes", "root")])
+                } else {
+                    request
+                };
+                let mut response = request.send().unwrap();
+
+                // Routing errors use the generic "request" action, and HEAD answers have no body
+                let status = response.status();
+                let body = response.text().unwrap();
+                let is_routing_error = status == StatusCode::METHOD_NOT_ALLOWED
+                    || (status == StatusCode::NOT_FOUND
+                        && serde_json::from_str::<serde_json::Value>(&body)
+                            .map(|answer| answer["action"] == "request")
+                            .unwrap_or(true));
+                assert!(
+                    !is_routing_error,
+                    "{} {} is not served: {} {}",
+                    method, route, status, body
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+mod common;
+
+use relayd::{configuration::cli::CliConfiguration, init_logger, start};
+use std::{
+    fs::metadata,
+    io::{Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    thread,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_serves_the_api_on_a_unix_socket() {
+        let cli_cfg = CliConfiguration::new("tests/files/config/unix_socket/", false);
+        thread::spawn(move || {
+            start(cli_cfg, init_logger().unwrap()).unwrap();
+        });
+        assert!(common::start_api().is_ok());
+
+        assert_eq!(
+            metadata("target/tmp/api.sock")
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+        let mut stream = UnixStream::connect("target/tmp/api.sock").unwrap();
+        stream
+            .write_all(b"GET /rudder/relay-api/1/system/info HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).unwrap();
+        assert!(response.lines().next().unwrap().ends_with(" 200 OK"));
+
+        // Same routes as on the TCP listener
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap();
+        let reference: serde_json::Value = serde_json::from_str("{\"data\":{\"major-version\":\"0.0\",\"full-version\":\"0.0.0-dev\"},\"result\":\"success\",\"action\":\"getSystemInfo\"}").unwrap();
+        assert_eq!(
+            reference,
+            serde_json::from_str::<serde_json::Value>(body).unwrap()
+        );
+    }
+}
@@ -0,0 +1,565 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Starts several relayd instances in-process, organized as a relay tree
+//!
+//! Each relay gets a generated configuration, nodes list and certificates in
+//! `target/tmp/harness/<name>/<relay>/`, and a local stand-in for its web
+//! server ("front"), which receives uploaded files into the incoming
+//! directories and proxies the relay API, like the web server does in
+//! production.
+//!
+//! Upstream relays are reached through their front, so the front of the
+//! root server is the upstream stand-in at the top of the tree. Like the web
+//! server, fronts are served over TLS, with the certificate of their relay.
+//!
+//! relayd instances can't be stopped and set the global logger, they run until
+//! the test binary exits. Ports are fixed, so there can only be one harness
+//! per test binary, which must not start other relayd instances.
+
+use bytes::IntoBuf;
+use futures::{Future, Stream};
+use native_tls::Identity;
+use openssl::{
+    asn1::Asn1Time,
+    base64,
+    hash::MessageDigest,
+    nid::Nid,
+    pkcs12::Pkcs12,
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sha::sha256,
+    sign::Signer,
+    stack::Stack,
+    x509::{X509NameBuilder, X509},
+};
+use relayd::{configuration::cli::CliConfiguration, init_logger, start};
+use reqwest::{self, r#async::Client};
+use std::{
+    collections::BTreeMap,
+    fs::{self, create_dir_all, read_to_string, remove_dir_all},
+    io,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread, time,
+};
+use tokio::net::TcpListener;
+use tokio_tls::TlsAcceptor;
+use warp::{
+    body::{self, FullBody},
+    filters::{
+        method::{method, v2::put},
+        path::Tail,
+    },
+    header,
+    http::{Method, Response, StatusCode},
+    path, query, Buf, Filter, Rejection, Reply,
+};
+
+const FIRST_API_PORT: u16 = 3040;
+const FIRST_FRONT_PORT: u16 = 3050;
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Relay or agent of the generated tree
+struct Node {
+    id: String,
+    hostname: String,
+    policy_server: String,
+    key: PKey<Private>,
+    certificate: X509,
+}
+
+impl Node {
+    fn new(id: &str, hostname: String, policy_server: &str) -> Self {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        // Rudder certificates are self-signed and contain the node id as UID
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", id).unwrap();
+        name.append_entry_by_nid(Nid::USERID, id).unwrap();
+        let name = name.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+
+        Self {
+            id: id.to_string(),
+            hostname,
+            policy_server: policy_server.to_string(),
+            key,
+            certificate: certificate.build(),
+        }
+    }
+
+    /// TLS acceptor using the node key and certificate
+    fn tls_acceptor(&self) -> TlsAcceptor {
+        let pkcs12 = Pkcs12::builder()
+            .build("", &self.id, &self.key, &self.certificate)
+            .unwrap();
+        let identity = Identity::from_pkcs12(&pkcs12.to_der().unwrap(), "").unwrap();
+        native_tls::TlsAcceptor::new(identity).unwrap().into()
+    }
+
+    fn key_hash(&self) -> String {
+        format!(
+            "sha256:{}",
+            hex::encode(sha256(&self.key.public_key_to_der().unwrap()))
+        )
+    }
+}
+
+/// A relayd instance with its front
+struct Relay {
+    id: String,
+    directory: PathBuf,
+    api: u16,
+    front: u16,
+}
+
+impl Relay {
+    fn incoming_reports(&self) -> PathBuf {
+        self.directory.join("reporting").join("incoming")
+    }
+
+    fn incoming_inventories(&self) -> PathBuf {
+        self.directory.join("inventories").join("incoming")
+    }
+
+    fn inventory_updates(&self) -> PathBuf {
+        self.directory
+            .join("inventories")
+            .join("accepted-nodes-updates")
+    }
+}
+
+pub struct Harness {
+    directory: PathBuf,
+    nodes: Vec<Node>,
+    relays: Vec<Relay>,
+}
+
+impl Harness {
+    pub fn new(name: &str) -> Self {
+        let directory = PathBuf::from("target/tmp/harness").join(name);
+        let _ = remove_dir_all(&directory);
+        Self {
+            directory,
+            nodes: vec![],
+            relays: vec![],
+        }
+    }
+
+    /// Adds a relay, the root server being its own policy server
+    pub fn relay(mut self, id: &str, policy_server: &str) -> Self {
+        let index = self.relays.len() as u16;
+        let relay = Relay {
+            id: id.to_string(),
+            directory: self.directory.join(id),
+            api: FIRST_API_PORT + index,
+            front: FIRST_FRONT_PORT + index,
+        };
+        // Relays are reached through their front
+        self.nodes.push(Node::new(
+            id,
+            format!("127.0.0.1:{}", relay.front),
+            policy_server,
+        ));
+        self.relays.push(relay);
+        self
+    }
+
+    pub fn agent(mut self, id: &str, policy_server: &str) -> Self {
+        self.nodes.push(Node::new(
+            id,
+            format!("{}.harness.local", id),
+            policy_server,
+        ));
+        self
+    }
+
+    /// Writes the configurations and starts relayd instances and their fronts
+    pub fn start(self) -> Self {
+        assert!(
+            !STARTED.swap(true, Ordering::SeqCst),
+            "only one harness can be started per test binary"
+        );
+        let log_handle =
+            init_logger().expect("no other relayd can be started in the harness test binary");
+
+        for relay in &self.relays {
+            self.configure(relay);
+
+            let front = front(relay);
+            let acceptor = self.node(&relay.id).tls_acceptor();
+            let address: SocketAddr = ([127, 0, 0, 1], relay.front).into();
+            let incoming = TcpListener::bind(&address)
+                .unwrap()
+                .incoming()
+                // A failed handshake must not stop the front
+                .and_then(move |socket| {
+                    acceptor
+                        .accept(socket)
+                        .then(|stream| Ok::<_, io::Error>(stream.ok()))
+                })
+                .filter_map(|stream| stream);
+            thread::spawn(move || tokio::run(warp::serve(front).serve_incoming(incoming)));
+
+            let cli_cfg = CliConfiguration::new(&relay.directory, false);
+            let log_handle = log_handle.clone();
+            thread::spawn(move || {
+                start(cli_cfg, log_handle).unwrap();
+            });
+        }
+
+        for relay in &self.relays {
+            assert!(
+                wait_for(|| reqwest::get(&self.api_url(&relay.id, "1/system/status")).is_ok()),
+                "{} did not start",
+                relay.id
+            );
+        }
+        self
+    }
+
+    fn node(&self, id: &str) -> &Node {
+        self.nodes
+            .iter()
+            .find(|n| n.id == id)
+            .unwrap_or_else(|| panic!("unknown node {}", id))
+    }
+
+    fn get(&self, id: &str) -> &Relay {
+        self.relays
+            .iter()
+            .find(|r| r.id == id)
+            .unwrap_or_else(|| panic!("unknown relay {}", id))
+    }
+
+    /// Nodes behind the given relay, at any depth
+    fn subnodes(&self, id: &str) -> Vec<&Node> {
+        let mut subnodes = vec![];
+        for node in self.nodes.iter().filter(|n| n.policy_server == id) {
+            // The root server is its own policy server
+            if node.id != id {
+                subnodes.push(node);
+                subnodes.extend(self.subnodes(&node.id));
+            }
+        }
+        subnodes
+    }
+
+    fn configure(&self, relay: &Relay) {
+        let directory = &relay.directory;
+        for dir in &[
+            relay.incoming_reports(),
+            relay.incoming_inventories(),
+            relay.inventory_updates(),
+            directory.join("shared-files"),
+            directory.join("shared-folder"),
+        ] {
+            create_dir_all(dir).unwrap();
+        }
+
+        let subnodes = self.subnodes(&relay.id);
+        let nodes_list: BTreeMap<&str, serde_json::Value> = subnodes
+            .iter()
+            .map(|n| {
+                (
+                    n.id.as_str(),
+                    serde_json::json!({
+                        "hostname": n.hostname,
+                        "key-hash": n.key_hash(),
+                        "policy-server": n.policy_server,
+                    }),
+                )
+            })
+            .collect();
+        fs::write(
+            directory.join("nodeslist.json"),
+            serde_json::to_string_pretty(&nodes_list).unwrap(),
+        )
+        .unwrap();
+        let certificates: Vec<u8> = subnodes
+            .iter()
+            .flat_map(|n| n.certificate.to_pem().unwrap())
+            .collect();
+        fs::write(directory.join("nodescerts.pem"), certificates).unwrap();
+
+        // Records its arguments, for each relay
+        let agent = directory.join("agent.sh");
+        fs::write(
+            &agent,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\necho \"{} OK\"\n",
+                directory.join("agent.log").display(),
+                relay.id
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&agent, fs::Permissions::from_mode(0o755)).unwrap();
+
+        fs::copy(
+            "tests/files/config/logging.conf",
+            directory.join("logging.conf"),
+        )
+        .unwrap();
+        let policy_server = self.node(&relay.id).policy_server.as_str();
+        // Files reaching the root server stay in its incoming directories
+        let (output, upstream) = if policy_server == relay.id {
+            ("disabled", String::new())
+        } else {
+            ("upstream", self.front_url(policy_server, ""))
+        };
+        fs::write(
+            directory.join("main.conf"),
+            format!(
+                r#"[general]
+nodes_list_file = "{dir}/nodeslist.json"
+nodes_certs_file = "{dir}/nodescerts.pem"
+node_id = "{id}"
+listen = "127.0.0.1:{api}"
+watch_nodes_list = false
+
+[processing.inventory]
+directory = "{dir}/inventories/"
+output = "{output}"
+
+[processing.reporting]
+directory = "{dir}/reporting/"
+output = "{output}"
+
+[[processing.reporting.sinks]]
+type = "file"
+path = "{dir}/sinks/reports.json"
+
+[output.upstream]
+url = "{upstream}"
+user = "rudder"
+password = "password"
+default_password = "password"
+# Fronts use self-signed certificates
+verify_certificates = false
+
+[remote_run]
+command = "{dir}/agent.sh"
+use_sudo = false
+
+[shared_files]
+path = "{dir}/shared-files/"
+
+[shared_folder]
+path = "{dir}/shared-folder/"
+"#,
+                dir = directory.display(),
+                id = relay.id,
+                api = relay.api,
+                output = output,
+                upstream = upstream,
+            ),
+        )
+        .unwrap();
+    }
+
+    /// URL of the relay API of the given relay, for `path` below `/rudder/relay-api/`
+    pub fn api_url(&self, relay: &str, path: &str) -> String {
+        format!(
+            "http://127.0.0.1:{}/rudder/relay-api/{}",
+            self.get(relay).api,
+            path
+        )
+    }
+
+    /// URL of the web server stand-in of the given relay
+    pub fn front_url(&self, relay: &str, path: &str) -> String {
+        format!("https://127.0.0.1:{}{}", self.get(relay).front, path)
+    }
+
+    pub fn directory(&self, relay: &str) -> &Path {
+        &self.get(relay).directory
+    }
+
+    pub fn incoming_reports(&self, relay: &str) -> PathBuf {
+        self.get(relay).incoming_reports()
+    }
+
+    pub fn incoming_inventories(&self, relay: &str) -> PathBuf {
+        self.get(relay).incoming_inventories()
+    }
+
+    /// Arguments of the agent runs on the given relay, one run per line
+    pub fn agent_runs(&self, relay: &str) -> String {
+        read_to_string(self.directory(relay).join("agent.log")).unwrap_or_default()
+    }
+
+    pub fn hostname(&self, node: &str) -> &str {
+        &self.node(node).hostname
+    }
+
+    /// Run log signed by the given node, like the agent does
+    pub fn signed_runlog(&self, node: &str, runlog: &str) -> Vec<u8> {
+        let node = self.node(node);
+        let flags = Pkcs7Flags::TEXT | Pkcs7Flags::DETACHED | Pkcs7Flags::NOCERTS;
+        Pkcs7::sign(
+            &node.certificate,
+            &node.key,
+            &Stack::new().unwrap(),
+            runlog.as_bytes(),
+            flags,
+        )
+        .unwrap()
+        .to_smime(runlog.as_bytes(), flags)
+        .unwrap()
+    }
+
+    /// Shared file upload body (metadata followed by content), signed by the given node
+    pub fn shared_file(&self, node: &str, content: &str) -> String {
+        let node = self.node(node);
+        let mut signer = Signer::new(MessageDigest::sha256(), &node.key).unwrap();
+        signer.update(content.as_bytes()).unwrap();
+        format!(
+            "header=rudder-signature-v1\nalgorithm=sha256\ndigest={}\nhash_value={}\n\
+             short_pubkey={}\nhostname={}\nkeydate=2020-01-24 12:17:59.014153459 +0100\n\
+             keyid=B85B4E8F\n\n{}",
+            hex::encode(signer.sign_to_vec().unwrap()),
+            hex::encode(sha256(content.as_bytes())),
+            base64::encode_block(&node.key.rsa().unwrap().public_key_to_der_pkcs1().unwrap()),
+            node.hostname,
+            content
+        )
+    }
+}
+
+/// Retries for 5 seconds
+pub fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+    let mut retry = 25;
+    while retry > 0 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(time::Duration::from_millis(200));
+        retry -= 1;
+    }
+    false
+}
+
+/// Stand-in for the web server in front of relayd
+fn front(relay: &Relay) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let api = format!("http://127.0.0.1:{}/rudder/relay-api/1", relay.api);
+
+    upload("reports", relay.incoming_reports())
+        .or(upload("inventories", relay.incoming_inventories()))
+        .or(upload("inventory-updates", relay.inventory_updates()))
+        // Used by relays
+        .or(proxy(path("relay-api"), api.clone()))
+        // Used by the server
+        .or(proxy(path("rudder").and(path("relay-api")), api))
+}
+
+/// Writes uploaded files into the incoming directory
+fn upload(
+    endpoint: &'static str,
+    directory: PathBuf,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    put()
+        .and(path(endpoint))
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(body::concat())
+        .map(move |file: String, body: FullBody| {
+            match fs::write(directory.join(file), body.into_buf().collect::<Vec<u8>>()) {
+                Ok(()) => StatusCode::CREATED,
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        })
+}
+
+/// Forwards requests below `prefix` to the relay API
+fn proxy(
+    prefix: impl Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static,
+    api: String,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let client = Client::new();
+    let base = prefix
+        .and(method())
+        .and(path::tail())
+        .and(header::optional::<String>("content-type"));
+
+    let api_query = api.clone();
+    let client_query = client.clone();
+    let with_query = base.clone().and(query::raw()).and(body::concat()).and_then(
+        move |method: Method,
+              tail: Tail,
+              content_type: Option<String>,
+              query: String,
+              body: FullBody| {
+            forward(
+                &client_query,
+                method,
+                format!("{}/{}?{}", api_query, tail.as_str(), query),
+                content_type,
+                body,
+            )
+        },
+    );
+    let without_query = base.and(body::concat()).and_then(
+        move |method: Method, tail: Tail, content_type: Option<String>, body: FullBody| {
+            forward(
+                &client,
+                method,
+                format!("{}/{}", api, tail.as_str()),
+                content_type,
+                body,
+            )
+        },
+    );
+    with_query.or(without_query)
+}
+
+fn forward(
+    client: &Client,
+    method: Method,
+    url: String,
+    content_type: Option<String>,
+    body: FullBody,
+) -> impl Future<Item = Response<Vec<u8>>, Error = Rejection> {
+    let mut request = client
+        .request(method, &url)
+        .body(body.into_buf().collect::<Vec<u8>>());
+    if let Some(content_type) = content_type {
+        request = request.header("content-type", content_type);
+    }
+    request
+        .send()
+        .and_then(|response| {
+            let status = response.status();
+            response
+                .into_body()
+                .concat2()
+                .map(move |body| (status, body))
+        })
+        .then(|result| {
+            Ok::<_, Rejection>(match result {
+                Ok((status, body)) => Response::builder()
+                    .status(status)
+                    .body(body.to_vec())
+                    .unwrap(),
+                Err(e) => Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(e.to_string().into_bytes())
+                    .unwrap(),
+            })
+        })
+}
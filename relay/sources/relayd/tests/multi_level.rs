@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+mod harness;
+
+use harness::{wait_for, Harness};
+use reqwest;
+use std::fs::{read, read_to_string, write};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_forwards_across_relay_levels() {
+        let harness = Harness::new("multi_level")
+            .relay("root", "root")
+            .relay("relay1", "root")
+            .relay("subrelay1", "relay1")
+            .agent("node1", "root")
+            .agent("node2", "relay1")
+            .agent("node3", "subrelay1")
+            .start();
+        let client = reqwest::Client::new();
+
+        // Reports
+
+        let report = "2018-08-24T15:55:01+00:00@node3.log";
+        let runlog = harness.signed_runlog(
+            "node3",
+            "2018-08-24T15:55:01+00:00 R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##node3@#Start execution\n",
+        );
+        write(harness.incoming_reports("subrelay1").join(report), &runlog).unwrap();
+        let forwarded = harness.incoming_reports("root").join(report);
+        assert!(wait_for(|| read(&forwarded).ok().as_ref() == Some(&runlog)));
+        assert!(wait_for(|| !harness
+            .incoming_reports("subrelay1")
+            .join(report)
+            .exists()));
+        // Relays check signatures to feed their sinks
+        for relay in &["subrelay1", "relay1"] {
+            assert!(wait_for(|| read_to_string(
+                harness.directory(relay).join("sinks/reports.json")
+            )
+            .map(|sink| sink.contains("Start execution"))
+            .unwrap_or(false)));
+        }
+
+        // Inventories
+
+        write(
+            harness.incoming_inventories("subrelay1").join("node3.xml"),
+            "<inventory/>",
+        )
+        .unwrap();
+        assert!(wait_for(|| read_to_string(
+            harness.incoming_inventories("root").join("node3.xml")
+        )
+        .map(|inventory| inventory == "<inventory/>")
+        .unwrap_or(false)));
+
+        // Shared files are stored by the relay of the target node
+
+        let share = |target: &str, signer: &str| {
+            client
+                .put(&harness.api_url(
+                    "subrelay1",
+                    &format!("1/shared-files/{}/node3/file?ttl=1d", target),
+                ))
+                .body(harness.shared_file(signer, "shared content"))
+                .send()
+                .unwrap()
+                .status()
+        };
+        assert_eq!(share("node2", "node3"), 200);
+        assert_eq!(
+            read_to_string(
+                harness
+                    .directory("relay1")
+                    .join("shared-files/node2/files/node3/file")
+            )
+            .unwrap(),
+            "shared content"
+        );
+        assert_eq!(share("node1", "node3"), 200);
+        assert_eq!(
+            read_to_string(
+                harness
+                    .directory("root")
+                    .join("shared-files/node1/files/node3/file")
+            )
+            .unwrap(),
+            "shared content"
+        );
+        assert!(!harness
+            .directory("relay1")
+            .join("shared-files/node1")
+            .exists());
+        // Checked by the storing relay
+        assert_eq!(share("node1", "node2"), 404);
+
+        // Remote runs are forwarded to sub-relays
+
+        let output = client
+            .post(&harness.api_url("root", "1/remote-run/all"))
+            .form(&[
+                ("asynchronous", "false"),
+                ("keep_output", "true"),
+                ("classes", "class1"),
+            ])
+            .send()
+            .unwrap()
+            .text()
+            .unwrap();
+        for (relay, node) in &[
+            ("root", "node1"),
+            ("relay1", "node2"),
+            ("subrelay1", "node3"),
+        ] {
+            assert!(output.contains(&format!("{} OK", relay)));
+            let runs = harness.agent_runs(relay);
+            assert_eq!(runs.lines().count(), 1);
+            assert!(runs.starts_with("remote run -D class1 "));
+            assert!(runs.contains(harness.hostname(node)));
+        }
+
+        let output = client
+            .post(&harness.api_url("root", "1/remote-run/nodes"))
+            .form(&[
+                ("asynchronous", "false"),
+                ("keep_output", "true"),
+                ("nodes", "node3"),
+            ])
+            .send()
+            .unwrap()
+            .text()
+            .unwrap();
+        assert_eq!(output, "subrelay1 OK\n");
+        assert_eq!(harness.agent_runs("root").lines().count(), 1);
+        assert_eq!(harness.agent_runs("relay1").lines().count(), 1);
+        assert_eq!(
+            harness.agent_runs("subrelay1").lines().last().unwrap(),
+            format!("remote run {}", harness.hostname("node3"))
+        );
+    }
+}
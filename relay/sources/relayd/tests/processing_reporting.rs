@@ -104,7 +104,7 @@ fn it_reads_and_inserts_a_runlog() {
         .unwrap()
         .text()
         .unwrap();
-    let answer = serde_json::from_str(&body).unwrap();
+    let answer: Stats = serde_json::from_str(&body).unwrap();
     let reference = Stats {
         report_received: 4,
         report_refused: 2,
@@ -113,6 +113,8 @@ fn it_reads_and_inserts_a_runlog() {
         inventory_received: 0,
         inventory_refused: 0,
         inventory_sent: 0,
+        // Covered by processing_reporting_features
+        ..answer.clone()
     };
     assert_eq!(reference, answer);
 }
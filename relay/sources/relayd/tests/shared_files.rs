@@ -81,6 +81,23 @@ mod tests {
         .send().unwrap();
         assert_eq!(500, upload.status());
 
+        // v2 API gives a client error with details
+
+        let mut upload = client.put("http://127.0.0.1:3030/rudder/relay-api/2/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/e745a140-40bc-4b86-b6dc-084488fc906b/file2?ttl=1d").body(format!("{}\n{}", wrong_signature, content))
+        .send().unwrap();
+        assert_eq!(400, upload.status());
+        let response: serde_json::Value = serde_json::from_str(&upload.text().unwrap()).unwrap();
+        assert_eq!(response["result"], "error");
+        assert_eq!(response["action"], "putSharedFile");
+
+        let mut not_found = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/2/shared-files/unknown")
+            .send()
+            .unwrap();
+        assert_eq!(404, not_found.status());
+        let response: serde_json::Value = serde_json::from_str(&not_found.text().unwrap()).unwrap();
+        assert_eq!(response["result"], "error");
+
         // Correct upload
 
         let upload = client.put("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/e745a140-40bc-4b86-b6dc-084488fc906b/file2?ttl=1d").body(format!("{}\n{}", signature, content))
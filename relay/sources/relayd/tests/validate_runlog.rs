@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use relayd::{data::RunInfo, validate_runlog};
+use std::{fs::copy, path::Path, str::FromStr};
+use tempfile::tempdir;
+
+#[test]
+fn it_validates_a_signed_runlog() {
+    let dir = tempdir().unwrap();
+    let file = dir
+        .path()
+        .join("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log");
+    copy(
+        "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.signed",
+        &file,
+    )
+    .unwrap();
+    let certificate = Path::new("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert");
+    let runlog = validate_runlog(
+        Path::new("tests/files/config/"),
+        &file,
+        Some(certificate),
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        runlog.info,
+        RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log")
+            .unwrap()
+    );
+    assert!(runlog.reports.iter().any(|r| r.event_type == "log_info"));
+
+    let filtered = validate_runlog(
+        Path::new("tests/files/config/"),
+        &file,
+        Some(certificate),
+        Some("log_info"),
+    )
+    .unwrap();
+    assert!(filtered.reports.iter().all(|r| r.event_type != "log_info"));
+    assert!(filtered.reports.len() < runlog.reports.len());
+
+    // Signed runlogs cannot be read without certificate
+    assert!(validate_runlog(Path::new("tests/files/config/"), &file, None, None).is_err());
+    // Wrong certificate
+    assert!(validate_runlog(
+        Path::new("tests/files/config/"),
+        &file,
+        Some(Path::new(
+            "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b-other.cert"
+        )),
+        None
+    )
+    .is_err());
+}